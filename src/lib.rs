@@ -0,0 +1,2 @@
+//! the managers as a library, so other rust services can talk to them with `managers::client`
+pub mod managers;
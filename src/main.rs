@@ -1,5 +1,5 @@
 // #![feature(map_try_insert)]
-mod managers;
+use service_manager::managers;
 // #[tokio::main(worker_threads=2)]
 // #[tokio::main(flavor = "current_thread")]
 #[tokio::main]
@@ -0,0 +1,291 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::{error, json, response, service_manager::other::Backend};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// connections a client keeps open when it is not told otherwise
+pub const POOL_SIZE: usize = 4;
+
+/// commands whose effect stays with the connection they were sent on. requests take turns over the
+/// pool, so `command` turns these down, `collection` and `transaction` do what they are for
+const STATEFUL: [&str; 7] = ["NEW", "USE", "MULTI", "DISCARD", "EXEC", "WATCH", "UNWATCH"];
+
+/// where to reach the database and who to log in as
+#[derive(Debug)]
+struct Config {
+    addr: String,
+    user: String,
+    password: String,
+}
+
+/// connections to the database, each logged in on its own. a slot is empty until it is first used
+/// and again after its connection went away
+#[derive(Debug)]
+struct Pool {
+    config: Config,
+    slots: Vec<Mutex<Option<Arc<Backend>>>>,
+    next: AtomicUsize,
+}
+
+/// talks to a database server from rust. requests take turns over a pool of connections, one that
+/// went away is dialed and logged in again, and values come back as any type serde can read.
+/// clones share the pool
+#[derive(Debug, Clone)]
+pub struct DbClient {
+    pool: Arc<Pool>,
+    collection: Option<String>,
+}
+
+impl DbClient {
+    /// connects to addr, a port on localhost or host:port, and logs in with `NEW`. one connection
+    /// is opened right away so wrong credentials show up here, the rest when they are needed
+    pub async fn connect(addr: &str, user: &str, password: &str) -> Res<DbClient> {
+        DbClient::connect_pooled(addr, user, password, POOL_SIZE).await
+    }
+
+    /// like `connect` with at most size connections
+    pub async fn connect_pooled(addr: &str, user: &str, password: &str, size: usize) -> Res<DbClient> {
+        let addr = if addr.contains(':') { addr.to_owned() } else { format!("127.0.0.1:{}", addr) };
+        let config = Config { addr, user: user.to_owned(), password: password.to_owned() };
+        let slots = (0..size.max(1)).map(|_| Mutex::new(None)).collect();
+        let pool = Arc::new(Pool { config, slots, next: AtomicUsize::new(0) });
+        pool.backend(0).await?;
+        Ok(DbClient { pool, collection: None })
+    }
+
+    /// the same client working on collection instead of the connection's default one
+    pub fn collection(&self, name: &str) -> DbClient {
+        DbClient { pool: self.pool.clone(), collection: Some(name.to_owned()) }
+    }
+
+    /// the value at a key, dotted path or json pointer, none if nothing is there
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Res<Option<T>> {
+        match self.keyed("FND", &[key]).await {
+            Ok(json::JSON::Null) | Err(error::ServerError::MISSING_DATA) => Ok(None),
+            Ok(x) => from_json(x).map(Some),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// puts value at a key
+    pub async fn insert<T: Serialize>(&self, key: &str, value: &T) -> Res<()> {
+        let value = match serde_json::to_string(value) {
+            Ok(x) => x,
+            Err(_) => return Err(error::ServerError::INVALID_JSON),
+        };
+        self.keyed("INS", &[key, &value]).await.map(|_| ())
+    }
+
+    /// removes a key and returns what it held, none if nothing was there
+    pub async fn delete<T: DeserializeOwned>(&self, key: &str) -> Res<Option<T>> {
+        match self.keyed("DEL", &[key]).await {
+            Ok(x) => from_json(x).map(Some),
+            Err(error::ServerError::MISSING_DATA) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// binds the collection to the .json file it is saved to and loaded from
+    pub async fn open(&self, file: &str) -> Res<()> {
+        let mut line = vec!["OPEN"];
+        if let Some(ref collection) = self.collection {
+            line.push(collection);
+        }
+        line.push(file);
+        self.command(&line).await.map(|_| ())
+    }
+
+    /// reads the collection back from its file and log
+    pub async fn load(&self) -> Res<()> {
+        self.keyed("LOAD", &[]).await.map(|_| ())
+    }
+
+    /// writes a snapshot of the collection to its file
+    pub async fn save(&self) -> Res<()> {
+        self.keyed("SAVE", &[]).await.map(|_| ())
+    }
+
+    /// runs a database command, `["SCAN", "0", "COUNT", "10"]`, and returns its result. commands that
+    /// change the connection itself, like `USE` or `MULTI`, are turned down with INVALID_ARG
+    pub async fn command(&self, line: &[&str]) -> Res<json::JSON> {
+        if line.first().is_some_and(|x| STATEFUL.iter().any(|name| name.eq_ignore_ascii_case(x))) {
+            return Err(error::ServerError::INVALID_ARG);
+        }
+        let message = json::to_json!(line).to_string();
+        let i = self.pool.next.fetch_add(1, Ordering::Relaxed) % self.pool.slots.len();
+        let id = Uuid::to_string(&Uuid::new_v4());
+        let mut backend = self.pool.backend(i).await?;
+        let reply = match backend.send(&id, &message).await {
+            // the connection went away before the command reached the server, so it can be sent
+            // once more on a fresh one. once it was sent it may have run, and is never sent again
+            Err(error::ServerError::CONNECTION) => {
+                backend = self.pool.backend(i).await?;
+                backend.send(&id, &message).await?
+            },
+            x => x?,
+        };
+        match backend.reply(&id, reply).await {
+            Ok(x) => response::parse(&x),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// runs the commands as one on a connection of its own, between `MULTI` and `EXEC`, and returns
+    /// their results. none of them runs if one can not be queued
+    pub async fn transaction(&self, lines: &[&[&str]]) -> Res<json::JSON> {
+        let backend = self.pool.dial().await?;
+        // the connection is this transaction's alone, so it may pick the collection for all of them
+        if let Some(ref collection) = self.collection {
+            request(&backend, &json::to_json!(["USE", collection]).to_string()).await?;
+        }
+        request(&backend, &json::to_json!(["MULTI"]).to_string()).await?;
+        for line in lines {
+            if let Err(e) = request(&backend, &json::to_json!(line).to_string()).await {
+                let _ = request(&backend, &json::to_json!(["DISCARD"]).to_string()).await;
+                return Err(e);
+            }
+        }
+        request(&backend, &json::to_json!(["EXEC"]).to_string()).await
+    }
+
+    /// a command taking `-c <collection>` before its arguments
+    async fn keyed(&self, name: &str, args: &[&str]) -> Res<json::JSON> {
+        let mut line = vec![name];
+        if let Some(ref collection) = self.collection {
+            line.push("-c");
+            line.push(collection);
+        }
+        line.extend_from_slice(args);
+        self.command(&line).await
+    }
+}
+
+impl Pool {
+    /// the connection in slot i, dialed and logged in first if there is none or it went away
+    async fn backend(&self, i: usize) -> Res<Arc<Backend>> {
+        let mut slot = self.slots[i].lock().await;
+        if let Some(ref backend) = *slot {
+            if !backend.is_closed() {
+                return Ok(backend.clone());
+            }
+        }
+        *slot = None;
+        let backend = self.dial().await?;
+        *slot = Some(backend.clone());
+        Ok(backend)
+    }
+
+    /// a new connection to the database, logged in
+    async fn dial(&self) -> Res<Arc<Backend>> {
+        let stream = match TcpStream::connect(&self.config.addr).await {
+            Ok(x) => x,
+            Err(_) => return Err(error::ServerError::CONNECTION),
+        };
+        let backend = Arc::new(Backend::negotiate(stream).await?);
+        let login = json::to_json!(["NEW", &self.config.user, &self.config.password]).to_string();
+        request(&backend, &login).await?;
+        Ok(backend)
+    }
+}
+
+async fn request(backend: &Backend, message: &str) -> Res<json::JSON> {
+    let id = Uuid::to_string(&Uuid::new_v4());
+    match backend.request(&id, message).await {
+        Ok(x) => response::parse(&x),
+        Err(e) => Err(e),
+    }
+}
+
+fn from_json<T: DeserializeOwned>(value: json::JSON) -> Res<T> {
+    match serde_json::from_value(value) {
+        Ok(x) => Ok(x),
+        Err(_) => Err(error::ServerError::INVALID_DATA),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+    use super::super::framing;
+
+    /// commands the test server got, in the order they came
+    type Seen = Arc<std::sync::Mutex<Vec<String>>>;
+
+    /// a database that answers every command with the command itself, and hangs up on `DROP`
+    /// without answering
+    async fn server() -> (String, Seen) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let seen: Seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = seen.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let log = log.clone();
+                tokio::spawn(async move {
+                    let mut decoder = framing::Decoder::server();
+                    while let Ok(Some(frame)) = framing::read_frame(&mut socket, &mut decoder).await {
+                        let reply = match frame {
+                            framing::Frame::Handshake(_) => "OK".to_owned(),
+                            framing::Frame::Message(x) => {
+                                let (id, command) = framing::untag(&x);
+                                log.lock().unwrap().push(command.to_owned());
+                                if command.contains("DROP") {
+                                    return;
+                                }
+                                framing::tag(id.unwrap_or(""), &response::ok(json::to_json!(command)).to_string())
+                            },
+                        };
+                        if socket.write_all(&decoder.framing().encode(&reply)).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (addr, seen)
+    }
+
+    fn seen(x: &Seen) -> Vec<String> {
+        x.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn stateful_commands_are_turned_down() {
+        let (addr, log) = server().await;
+        let client = DbClient::connect_pooled(&addr, "ann", "pw", 1).await.unwrap();
+        for name in ["MULTI", "exec", "USE", "WATCH", "NEW"] {
+            assert_eq!(client.command(&[name, "x"]).await.unwrap_err().code(), "INVALID_ARG");
+        }
+        assert_eq!(client.command(&["FND", "k"]).await.unwrap(), json::to_json!(r#"["FND","k"]"#));
+        assert_eq!(seen(&log), vec![r#"["NEW","ann","pw"]"#, r#"["FND","k"]"#]);
+    }
+
+    #[tokio::test]
+    async fn a_sent_command_is_never_sent_again() {
+        let (addr, log) = server().await;
+        let client = DbClient::connect_pooled(&addr, "ann", "pw", 1).await.unwrap();
+        assert_eq!(client.command(&["INCR", "DROP"]).await.unwrap_err().code(), "CONNECTION");
+        // the connection that went away is replaced before the next command goes out
+        assert!(client.command(&["FND", "k"]).await.is_ok());
+        assert_eq!(seen(&log).iter().filter(|x| x.contains("DROP")).count(), 1);
+        assert_eq!(seen(&log).iter().filter(|x| x.contains("NEW")).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn transactions_get_a_connection_of_their_own() {
+        let (addr, log) = server().await;
+        let client = DbClient::connect_pooled(&addr, "ann", "pw", 1).await.unwrap().collection("c");
+        let result = client.transaction(&[&["INS", "k", "1"], &["DEL", "j"]]).await.unwrap();
+        assert_eq!(result, json::to_json!(r#"["EXEC"]"#));
+        assert_eq!(seen(&log)[1..], [
+            r#"["NEW","ann","pw"]"#, r#"["USE","c"]"#, r#"["MULTI"]"#, r#"["INS","k","1"]"#, r#"["DEL","j"]"#, r#"["EXEC"]"#,
+        ]);
+    }
+}
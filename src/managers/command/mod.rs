@@ -0,0 +1,606 @@
+use std::{collections::HashMap, iter::Peekable, str::{CharIndices, FromStr}};
+
+use super::{error, json};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// splits a command line into its arguments. arguments are separated by whitespace and
+/// - `'...'` is one argument without the quotes, `\'` and `\\` escape inside it
+/// - `"..."` is a json string and stays one argument with its quotes and escapes
+/// - an argument starting with `{` or `[` runs to its matching bracket, so a json value may contain spaces
+/// - `\` outside quotes takes the next character as it is, `\ ` to put a space in an argument
+///
+/// a line that is a json array, `["INS", "user", {"name": "Ann Lee"}]`, is a command too. its strings
+/// are arguments as they are and any other value is its json text
+pub fn parse(line: &str) -> Res<Vec<String>> {
+    split(line).map(|tokens| tokens.into_iter().map(|(_, x)| x).collect())
+}
+
+/// the arguments of a line with the byte offset each of them starts at. the items of a json array
+/// line have none
+fn split(line: &str) -> Res<Vec<(Option<usize>, String)>> {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('[') {
+        return match serde_json::from_str::<json::JSON>(trimmed) {
+            Ok(json::JSON::Array(items)) => {
+                let mut tokens = Vec::with_capacity(items.len());
+                for item in items {
+                    match item {
+                        json::JSON::String(x) => tokens.push((None, x)),
+                        x => tokens.push((None, x.to_string())),
+                    }
+                }
+                Ok(tokens)
+            },
+            Ok(_) => Err(syntax(line, 0, "expected a json array")),
+            Err(e) => {
+                // serde puts the position at the end of its message, it is reported like the others
+                let reason = e.to_string();
+                let reason = reason.split(" at line ").next().unwrap_or("");
+                Err(syntax_at(line, e.line(), e.column(), &format!("invalid json array, {}", reason)))
+            },
+        };
+    }
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    loop {
+        while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        let (start, first) = match chars.peek() {
+            Some(x) => *x,
+            None => break,
+        };
+        let mut token = String::new();
+        if first == '{' || first == '[' {
+            json_value(line, &mut chars, &mut token)?;
+            if let Some((i, _)) = chars.peek().filter(|(_, c)| !c.is_whitespace()) {
+                return Err(syntax(line, *i, &format!("expected a space after the json value that starts at column {}", column(line, start))));
+            }
+            tokens.push((Some(start), token));
+            continue;
+        }
+        while let Some((i, c)) = chars.next_if(|(_, c)| !c.is_whitespace()) {
+            match c {
+                '\'' => {
+                    loop {
+                        match chars.next() {
+                            Some((_, '\'')) => break,
+                            Some((j, '\\')) => {
+                                match chars.next() {
+                                    Some((_, x)) if x == '\'' || x == '\\' => token.push(x),
+                                    Some((_, x)) => {
+                                        token.push('\\');
+                                        token.push(x);
+                                    },
+                                    None => return Err(syntax(line, j, "nothing to escape")),
+                                }
+                            },
+                            Some((_, x)) => token.push(x),
+                            None => return Err(syntax(line, i, "unterminated '")),
+                        }
+                    }
+                },
+                '"' => {
+                    token.push('"');
+                    json_string(line, i, &mut chars, &mut token)?;
+                },
+                '\\' => {
+                    match chars.next() {
+                        Some((_, x)) => token.push(x),
+                        None => return Err(syntax(line, i, "nothing to escape")),
+                    }
+                },
+                _ => token.push(c),
+            }
+        }
+        tokens.push((Some(start), token));
+    }
+    Ok(tokens)
+}
+
+/// copies a json object or array, strings in it may hold spaces and brackets
+fn json_value(line: &str, chars: &mut Peekable<CharIndices>, token: &mut String) -> Res<()> {
+    // the brackets still open and where they were opened
+    let mut open: Vec<(char, usize)> = Vec::new();
+    while let Some((i, c)) = chars.next() {
+        token.push(c);
+        match c {
+            '{' => open.push(('}', i)),
+            '[' => open.push((']', i)),
+            '}' | ']' => {
+                match open.pop() {
+                    Some((x, _)) if x == c => (),
+                    Some((x, _)) => return Err(syntax(line, i, &format!("expected {} but found {}", x, c))),
+                    None => return Err(syntax(line, i, &format!("unexpected {}", c))),
+                }
+                if open.is_empty() {
+                    return Ok(());
+                }
+            },
+            '"' => json_string(line, i, chars, token)?,
+            _ => (),
+        }
+    }
+    match open.last() {
+        Some((x, at)) => Err(syntax(line, *at, &format!("missing {} for this bracket", x))),
+        None => Ok(()),
+    }
+}
+
+/// copies the rest of a json string whose opening quote at `start` was already copied
+fn json_string(line: &str, start: usize, chars: &mut Peekable<CharIndices>, token: &mut String) -> Res<()> {
+    loop {
+        match chars.next() {
+            Some((_, '"')) => {
+                token.push('"');
+                return Ok(());
+            },
+            Some((_, '\\')) => {
+                token.push('\\');
+                match chars.next() {
+                    Some((_, x)) => token.push(x),
+                    None => return Err(syntax(line, start, "unterminated \"")),
+                }
+            },
+            Some((_, x)) => token.push(x),
+            None => return Err(syntax(line, start, "unterminated \"")),
+        }
+    }
+}
+
+/// the column a byte offset is at, counting characters from 1
+fn column(line: &str, at: usize) -> usize {
+    line[..at].chars().count() + 1
+}
+
+fn syntax(line: &str, at: usize, what: &str) -> error::ServerError {
+    error::ServerError::SYNTAX_ERROR(format!("Error: Syntax error at column {}: {}", column(line, at), what))
+}
+
+/// an error serde_json found in the trimmed line, which is the whole line if it has only one
+fn syntax_at(line: &str, row: usize, col: usize, what: &str) -> error::ServerError {
+    let offset = line.len() - line.trim_start().len();
+    let at = if row <= 1 { line[..offset].chars().count() + col } else { col };
+    error::ServerError::SYNTAX_ERROR(format!("Error: Syntax error at column {}: {}", at, what))
+}
+
+/// one argument of a command. usage lines, help and checking the arguments before a command runs
+/// are all made from these
+#[derive(Debug, Clone, Copy)]
+pub enum Arg {
+    /// a value that has to be there
+    Required(&'static str),
+    /// a value that is taken when there are more arguments left than the ones after it need
+    Optional(&'static str),
+    /// a word like `-p` or `FRONT`, or with a value like `EX <seconds>`. flags next to each other may
+    /// come in any order and case does not matter
+    Flag(&'static str, Option<&'static str>),
+    /// one or more values up to the end
+    Many(&'static str),
+    /// the rest of the line as one value, it may contain spaces
+    Rest(&'static str),
+}
+
+impl Arg {
+    fn usage(&self) -> String {
+        match self {
+            Arg::Required(x) | Arg::Rest(x) => format!("<{}>", x),
+            Arg::Optional(x) => format!("[<{}>]", x),
+            Arg::Flag(x, None) => format!("[{}]", x),
+            Arg::Flag(x, Some(value)) => format!("[{} <{}>]", x, value),
+            Arg::Many(x) => format!("<{}>...", x),
+        }
+    }
+
+    fn to_json(self) -> json::JSON {
+        match self {
+            Arg::Required(x) => json::to_json!({"name": x, "kind": "required"}),
+            Arg::Optional(x) => json::to_json!({"name": x, "kind": "optional"}),
+            Arg::Flag(x, value) => json::to_json!({"name": x, "kind": "flag", "value": value}),
+            Arg::Many(x) => json::to_json!({"name": x, "kind": "many"}),
+            Arg::Rest(x) => json::to_json!({"name": x, "kind": "rest"}),
+        }
+    }
+
+    fn needs_value(&self) -> bool {
+        matches!(self, Arg::Required(_) | Arg::Many(_) | Arg::Rest(_))
+    }
+}
+
+/// a command a manager understands. name may be two words for commands like `INDEX CREATE`, parse
+/// turns the checked arguments into the manager's own command type
+pub struct Spec<C: 'static> {
+    pub name: &'static str,
+    pub args: &'static [Arg],
+    pub help: &'static str,
+    pub parse: fn(&Parsed) -> Res<C>,
+}
+
+impl<C> Spec<C> {
+    pub fn usage(&self) -> String {
+        std::iter::once(self.name.to_owned()).chain(self.args.iter().map(|x| x.usage())).collect::<Vec<_>>().join(" ")
+    }
+
+    fn to_json(&self) -> json::JSON {
+        let args: Vec<json::JSON> = self.args.iter().map(|x| x.to_json()).collect();
+        json::to_json!({"command": self.name, "usage": self.usage(), "help": self.help, "args": args})
+    }
+
+    /// matches the arguments against the spec, flags by their name and values by their position.
+    /// line is where the arguments start in the line they came from, if there is one
+    fn check<'a>(&self, tokens: &'a [String], line: Option<(&'a str, &[Option<usize>])>) -> Res<Parsed<'a>> {
+        let mut parsed = Parsed { usage: self.usage(), values: HashMap::new(), rest: None };
+        let mut rest = tokens.iter().map(|x| x.as_str()).peekable();
+        let mut i = 0;
+        while i < self.args.len() {
+            match self.args[i] {
+                Arg::Flag(..) => {
+                    let end = self.args[i..].iter().position(|x| !matches!(x, Arg::Flag(..))).map_or(self.args.len(), |x| x + i);
+                    while let Some(token) = rest.peek() {
+                        let flag = self.args[i..end].iter().find_map(|x| match x {
+                            Arg::Flag(name, value) if name.eq_ignore_ascii_case(token) && !parsed.values.contains_key(name) => Some((*name, *value)),
+                            _ => None,
+                        });
+                        let (name, value) = match flag {
+                            Some(x) => x,
+                            None => break,
+                        };
+                        rest.next();
+                        match value {
+                            Some(value) => {
+                                match rest.next() {
+                                    Some(x) => parsed.values.insert(name, vec![x]),
+                                    None => return Err(parsed.invalid(&format!("{} needs <{}>", name, value))),
+                                };
+                            },
+                            None => {
+                                parsed.values.insert(name, Vec::new());
+                            },
+                        }
+                    }
+                    i = end;
+                    continue;
+                },
+                Arg::Required(name) => {
+                    match rest.next() {
+                        Some(x) if !x.is_empty() => parsed.values.insert(name, vec![x]),
+                        _ => return Err(parsed.invalid(&format!("missing <{}>", name))),
+                    };
+                },
+                Arg::Optional(name) => {
+                    let needed = self.args[i + 1..].iter().filter(|x| x.needs_value()).count();
+                    if rest.len() > needed {
+                        parsed.values.insert(name, rest.next().into_iter().collect());
+                    }
+                },
+                Arg::Many(name) | Arg::Rest(name) => {
+                    let first = tokens.len() - rest.len();
+                    let values: Vec<&str> = rest.by_ref().collect();
+                    if values.is_empty() {
+                        return Err(parsed.invalid(&format!("missing <{}>", name)));
+                    }
+                    if let (Arg::Rest(_), Some((line, starts))) = (self.args[i], line) {
+                        // the rest of the line as it was typed, quotes and spaces included
+                        parsed.rest = starts[first].map(|x| line[x..].trim_end());
+                    }
+                    parsed.values.insert(name, values);
+                },
+            }
+            i += 1;
+        }
+        match rest.next() {
+            Some(x) => Err(parsed.invalid(&format!("unexpected argument {}", x))),
+            None => Ok(parsed),
+        }
+    }
+}
+
+/// the arguments of a command after they were checked against its spec, by the name of their `Arg`
+pub struct Parsed<'a> {
+    usage: String,
+    values: HashMap<&'static str, Vec<&'a str>>,
+    // the text of the `Rest` in the line
+    rest: Option<&'a str>,
+}
+
+impl<'a> Parsed<'a> {
+    /// whether a flag or optional value was given
+    pub fn has(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.values.get(name).and_then(|x| x.first().copied())
+    }
+
+    /// a value that the spec makes sure is there
+    pub fn string(&self, name: &str) -> String {
+        self.get(name).unwrap_or("").to_owned()
+    }
+
+    /// a value that may be written as a json string, `"Ann Lee"`, without its quotes and escapes
+    pub fn text(&self, name: &str) -> String {
+        let value = self.string(name);
+        if !value.starts_with('"') {
+            return value;
+        }
+        match serde_json::from_str::<String>(&value) {
+            Ok(x) => x,
+            Err(_) => value,
+        }
+    }
+
+    /// the values of a `Many`
+    pub fn all(&self, name: &str) -> Vec<&'a str> {
+        self.values.get(name).cloned().unwrap_or_default()
+    }
+
+    /// a `Rest` as it was written in the line. the items of a json array line are joined with spaces
+    pub fn rest(&self, name: &str) -> String {
+        match self.rest {
+            Some(x) if self.has(name) => x.to_owned(),
+            _ => self.all(name).join(" "),
+        }
+    }
+
+    pub fn number<T: FromStr>(&self, name: &str) -> Res<T> {
+        match self.number_opt(name)? {
+            Some(x) => Ok(x),
+            None => Err(self.invalid(&format!("missing <{}>", name))),
+        }
+    }
+
+    pub fn number_opt<T: FromStr>(&self, name: &str) -> Res<Option<T>> {
+        match self.get(name).map(|x| x.parse::<T>()) {
+            Some(Ok(x)) => Ok(Some(x)),
+            Some(Err(_)) => Err(self.invalid(&format!("<{}> has to be a number", name))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn json(&self, name: &str) -> Res<json::JSON> {
+        self.json_text(self.get(name).unwrap_or(""), name)
+    }
+
+    /// a `Rest` or one of the values of a `Many` read as json
+    pub fn json_text(&self, text: &str, name: &str) -> Res<json::JSON> {
+        match serde_json::from_str::<json::JSON>(text) {
+            Ok(x) => Ok(x),
+            Err(_) => Err(self.invalid(&format!("<{}> is not valid json", name))),
+        }
+    }
+
+    /// an error saying what is wrong with the arguments and how the command is written
+    pub fn invalid(&self, what: &str) -> error::ServerError {
+        error::ServerError::SYNTAX_ERROR(format!("Error: Syntax error: {}, usage: {}", what, self.usage))
+    }
+}
+
+/// the commands of a manager. it finds the spec a command line names, checks the arguments and hands
+/// them to the spec's parse, so handlers only ever see well formed commands
+pub struct Registry<C: 'static>(pub &'static [Spec<C>]);
+
+impl<C> Registry<C> {
+    pub fn parse(&self, line: &str) -> Res<C> {
+        let (starts, tokens): (Vec<Option<usize>>, Vec<String>) = split(line)?.into_iter().unzip();
+        self.parse_in(&tokens, Some((line, &starts)))
+    }
+
+    pub fn parse_tokens(&self, tokens: &[String]) -> Res<C> {
+        self.parse_in(tokens, None)
+    }
+
+    fn parse_in(&self, tokens: &[String], line: Option<(&str, &[Option<usize>])>) -> Res<C> {
+        let mut failed = None;
+        for (spec, skip) in self.find(tokens)? {
+            let parsed = match spec.check(&tokens[skip..], line.map(|(line, starts)| (line, &starts[skip..]))) {
+                Ok(x) => x,
+                Err(e) => {
+                    failed = failed.or(Some(e));
+                    continue;
+                },
+            };
+            return match (spec.parse)(&parsed) {
+                Ok(x) => Ok(x),
+                // a value the spec let through that parse did not like
+                Err(error::ServerError::INVALID_ARG) => Err(parsed.invalid("invalid argument")),
+                Err(e) => Err(e),
+            };
+        }
+        // the arguments fit none of the specs, the error is about the one named by more words
+        Err(failed.unwrap_or(error::ServerError::INVALID_ARG))
+    }
+
+    /// the spec of every command, or of the ones whose first word is name
+    pub fn help(&self, name: &str) -> Res<json::JSON> {
+        let specs: Vec<json::JSON> = self.0.iter()
+            .filter(|x| name.is_empty() || x.name == name || x.name.split(' ').next() == Some(name))
+            .map(|x| x.to_json()).collect();
+        if specs.is_empty() {
+            return Err(self.unknown(name));
+        }
+        Ok(json::JSON::Array(specs))
+    }
+
+    /// the specs the first one or two tokens may name and how many tokens the name took, the two word
+    /// one first. `FND BY` is FND of a key named BY when its arguments do not fit FND BY
+    fn find(&self, tokens: &[String]) -> Res<Vec<(&Spec<C>, usize)>> {
+        let first = tokens.first().map(|x| x.as_str()).unwrap_or("");
+        let mut specs = Vec::new();
+        if let Some(second) = tokens.get(1) {
+            let two = format!("{} {}", first, second);
+            if let Some(spec) = self.0.iter().find(|x| x.name == two) {
+                specs.push((spec, 2));
+            }
+        }
+        if let Some(spec) = self.0.iter().find(|x| x.name == first) {
+            specs.push((spec, 1));
+        }
+        if specs.is_empty() {
+            return Err(self.unknown(first));
+        }
+        Ok(specs)
+    }
+
+    fn unknown(&self, name: &str) -> error::ServerError {
+        let names: Vec<&str> = self.0.iter().map(|x| x.name).collect();
+        error::ServerError::UNKNOWN_COMMAND(format!("Error: Unknown command {}, valid commands are {}", name, names.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: &str) -> Vec<String> {
+        parse(line).unwrap()
+    }
+
+    fn error(line: &str) -> String {
+        match parse(line) {
+            Err(error::ServerError::SYNTAX_ERROR(x)) => x,
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn whitespace_separates_arguments() {
+        assert_eq!(tokens("  INS\tk   1 "), vec!["INS", "k", "1"]);
+        assert_eq!(tokens(""), Vec::<String>::new());
+        assert_eq!(tokens(r"a\ b c\\d"), vec!["a b", r"c\d"]);
+    }
+
+    #[test]
+    fn quotes_and_escapes() {
+        assert_eq!(tokens("'Ann Lee' x'y z'"), vec!["Ann Lee", "xy z"]);
+        assert_eq!(tokens(r"'it\'s' 'a\\b' 'a\nb'"), vec!["it's", r"a\b", r"a\nb"]);
+        assert_eq!(tokens(r#""Ann Lee" "a\"b c""#), vec![r#""Ann Lee""#, r#""a\"b c""#]);
+        assert_eq!(tokens(r#"'say "hi"' "it's""#), vec![r#"say "hi""#, r#""it's""#]);
+    }
+
+    #[test]
+    fn json_values_are_one_argument() {
+        assert_eq!(tokens(r#"INS k {"a": [1, {"b": "} ]"}], "c": "\"{"} x"#), vec!["INS", "k", r#"{"a": [1, {"b": "} ]"}], "c": "\"{"}"#, "x"]);
+        assert_eq!(tokens("PUSH k [1, [2, 3]] 4"), vec!["PUSH", "k", "[1, [2, 3]]", "4"]);
+        assert_eq!(tokens(r#" ["INS", "k", {"name": "Ann Lee"}, 1]"#), vec!["INS", "k", r#"{"name":"Ann Lee"}"#, "1"]);
+    }
+
+    #[test]
+    fn unterminated_quotes() {
+        assert_eq!(error("INS 'k"), "Error: Syntax error at column 5: unterminated '");
+        assert_eq!(error(r#"INS k "ab"#), "Error: Syntax error at column 7: unterminated \"");
+        assert_eq!(error(r#"INS k "ab\"#), "Error: Syntax error at column 7: unterminated \"");
+        assert_eq!(error(r"INS 'k\"), "Error: Syntax error at column 7: nothing to escape");
+        assert_eq!(error(r"INS k\"), "Error: Syntax error at column 6: nothing to escape");
+        assert_eq!(error(r#"INS k {"a": "b}"#), "Error: Syntax error at column 13: unterminated \"");
+    }
+
+    #[test]
+    fn syntax_error_columns() {
+        assert_eq!(error(r#"INS k {"a": [1}"#), "Error: Syntax error at column 15: expected ] but found }");
+        assert_eq!(error(r#"INS k {"a": [1]"#), "Error: Syntax error at column 7: missing } for this bracket");
+        assert_eq!(error(r#"INS k {"a": 1}x"#), "Error: Syntax error at column 15: expected a space after the json value that starts at column 7");
+        // columns count characters, not bytes
+        assert_eq!(error("INS é 'k"), "Error: Syntax error at column 7: unterminated '");
+        assert!(error(r#"  ["INS", 1,]"#).starts_with("Error: Syntax error at column 13: invalid json array"));
+    }
+
+    static SPECS: Registry<String> = Registry(&[
+        Spec {
+            name: "SET",
+            args: &[Arg::Required("key"), Arg::Rest("value")],
+            help: "",
+            parse: |p| Ok(p.rest("value")),
+        },
+        Spec {
+            name: "GET",
+            args: &[Arg::Flag("-c", Some("collection")), Arg::Flag("RAW", None), Arg::Required("key")],
+            help: "the value at a key",
+            parse: |p| Ok(format!("GET {} {} {}", p.string("-c"), p.has("RAW"), p.string("key"))),
+        },
+        Spec {
+            name: "GET BY",
+            args: &[Arg::Required("path"), Arg::Required("value")],
+            help: "the keys whose path holds value",
+            parse: |p| Ok(format!("GET BY {} {}", p.string("path"), p.string("value"))),
+        },
+        Spec {
+            name: "PUSH",
+            args: &[Arg::Required("key"), Arg::Optional("at"), Arg::Many("value")],
+            help: "",
+            parse: |p| Ok(format!("PUSH {} {} {}", p.string("key"), p.number_opt::<usize>("at")?.map_or(-1, |x| x as i64), p.all("value").join(","))),
+        },
+        Spec {
+            name: "NEW",
+            args: &[Arg::Required("user"), Arg::Required("password")],
+            help: "",
+            parse: |p| {
+                if p.text("user").is_empty() {
+                    return Err(error::ServerError::INVALID_ARG);
+                }
+                Ok(format!("NEW {}:{}", p.text("user"), p.text("password")))
+            },
+        },
+    ]);
+
+    fn invalid(line: &str) -> String {
+        match SPECS.parse(line) {
+            Err(error::ServerError::SYNTAX_ERROR(x)) | Err(error::ServerError::UNKNOWN_COMMAND(x)) => x,
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn flags_and_values() {
+        assert_eq!(SPECS.parse("GET k").unwrap(), "GET  false k");
+        assert_eq!(SPECS.parse("GET raw -c users k").unwrap(), "GET users true k");
+        assert_eq!(SPECS.parse("GET -c users RAW k").unwrap(), "GET users true k");
+        assert_eq!(invalid("GET -c"), "Error: Syntax error: -c needs <collection>, usage: GET [-c <collection>] [RAW] <key>");
+        assert_eq!(invalid("GET"), "Error: Syntax error: missing <key>, usage: GET [-c <collection>] [RAW] <key>");
+        assert_eq!(invalid("GET k l"), "Error: Syntax error: unexpected argument l, usage: GET [-c <collection>] [RAW] <key>");
+    }
+
+    #[test]
+    fn optional_and_many() {
+        assert_eq!(SPECS.parse("PUSH k 1").unwrap(), "PUSH k -1 1");
+        assert_eq!(SPECS.parse("PUSH k 1 2 3").unwrap(), "PUSH k 1 2,3");
+        assert_eq!(invalid("PUSH k x 2"), "Error: Syntax error: <at> has to be a number, usage: PUSH <key> [<at>] <value>...");
+        assert_eq!(invalid("PUSH k"), "Error: Syntax error: missing <value>, usage: PUSH <key> [<at>] <value>...");
+    }
+
+    #[test]
+    fn two_word_names_only_when_the_arguments_fit() {
+        assert_eq!(SPECS.parse("GET BY a 1").unwrap(), "GET BY a 1");
+        assert_eq!(SPECS.parse("GET BY").unwrap(), "GET  false BY");
+        assert_eq!(SPECS.parse("GET -c users BY").unwrap(), "GET users false BY");
+        assert_eq!(invalid("GET BY a"), "Error: Syntax error: missing <value>, usage: GET BY <path> <value>");
+    }
+
+    #[test]
+    fn json_strings_are_unquoted() {
+        assert_eq!(SPECS.parse(r#"NEW "Ann Lee" "p\"w""#).unwrap(), r#"NEW Ann Lee:p"w"#);
+        assert_eq!(SPECS.parse(r#"NEW ann "pw"#).unwrap_err().code(), "SYNTAX_ERROR");
+        assert_eq!(SPECS.parse(r#"NEW ann \"pw"#).unwrap(), r#"NEW ann:"pw"#);
+        assert_eq!(SPECS.parse(r#"["NEW", "ann", "pw"]"#).unwrap(), "NEW ann:pw");
+        assert_eq!(invalid(r#"NEW "" pw"#), "Error: Syntax error: invalid argument, usage: NEW <user> <password>");
+    }
+
+    #[test]
+    fn unknown_commands_and_help() {
+        assert_eq!(invalid("FOO"), "Error: Unknown command FOO, valid commands are SET, GET, GET BY, PUSH, NEW");
+        assert_eq!(invalid("get k"), "Error: Unknown command get, valid commands are SET, GET, GET BY, PUSH, NEW");
+        let names = |name: &str| -> Vec<String> {
+            SPECS.help(name).unwrap().as_array().unwrap().iter().map(|x| x["command"].as_str().unwrap().to_owned()).collect()
+        };
+        assert_eq!(names("GET"), vec!["GET", "GET BY"]);
+        assert_eq!(names("").len(), 5);
+        assert_eq!(SPECS.help("FOO").unwrap_err().code(), "UNKNOWN_COMMAND");
+    }
+
+    #[test]
+    fn rest_is_the_line_as_written() {
+        assert_eq!(SPECS.parse(r#"SET k {"a":  "b"}   'c  d' \x  "#).unwrap(), r#"{"a":  "b"}   'c  d' \x"#);
+        assert_eq!(SPECS.parse("SET k é  ü").unwrap(), "é  ü");
+        assert_eq!(SPECS.parse(r#"["SET", "k", "a", {"b": 1}]"#).unwrap(), r#"a {"b":1}"#);
+        assert_eq!(SPECS.parse_tokens(&tokens("SET k a  b")).unwrap(), "a b");
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+
+use super::super::{error, json};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// how often the background task checks whether a snapshot is due
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// save once at least `writes` changes were made and `seconds` have passed since the last save,
+/// the same rule as redis' `save <seconds> <changes>`
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SavePolicy {
+    pub seconds: u64,
+    pub writes: u64,
+}
+
+/// reads policies written as pairs, `"900 1 300 10"`. `"OFF"` or nothing turns auto-save off
+pub fn parse_policies<'a, I: Iterator<Item = &'a str>>(args: I) -> Res<Vec<SavePolicy>> {
+    let args: Vec<&str> = args.filter(|x| !x.is_empty()).collect();
+    if args.len() == 1 && args[0].eq_ignore_ascii_case("OFF") {
+        return Ok(Vec::new());
+    }
+    if !args.len().is_multiple_of(2) {
+        return Err(error::ServerError::INVALID_ARG);
+    }
+    let mut policies = Vec::new();
+    for pair in args.chunks(2) {
+        match (pair[0].parse::<u64>(), pair[1].parse::<u64>()) {
+            // a policy that needs no writes would save an unchanged collection over and over
+            (Ok(seconds), Ok(writes)) if writes > 0 => {
+                policies.push(SavePolicy { seconds, writes });
+            },
+            _ => return Err(error::ServerError::INVALID_ARG),
+        }
+    }
+    Ok(policies)
+}
+
+/// tracks the writes made to a collection since its last snapshot and decides when the next one is due
+#[derive(Debug)]
+pub struct SaveState {
+    dirty: u64,
+    last_save: Option<SystemTime>,
+    // policies count from when the collection was created until its first save
+    since: Instant,
+}
+
+impl Default for SaveState {
+    fn default() -> SaveState {
+        SaveState::new()
+    }
+}
+
+impl SaveState {
+    pub fn new() -> SaveState {
+        SaveState { dirty: 0, last_save: None, since: Instant::now() }
+    }
+
+    /// counts a change that is not in the snapshot yet
+    pub fn wrote(&mut self) {
+        self.dirty += 1;
+    }
+
+    /// called after a snapshot was written
+    pub fn saved(&mut self) {
+        self.dirty = 0;
+        self.last_save = Some(SystemTime::now());
+        self.since = Instant::now();
+    }
+
+    pub fn due(&self, policies: &[SavePolicy]) -> bool {
+        if self.dirty == 0 {
+            return false;
+        }
+        let elapsed = self.since.elapsed().as_secs();
+        policies.iter().any(|p| self.dirty >= p.writes && elapsed >= p.seconds)
+    }
+
+    pub fn status(&self) -> json::JSON {
+        let last_save = self.last_save.and_then(|x| x.duration_since(UNIX_EPOCH).ok()).map(|x| x.as_secs());
+        json::to_json!({
+            "dirty": self.dirty,
+            "last_save": last_save,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(policies: &str) -> Res<Vec<(u64, u64)>> {
+        parse_policies(policies.split(' ')).map(|x| x.iter().map(|p| (p.seconds, p.writes)).collect())
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(parse("900 1 300 10").unwrap(), vec![(900, 1), (300, 10)]);
+        assert_eq!(parse("0 5").unwrap(), vec![(0, 5)]);
+        assert_eq!(parse("off").unwrap(), vec![]);
+        assert_eq!(parse("").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn invalid_policies() {
+        assert_eq!(parse("900 0").unwrap_err().code(), "INVALID_ARG");
+        assert_eq!(parse("900").unwrap_err().code(), "INVALID_ARG");
+        assert_eq!(parse("900 x").unwrap_err().code(), "INVALID_ARG");
+    }
+
+    #[test]
+    fn due_after_enough_writes() {
+        let policies = [SavePolicy { seconds: 0, writes: 2 }];
+        let mut state = SaveState::default();
+        assert!(!state.due(&policies));
+        state.wrote();
+        assert!(!state.due(&policies));
+        state.wrote();
+        assert!(state.due(&policies));
+        state.saved();
+        assert!(!state.due(&policies));
+    }
+}
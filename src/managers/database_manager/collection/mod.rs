@@ -0,0 +1,1149 @@
+use std::{borrow::Cow, collections::HashMap, ops::Bound};
+
+use super::super::{error, json};
+use super::persistence::{Persistence, WalEntry};
+use super::autosave::{SavePolicy, SaveState};
+use super::scan::{RangeArgs, ScanArgs};
+use super::store::{Store, StoreKind};
+use super::index::{self, Index};
+use super::query::Query;
+use super::expiry;
+use super::pointer;
+use super::schema::{self, Schema, Schemas};
+use super::ops::Outcome;
+use super::{insert_into, insert_parents, replace_whole, set_into, delete_from, get_from};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// the collection connections start in and the one `DB_FILE` is loaded into
+pub const DEFAULT: &str = "default";
+
+#[derive(Debug)]
+enum FileManager {
+    Closed,
+    Open(Persistence)
+}
+
+/// a named set of keys with its own backing file. every change goes through here so it is
+/// logged to that file's write-ahead log and counted towards its next auto-save
+#[derive(Debug)]
+pub struct Collection {
+    store: Store,
+    file: FileManager,
+    save: SaveState,
+    indexes: Vec<Index>,
+    // set while a transaction runs, its changes are logged together when it commits
+    pending: Option<Vec<WalEntry>>,
+    undo: Vec<Undo>,
+    // every change to a document gives it the next number of seq as its version, so a key that is
+    // deleted and inserted again never gets an old version back
+    versions: HashMap<String, u64>,
+    seq: u64,
+    // when keys with a time to live expire, in milliseconds since the unix epoch
+    expires: HashMap<String, u64>,
+    // what documents have to look like, checked on every change
+    schemas: Schemas,
+}
+
+/// what a key held before a transaction changed it, to put back if the transaction fails
+#[derive(Debug)]
+struct Undo {
+    key: String,
+    value: Option<json::JSON>,
+    version: Option<u64>,
+    expires: Option<u64>,
+}
+
+impl Collection {
+    pub fn new(kind: StoreKind) -> Collection {
+        Collection {
+            store: Store::new(kind),
+            file: FileManager::Closed,
+            save: SaveState::new(),
+            indexes: Vec::new(),
+            pending: None,
+            undo: Vec::new(),
+            versions: HashMap::new(),
+            seq: 0,
+            expires: HashMap::new(),
+            schemas: Schemas::default(),
+        }
+    }
+
+    /// opens a file at path or creates a new one if not present. returns error if fails to create new file.
+    pub fn open(&mut self, path: &str) -> Res<()> {
+        match Persistence::open(path) {
+            Ok(x) => {
+                self.file = FileManager::Open(x);
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(self.file, FileManager::Open(_))
+    }
+
+    /// the file the collection is bound to, none if it has none
+    pub fn file(&self) -> Option<String> {
+        match self.file {
+            FileManager::Closed => None,
+            FileManager::Open(ref x) => Some(x.path().to_string_lossy().into_owned()),
+        }
+    }
+
+    /// loads json object from file into store if file is opened, then replays the write-ahead log on top of it.
+    pub fn load(&mut self) -> Res<()> {
+        let loaded = match self.file {
+            FileManager::Closed => Err(error::ServerError::INVALID_FILE),
+            FileManager::Open(ref x) => x.load(),
+        };
+        let (snapshot, entries) = loaded?;
+        let paths = self.load_meta()?;
+        for (k, v) in snapshot {
+            if !self.versions.contains_key(&k) {
+                self.seq += 1;
+                self.versions.insert(k.clone(), self.seq);
+            }
+            // snapshot keys are whole keys even if they contain a dot
+            self.store.insert(k, v);
+        }
+        // entries after the last snapshot may be replayed twice if we crashed while saving,
+        // replaying an INS again is harmless and a DEL of a missing key is ignored. they were checked
+        // against the schemas when they were made, which may have been set after some of them
+        let schemas = std::mem::take(&mut self.schemas);
+        for entry in entries {
+            let _ = self.replay(entry);
+        }
+        self.schemas = schemas;
+        self.indexes = paths.iter().map(|path| build_index(&self.store, path)).collect();
+        // keys that expired while the file was closed are gone for good
+        self.sweep();
+        Ok(())
+    }
+
+    /// if a files is open the current store is saved to the file otherwise en error is returned
+    pub fn save(&mut self) -> Res<()> {
+        match self.file {
+            FileManager::Closed => Err(error::ServerError::INVALID_FILE),
+            FileManager::Open(ref mut x) => {
+                let meta = meta(&self.indexes, &self.versions, self.seq, &self.expires, &self.schemas);
+                match x.snapshot(&self.store, &meta) {
+                    Ok(_) => {
+                        self.save.saved();
+                        Ok(())
+                    },
+                    Err(e) => Err(e),
+                }
+            },
+        }
+    }
+
+    pub fn save_due(&self, policies: &[SavePolicy]) -> bool {
+        self.is_open() && self.save.due(policies)
+    }
+
+    pub fn get(&self, key: &str) -> Res<json::JSON> {
+        if self.expired(&top(key), expiry::now_ms()) {
+            // an expired key reads the same as a missing one
+            return if nested(key) { Err(error::ServerError::MISSING_DATA) } else { Ok(json::JSON::Null) };
+        }
+        get_from(&self.store, key)
+    }
+
+    /// inserts the value and logs the change. replacing a whole key clears its time to live
+    pub fn insert(&mut self, key: &str, value: json::JSON) -> Res<()> {
+        self.insert_expiring(key, value, None)
+    }
+
+    /// inserts the value and, if given, when the key expires in milliseconds since the unix epoch.
+    /// only whole keys can expire
+    pub fn insert_expiring(&mut self, key: &str, value: json::JSON, expires: Option<u64>) -> Res<()> {
+        self.write(key, value, expires, insert_into, WalEntry::ins)
+    }
+
+    /// inserts the value, creating the objects missing on the way to a nested key first
+    pub fn insert_parents(&mut self, key: &str, value: json::JSON, expires: Option<u64>) -> Res<()> {
+        self.write(key, value, expires, insert_parents, WalEntry::ins)
+    }
+
+    /// puts the value at a whole key even when it holds a value of another type
+    pub fn replace(&mut self, key: &str, value: json::JSON, expires: Option<u64>) -> Res<()> {
+        if nested(key) {
+            return Err(error::ServerError::INVALID_ARG);
+        }
+        self.write(key, value, expires, replace_whole, WalEntry::ins)
+    }
+
+    /// inserts the value the way insert does and logs it
+    fn write(&mut self, key: &str, value: json::JSON, expires: Option<u64>, insert: fn(&mut Store, String, json::JSON) -> Res<()>, entry: fn(String, json::JSON, Option<u64>) -> WalEntry) -> Res<()> {
+        if expires.is_some() && nested(key) {
+            return Err(error::ServerError::INVALID_ARG);
+        }
+        self.sweep_key(key)?;
+        let before = self.saved(key);
+        let inserted = self.indexed(key, |store| insert(store, key.to_owned(), value.clone()));
+        match inserted {
+            Ok(_) => {
+                if !nested(key) {
+                    self.set_expiry(&top(key), expires);
+                }
+                let entry = self.logged(key, entry(key.to_owned(), value, expires));
+                self.log_or_restore(entry, before)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// reads the value at key, null counting as missing, and stores what the operation makes of it
+    /// in the same step, in the place of the old value whatever its type. a whole key made null is
+    /// deleted. a key keeps its time to live. returns the operation's reply
+    pub fn update<F: FnOnce(Option<json::JSON>) -> Res<Outcome>>(&mut self, key: &str, operation: F) -> Res<json::JSON> {
+        let current = match self.get(key)? {
+            json::JSON::Null => None,
+            x => Some(x),
+        };
+        let keep = if nested(key) || self.expired(&top(key), expiry::now_ms()) { None } else { self.expires.get(&*top(key)).cloned() };
+        let (value, reply) = operation(current)?;
+        match value {
+            Some(json::JSON::Null) if !nested(key) => {
+                match self.delete(key) {
+                    Ok(_) | Err(error::ServerError::MISSING_DATA) => Ok(reply),
+                    Err(e) => Err(e),
+                }
+            },
+            Some(x) => self.write(key, x, keep, set_into, WalEntry::set).map(|_| reply),
+            None => Ok(reply),
+        }
+    }
+
+    /// deletes the key, logs the change and returns what was there
+    pub fn delete(&mut self, key: &str) -> Res<json::JSON> {
+        self.sweep_key(key)?;
+        self.remove(key)
+    }
+
+    fn remove(&mut self, key: &str) -> Res<json::JSON> {
+        let before = self.saved(key);
+        match self.indexed(key, |store| delete_from(store, key)) {
+            Ok(x) => {
+                let entry = self.logged(key, WalEntry::Del { key: key.to_owned(), version: None });
+                match self.log_or_restore(entry, before) {
+                    Ok(_) => Ok(x),
+                    Err(e) => Err(e),
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// runs a change to key, gives the document it belongs to a new version and moves it to where its
+    /// new values are filed in every index. remembers what the document was if a transaction is running
+    fn indexed<T, F: FnOnce(&mut Store) -> Res<T>>(&mut self, key: &str, change: F) -> Res<T> {
+        // a dotted key or pointer changes the document stored under its first part
+        let top = &*top(key);
+        if self.indexes.is_empty() && self.pending.is_none() && self.schemas.is_empty() {
+            let changed = change(&mut self.store)?;
+            self.bump(top);
+            return Ok(changed);
+        }
+        let old = self.store.get(top).cloned();
+        let old_version = self.versions.get(top).cloned();
+        let old_expires = self.expires.get(top).cloned();
+        let changed = change(&mut self.store)?;
+        if let Some(new) = self.store.get(top) {
+            if let Err(e) = self.schemas.validate(top, new) {
+                // the change never happened
+                match old {
+                    Some(x) => {
+                        self.store.insert(top.to_owned(), x);
+                    },
+                    None => {
+                        self.store.remove(top);
+                    },
+                }
+                return Err(e);
+            }
+        }
+        self.bump(top);
+        for index in self.indexes.iter_mut() {
+            if let Some(ref old) = old {
+                index.remove(top, old);
+            }
+            if let Some(new) = self.store.get(top) {
+                index.add(top, new);
+            }
+        }
+        if self.pending.is_some() {
+            self.undo.push(Undo { key: top.to_owned(), value: old, version: old_version, expires: old_expires });
+        }
+        Ok(changed)
+    }
+
+    fn bump(&mut self, top: &str) {
+        if self.store.get(top).is_some() {
+            self.seq += 1;
+            self.versions.insert(top.to_owned(), self.seq);
+        } else {
+            self.versions.remove(top);
+            self.expires.remove(top);
+        }
+    }
+
+    /// the version of the document key belongs to, 0 if there is none
+    pub fn version(&self, key: &str) -> u64 {
+        let top = top(key);
+        if self.expired(&top, expiry::now_ms()) {
+            return 0;
+        }
+        self.versions.get(&*top).cloned().unwrap_or(0)
+    }
+
+    fn expired(&self, top: &str, now: u64) -> bool {
+        match self.expires.get(top) {
+            Some(at) => *at <= now,
+            None => false,
+        }
+    }
+
+    fn set_expiry(&mut self, top: &str, expires: Option<u64>) {
+        match expires {
+            Some(at) => {
+                self.expires.insert(top.to_owned(), at);
+            },
+            None => {
+                self.expires.remove(top);
+            },
+        }
+    }
+
+    /// deletes the key key belongs to if its time ran out, before a write sees it
+    fn sweep_key(&mut self, key: &str) -> Res<()> {
+        let top = top(key);
+        if self.expired(&top, expiry::now_ms()) {
+            self.remove(&whole(&top))?;
+        }
+        Ok(())
+    }
+
+    /// deletes every key whose time ran out. returns the keys it deleted
+    pub fn sweep(&mut self) -> Vec<String> {
+        let now = expiry::now_ms();
+        let expired: Vec<String> = self.expires.iter().filter(|(_, at)| **at <= now).map(|(k, _)| k.clone()).collect();
+        let mut deleted = Vec::new();
+        for key in expired {
+            match self.remove(&whole(&key)) {
+                Ok(_) => deleted.push(key),
+                // the key was already gone
+                Err(_) if self.store.get(&key).is_none() => {
+                    self.expires.remove(&key);
+                },
+                Err(_) => (),
+            }
+        }
+        deleted
+    }
+
+    /// sets the key to expire after this many seconds, 0 deletes it now. returns whether the key exists
+    pub fn expire(&mut self, key: &str, seconds: u64) -> Res<bool> {
+        if nested(key) {
+            return Err(error::ServerError::INVALID_ARG);
+        }
+        self.sweep_key(key)?;
+        let key = &*top(key);
+        if self.store.get(key).is_none() {
+            return Ok(false);
+        }
+        if seconds == 0 {
+            return self.remove(&whole(key)).map(|_| true);
+        }
+        let at = expiry::after_secs(seconds);
+        let before = self.saved(key);
+        self.indexed(&whole(key), |_| Ok(()))?;
+        self.set_expiry(key, Some(at));
+        let entry = self.logged(&whole(key), WalEntry::Expire { key: key.to_owned(), at: Some(at), version: None });
+        self.log_or_restore(entry, before).map(|_| true)
+    }
+
+    /// removes the key's time to live. returns whether it had one
+    pub fn persist(&mut self, key: &str) -> Res<bool> {
+        if nested(key) {
+            return Ok(false);
+        }
+        self.sweep_key(key)?;
+        let key = &*top(key);
+        if !self.expires.contains_key(key) {
+            return Ok(false);
+        }
+        let before = self.saved(key);
+        self.indexed(&whole(key), |_| Ok(()))?;
+        self.set_expiry(key, None);
+        let entry = self.logged(&whole(key), WalEntry::Expire { key: key.to_owned(), at: None, version: None });
+        self.log_or_restore(entry, before).map(|_| true)
+    }
+
+    /// seconds until the key expires, -1 if it does not expire and -2 if there is no such key
+    pub fn ttl(&self, key: &str) -> i64 {
+        if nested(key) {
+            return -2;
+        }
+        let key = &*top(key);
+        let now = expiry::now_ms();
+        if self.store.get(key).is_none() || self.expired(key, now) {
+            return -2;
+        }
+        match self.expires.get(key) {
+            Some(at) => (at - now).div_ceil(1000) as i64,
+            None => -1,
+        }
+    }
+
+    /// inserts the value only if the document key belongs to still has the expected version, 0 expects
+    /// no document. returns the new version
+    pub fn cas(&mut self, key: &str, expected: u64, value: json::JSON) -> Res<u64> {
+        if self.version(key) != expected {
+            return Err(error::ServerError::CONFLICT);
+        }
+        match self.insert(key, value) {
+            Ok(_) => Ok(self.version(key)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// starts holding back changes from the log until `commit` or `rollback`
+    pub fn begin(&mut self) {
+        self.pending = Some(Vec::new());
+        self.undo.clear();
+    }
+
+    /// logs every change made since `begin` as one entry. if the log cannot take it every change is
+    /// put back. until `end` a committed transaction can still be taken back with `revert`
+    pub fn commit(&mut self) -> Res<()> {
+        match self.pending.take() {
+            Some(entries) if !entries.is_empty() => {
+                let count = entries.len();
+                match self.log(WalEntry::Txn { entries }) {
+                    Ok(_) => {
+                        // log counted the transaction once
+                        for _ in 1..count {
+                            self.save.wrote();
+                        }
+                        Ok(())
+                    },
+                    Err(e) => {
+                        self.rollback();
+                        Err(e)
+                    },
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// takes back a committed transaction because the part of it in another collection failed. the
+    /// documents it changed are put back and logged the way they are again
+    pub fn revert(&mut self) -> Res<()> {
+        let mut keys: Vec<String> = self.undo.iter().map(|x| x.key.clone()).collect();
+        keys.sort();
+        keys.dedup();
+        self.rollback();
+        let mut entries = Vec::new();
+        for key in keys {
+            // deleting first lets the insert replay even if the transaction changed the type of the value
+            entries.push(WalEntry::Del { key: whole(&key), version: None });
+            if let Some(x) = self.store.get(&key) {
+                let version = self.versions.get(&key).cloned();
+                entries.push(WalEntry::Ins { key: whole(&key), value: x.clone(), expires: self.expires.get(&key).cloned(), version });
+            }
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.log(WalEntry::Txn { entries })
+    }
+
+    /// forgets what a committed transaction changed, it can not be reverted anymore
+    pub fn end(&mut self) {
+        self.undo.clear();
+    }
+
+    /// puts back every document changed since `begin`
+    pub fn rollback(&mut self) {
+        self.pending = None;
+        let undo: Vec<Undo> = self.undo.drain(..).collect();
+        for undo in undo.into_iter().rev() {
+            self.restore(undo);
+        }
+    }
+
+    /// the document key belongs to as it is now, to put back with `restore`
+    fn saved(&self, key: &str) -> Undo {
+        let top = top(key).into_owned();
+        Undo {
+            value: self.store.get(&top).cloned(),
+            version: self.versions.get(&top).cloned(),
+            expires: self.expires.get(&top).cloned(),
+            key: top,
+        }
+    }
+
+    /// puts a document back the way it was, in the store and in every index
+    fn restore(&mut self, undo: Undo) {
+        let Undo { key: top, value: old, version: old_version, expires } = undo;
+        let current = self.store.get(&top).cloned();
+        self.set_expiry(&top, expires);
+        for index in self.indexes.iter_mut() {
+            if let Some(ref current) = current {
+                index.remove(&top, current);
+            }
+            if let Some(ref old) = old {
+                index.add(&top, old);
+            }
+        }
+        match old_version {
+            Some(x) => {
+                self.versions.insert(top.clone(), x);
+            },
+            None => {
+                self.versions.remove(&top);
+            },
+        }
+        match old {
+            Some(x) => {
+                self.store.insert(top, x);
+            },
+            None => {
+                self.store.remove(&top);
+            },
+        }
+    }
+
+    /// starts indexing the documents of this collection by the value at path
+    pub fn create_index(&mut self, path: &str) -> Res<()> {
+        if path.is_empty() || path.split('.').any(|x| x.is_empty()) {
+            return Err(error::ServerError::INVALID_ARG);
+        }
+        if self.indexes.iter().any(|x| x.path() == path) {
+            return Err(error::ServerError::TAKEN);
+        }
+        self.indexes.push(build_index(&self.store, path));
+        self.save_meta()
+    }
+
+    pub fn drop_index(&mut self, path: &str) -> Res<()> {
+        match self.indexes.iter().position(|x| x.path() == path) {
+            Some(i) => {
+                self.indexes.remove(i);
+                self.save_meta()
+            },
+            None => Err(error::ServerError::MISSING_DATA),
+        }
+    }
+
+    pub fn index_paths(&self) -> Vec<&str> {
+        self.indexes.iter().map(|x| x.path()).collect()
+    }
+
+    /// makes every change to the document at key match the schema, `*` every document. fails if a
+    /// document already there does not match it
+    pub fn set_schema(&mut self, key: &str, source: &json::JSON) -> Res<()> {
+        if key != schema::ALL && nested(key) {
+            return Err(error::ServerError::INVALID_ARG);
+        }
+        let schema = Schema::parse(source)?;
+        let now = expiry::now_ms();
+        if key == schema::ALL {
+            for (k, doc) in self.store.iter() {
+                if !self.expired(k, now) {
+                    schema.validate(doc, &pointer::escape(k))?;
+                }
+            }
+        } else if let Some(doc) = self.store.get(&top(key)) {
+            if !self.expired(&top(key), now) {
+                schema.validate(doc, &pointer::escape(&top(key)))?;
+            }
+        }
+        let key = if key == schema::ALL { key.to_owned() } else { top(key).into_owned() };
+        self.schemas.set(&key, schema);
+        self.save_meta()
+    }
+
+    pub fn drop_schema(&mut self, key: &str) -> Res<()> {
+        let key = if key == schema::ALL { Cow::Borrowed(key) } else { top(key) };
+        if !self.schemas.remove(&key) {
+            return Err(error::ServerError::MISSING_DATA);
+        }
+        self.save_meta()
+    }
+
+    /// the schema of the document at key, `*` the one of every document, null if there is none
+    pub fn schema(&self, key: &str) -> json::JSON {
+        let key = if key == schema::ALL { Cow::Borrowed(key) } else { top(key) };
+        match self.schemas.get(&key) {
+            Some(x) => x.source().clone(),
+            None => json::JSON::Null,
+        }
+    }
+
+    /// every schema by the key it belongs to
+    pub fn schemas(&self) -> json::JSON {
+        self.schemas.to_json()
+    }
+
+    /// every document whose value at path equals value, by key. uses the index on path if there is
+    /// one and looks through every document otherwise
+    pub fn find_by(&self, path: &str, value: &json::JSON) -> json::JSON {
+        let mut found = serde_json::Map::new();
+        match self.indexes.iter().find(|x| x.path() == path) {
+            Some(index) => {
+                let now = expiry::now_ms();
+                for key in index.find(value) {
+                    if self.expired(key, now) {
+                        continue;
+                    }
+                    if let Some(doc) = self.store.get(key) {
+                        found.insert(key.clone(), doc.clone());
+                    }
+                }
+            },
+            None => {
+                let lookup = Index::new(path);
+                let wanted = Some(index::key(value));
+                let now = expiry::now_ms();
+                for (key, doc) in self.store.iter() {
+                    if lookup.value_of(doc) == wanted && !self.expired(key, now) {
+                        found.insert(key.clone(), doc.clone());
+                    }
+                }
+            },
+        }
+        json::JSON::Object(found)
+    }
+
+    /// every document the query's filter matches as `{"key", "value"}` pairs, in key order unless
+    /// the query sorts them
+    pub fn query(&self, query: &Query) -> json::JSON {
+        let now = expiry::now_ms();
+        let matching = self.store.range(Bound::Unbounded, Bound::Unbounded, false)
+            .filter(|(key, doc)| !self.expired(key, now) && query.matches(doc));
+        let found: Vec<(&String, &json::JSON)> = if query.is_sorted() {
+            let mut found: Vec<(&String, &json::JSON)> = matching.collect();
+            found.sort_by(|a, b| query.compare(a.1, b.1));
+            found.truncate(query.limit().unwrap_or(usize::MAX));
+            found
+        } else {
+            matching.take(query.limit().unwrap_or(usize::MAX)).collect()
+        };
+        json::JSON::Array(found.into_iter().map(|(k, v)| json::to_json!({"key": k, "value": query.project(v)})).collect())
+    }
+
+    /// writes the index definitions next to the snapshot so they are rebuilt on the next LOAD
+    fn save_meta(&self) -> Res<()> {
+        match self.file {
+            FileManager::Closed => Ok(()),
+            FileManager::Open(ref x) => x.save_meta(&meta(&self.indexes, &self.versions, self.seq, &self.expires, &self.schemas)),
+        }
+    }
+
+    /// reads the versions saved with the file and returns the paths of the indexes to rebuild once
+    /// the store is loaded, the ones saved with the file and the ones created before
+    fn load_meta(&mut self) -> Res<Vec<String>> {
+        let mut paths: Vec<String> = self.indexes.iter().map(|x| x.path().to_owned()).collect();
+        if let FileManager::Open(ref x) = self.file {
+            let meta = x.load_meta()?;
+            if let Some(saved) = meta.get("indexes").and_then(|x| x.as_array()) {
+                for path in saved.iter().filter_map(|x| x.as_str()) {
+                    if !paths.iter().any(|x| x == path) {
+                        paths.push(path.to_owned());
+                    }
+                }
+            }
+            if let Some(saved) = meta.get("versions").and_then(|x| x.as_object()) {
+                for (k, v) in saved {
+                    if let Some(v) = v.as_u64() {
+                        self.versions.insert(k.clone(), v);
+                    }
+                }
+            }
+            if let Some(seq) = meta.get("seq").and_then(|x| x.as_u64()) {
+                self.seq = self.seq.max(seq);
+            }
+            if let Some(saved) = meta.get("expires").and_then(|x| x.as_object()) {
+                for (k, v) in saved {
+                    if let Some(v) = v.as_u64() {
+                        self.expires.insert(k.clone(), v);
+                    }
+                }
+            }
+            if let Some(saved) = meta.get("schemas").and_then(|x| x.as_object()) {
+                for (k, v) in saved {
+                    if let Ok(schema) = Schema::parse(v) {
+                        self.schemas.set(k, schema);
+                    }
+                }
+            }
+        }
+        Ok(paths)
+    }
+
+    /// applies a change read back from the write-ahead log without logging it again
+    fn replay(&mut self, entry: WalEntry) -> Res<()> {
+        match entry {
+            WalEntry::Ins { key, value, expires, version } => {
+                // an insert that made its parents logs like any other, so replay makes them too. a
+                // whole key is set to what was logged, whatever it held
+                let insert = if nested(&key) { insert_parents } else { replace_whole };
+                self.indexed(&key.clone(), |store| insert(store, key.clone(), value))?;
+                if !nested(&key) {
+                    self.set_expiry(&top(&key), expires);
+                }
+                self.set_version(&key, version);
+                Ok(())
+            },
+            WalEntry::Set { key, value, expires, version } => {
+                self.indexed(&key.clone(), |store| set_into(store, key.clone(), value))?;
+                if !nested(&key) {
+                    self.set_expiry(&top(&key), expires);
+                }
+                self.set_version(&key, version);
+                Ok(())
+            },
+            WalEntry::Del { key, version } => {
+                self.indexed(&key, |store| delete_from(store, &key).map(|_| ()))?;
+                self.set_version(&key, version);
+                Ok(())
+            },
+            WalEntry::Expire { key, at, version } => {
+                if self.store.get(&key).is_some() {
+                    // a new time to live is a change like any other and gives the document a new version
+                    self.indexed(&whole(&key), |_| Ok(()))?;
+                    self.set_expiry(&key, at);
+                    self.set_version(&whole(&key), version);
+                }
+                Ok(())
+            },
+            WalEntry::Txn { entries } => {
+                for entry in entries {
+                    let _ = self.replay(entry);
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// the page of keys a SCAN asked for, with their values if it asked for them
+    pub fn scan(&self, args: &ScanArgs) -> json::JSON {
+        let now = expiry::now_ms();
+        let keys = self.store.range(args.start(), Bound::Unbounded, false).map(|(k, _)| k).filter(|k| !self.expired(k, now));
+        let (page, cursor) = args.page(keys);
+        if args.values {
+            let mut values = serde_json::Map::new();
+            for key in page {
+                values.insert(key.clone(), self.store.get(key).cloned().unwrap_or(json::JSON::Null));
+            }
+            json::to_json!({"cursor": cursor, "values": values})
+        } else {
+            json::to_json!({"cursor": cursor, "keys": page})
+        }
+    }
+
+    /// the keys between the bounds of a RANGE in order, each with its value unless only keys were asked for
+    pub fn range(&self, args: &RangeArgs) -> json::JSON {
+        let (start, end) = args.bounds();
+        let now = expiry::now_ms();
+        let entries = self.store.range(start, end, args.reverse)
+            .filter(|(k, _)| !self.expired(k, now))
+            .take(args.limit.unwrap_or(usize::MAX));
+        if args.keys_only {
+            json::to_json!(entries.map(|(k, _)| k).collect::<Vec<_>>())
+        } else {
+            json::JSON::Array(entries.map(|(k, v)| json::to_json!({"key": k, "value": v})).collect())
+        }
+    }
+
+    /// what the log records for a change to key, with the version it left the document at. inserting
+    /// or removing an array element is not safe to replay twice, so a change made through a pointer
+    /// logs the whole document it left behind
+    fn logged(&self, key: &str, entry: WalEntry) -> WalEntry {
+        let top = top(key);
+        let version = self.versions.get(&*top).cloned();
+        let entry = if !key.starts_with('/') || !nested(key) {
+            entry
+        } else {
+            match self.store.get(&top) {
+                Some(x) => WalEntry::Ins { key: whole(&top), value: x.clone(), expires: self.expires.get(&*top).cloned(), version: None },
+                None => WalEntry::Del { key: whole(&top), version: None },
+            }
+        };
+        entry.with_version(version)
+    }
+
+    /// gives the document key belongs to the version a replayed change logged, so a version handed out
+    /// before a crash never comes back for a different document
+    fn set_version(&mut self, key: &str, version: Option<u64>) {
+        if let Some(version) = version {
+            let top = top(key);
+            if self.store.get(&top).is_some() {
+                self.versions.insert(top.into_owned(), version);
+            }
+            self.seq = self.seq.max(version);
+        }
+    }
+
+    /// logs a change already made to the store. if the log cannot take it the document is put back
+    /// the way it was before, so nothing that is not in the log can reach the next snapshot
+    fn log_or_restore(&mut self, entry: WalEntry, before: Undo) -> Res<()> {
+        match self.log(entry) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.restore(before);
+                Err(e)
+            },
+        }
+    }
+
+    /// makes every append to the write-ahead log fail from now on
+    #[cfg(test)]
+    pub fn break_log(&mut self) {
+        if let FileManager::Open(ref mut x) = self.file {
+            x.break_log();
+        }
+    }
+
+    /// appends a change to the write-ahead log if a file is open
+    fn log(&mut self, entry: WalEntry) -> Res<()> {
+        if let Some(ref mut pending) = self.pending {
+            pending.push(entry);
+            return Ok(());
+        }
+        let logged = match self.file {
+            FileManager::Closed => Ok(()),
+            FileManager::Open(ref mut x) => x.log(&entry),
+        };
+        if logged.is_ok() {
+            self.save.wrote();
+        }
+        logged
+    }
+
+    /// where the collection is saved, how many keys it has and how many writes are not saved yet
+    pub fn status(&self) -> json::JSON {
+        let mut status = self.save.status();
+        status["keys"] = json::to_json!(self.store.len());
+        status["store"] = json::to_json!(self.store.kind().name());
+        status["indexes"] = json::to_json!(self.index_paths());
+        status["expiring"] = json::to_json!(self.expires.len());
+        status["file"] = match self.file {
+            FileManager::Closed => json::JSON::Null,
+            FileManager::Open(ref x) => json::to_json!(x.path()),
+        };
+        status
+    }
+}
+
+fn build_index(store: &Store, path: &str) -> Index {
+    let mut index = Index::new(path);
+    for (key, doc) in store.iter() {
+        index.add(key, doc);
+    }
+    index
+}
+
+fn meta(indexes: &[Index], versions: &HashMap<String, u64>, seq: u64, expires: &HashMap<String, u64>, schemas: &Schemas) -> json::JSON {
+    json::to_json!({
+        "indexes": indexes.iter().map(|x| x.path()).collect::<Vec<_>>(),
+        "versions": versions,
+        "seq": seq,
+        "expires": expires,
+        "schemas": schemas.to_json(),
+    })
+}
+
+/// the top-level key a dotted key or json pointer belongs to
+fn top(key: &str) -> Cow<'_, str> {
+    if key.starts_with('/') {
+        return match pointer::parse(key) {
+            Ok(tokens) if !tokens.is_empty() => Cow::Owned(tokens[0].clone()),
+            _ => Cow::Borrowed(key),
+        };
+    }
+    Cow::Borrowed(key.split('.').next().unwrap_or(key))
+}
+
+/// whether key points inside a document rather than at a whole one
+fn nested(key: &str) -> bool {
+    match key.strip_prefix('/') {
+        Some(x) => x.contains('/'),
+        None => key.contains('.'),
+    }
+}
+
+/// how to write a top-level key so a dot or leading slash in it is not read as a path
+fn whole(top: &str) -> String {
+    if top.contains('.') || top.starts_with('/') {
+        pointer::escape(top)
+    } else {
+        top.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a fresh file in the temp directory, gone once the test is done with it
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new() -> TempFile {
+            let path = std::env::temp_dir().join(format!("collection-{}.json", uuid::Uuid::new_v4()));
+            TempFile(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            for extension in ["", ".wal", ".meta", ".tmp"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.0, extension));
+            }
+        }
+    }
+
+    fn opened(file: &TempFile) -> Collection {
+        let mut collection = Collection::new(StoreKind::Hash);
+        collection.open(&file.0).unwrap();
+        collection.load().unwrap();
+        collection
+    }
+
+    #[test]
+    fn failed_log_undoes_the_change() {
+        let file = TempFile::new();
+        let mut collection = opened(&file);
+        collection.create_index("name").unwrap();
+        collection.insert("a", json::to_json!({"name": "x"})).unwrap();
+        collection.insert("b", json::to_json!(1)).unwrap();
+        let version = collection.version("a");
+        collection.break_log();
+
+        assert_eq!(collection.insert("a", json::to_json!({"name": "y"})).unwrap_err().code(), "FAILED_WRITE");
+        assert_eq!(collection.insert("a.name", json::to_json!("z")).unwrap_err().code(), "FAILED_WRITE");
+        assert_eq!(collection.insert("c", json::to_json!(2)).unwrap_err().code(), "FAILED_WRITE");
+        assert_eq!(collection.delete("b").unwrap_err().code(), "FAILED_WRITE");
+        assert_eq!(collection.expire("b", 10).unwrap_err().code(), "FAILED_WRITE");
+
+        assert_eq!(collection.get("a").unwrap(), json::to_json!({"name": "x"}));
+        assert_eq!(collection.version("a"), version);
+        assert_eq!(collection.get("b").unwrap(), json::to_json!(1));
+        assert_eq!(collection.ttl("b"), -1);
+        assert_eq!(collection.get("c").unwrap(), json::JSON::Null);
+        assert_eq!(collection.find_by("name", &json::to_json!("x")), json::to_json!({"a": {"name": "x"}}));
+        assert_eq!(collection.find_by("name", &json::to_json!("y")), json::to_json!({}));
+    }
+
+    #[test]
+    fn log_is_replayed_on_load() {
+        let file = TempFile::new();
+        let mut collection = opened(&file);
+        collection.insert("a", json::to_json!({"list": [1, 2]})).unwrap();
+        collection.insert("/a/list/0", json::to_json!(0)).unwrap();
+        collection.insert("a.name", json::to_json!("x")).unwrap();
+        collection.insert("b", json::to_json!(1)).unwrap();
+        collection.delete("b").unwrap();
+        collection.insert_parents("c.d.e", json::to_json!(true), None).unwrap();
+        drop(collection);
+
+        let collection = opened(&file);
+        assert_eq!(collection.get("a").unwrap(), json::to_json!({"list": [0, 1, 2], "name": "x"}));
+        assert_eq!(collection.get("b").unwrap(), json::JSON::Null);
+        assert_eq!(collection.get("c").unwrap(), json::to_json!({"d": {"e": true}}));
+    }
+
+    #[test]
+    fn snapshot_empties_the_log() {
+        let file = TempFile::new();
+        let mut collection = opened(&file);
+        collection.insert("a", json::to_json!(1)).unwrap();
+        collection.save().unwrap();
+        assert_eq!(std::fs::metadata(format!("{}.wal", file.0)).unwrap().len(), 0);
+        collection.insert("b", json::to_json!(2)).unwrap();
+        drop(collection);
+
+        let collection = opened(&file);
+        assert_eq!(collection.get("a").unwrap(), json::to_json!(1));
+        assert_eq!(collection.get("b").unwrap(), json::to_json!(2));
+    }
+
+    #[test]
+    fn torn_last_entry_is_dropped() {
+        let file = TempFile::new();
+        let mut collection = opened(&file);
+        collection.insert("a", json::to_json!(1)).unwrap();
+        drop(collection);
+        let mut wal = std::fs::OpenOptions::new().append(true).open(format!("{}.wal", file.0)).unwrap();
+        std::io::Write::write_all(&mut wal, br#"{"op":"INS","key":"b","val"#).unwrap();
+
+        let collection = opened(&file);
+        assert_eq!(collection.get("a").unwrap(), json::to_json!(1));
+        assert_eq!(collection.get("b").unwrap(), json::JSON::Null);
+    }
+
+    #[test]
+    fn committed_transaction_is_replayed() {
+        let file = TempFile::new();
+        let mut collection = opened(&file);
+        collection.begin();
+        collection.insert("a", json::to_json!(1)).unwrap();
+        collection.insert("b", json::to_json!(2)).unwrap();
+        collection.commit().unwrap();
+        collection.begin();
+        collection.insert("c", json::to_json!(3)).unwrap();
+        collection.rollback();
+        drop(collection);
+
+        let collection = opened(&file);
+        assert_eq!(collection.get("a").unwrap(), json::to_json!(1));
+        assert_eq!(collection.get("b").unwrap(), json::to_json!(2));
+        assert_eq!(collection.get("c").unwrap(), json::JSON::Null);
+    }
+
+    #[test]
+    fn versions_survive_a_crash() {
+        let file = TempFile::new();
+        let mut collection = opened(&file);
+        collection.insert("a", json::to_json!(1)).unwrap();
+        collection.save().unwrap();
+        collection.insert("b", json::to_json!(2)).unwrap();
+        collection.insert("a", json::to_json!(3)).unwrap();
+        let stale = collection.version("a");
+        collection.expire("a", 100).unwrap();
+        let version = collection.version("a");
+        assert!(version > stale);
+        let b = collection.version("b");
+        drop(collection);
+
+        let mut collection = opened(&file);
+        assert_eq!(collection.version("a"), version);
+        assert_eq!(collection.version("b"), b);
+        assert_eq!(collection.cas("a", stale, json::to_json!(5)).unwrap_err().code(), "CONFLICT");
+        let next = collection.cas("a", version, json::to_json!(5)).unwrap();
+        assert!(next > version && next > b);
+        assert_eq!(collection.get("a").unwrap(), json::to_json!(5));
+    }
+
+    #[test]
+    fn ttls_survive_save_and_load() {
+        let file = TempFile::new();
+        let mut collection = opened(&file);
+        collection.insert_expiring("live", json::to_json!(1), Some(expiry::after_secs(100))).unwrap();
+        collection.insert_expiring("dead", json::to_json!(2), Some(expiry::now_ms() + 50)).unwrap();
+        collection.insert("forever", json::to_json!(3)).unwrap();
+        collection.save().unwrap();
+        // these only reach the log
+        collection.insert_expiring("logged", json::to_json!(4), Some(expiry::after_secs(100))).unwrap();
+        collection.expire("forever", 200).unwrap();
+        drop(collection);
+        std::thread::sleep(std::time::Duration::from_millis(60));
+
+        let collection = opened(&file);
+        assert!((99..=100).contains(&collection.ttl("live")));
+        assert!((99..=100).contains(&collection.ttl("logged")));
+        assert!((199..=200).contains(&collection.ttl("forever")));
+        assert_eq!(collection.ttl("dead"), -2);
+        assert_eq!(collection.get("dead").unwrap(), json::JSON::Null);
+        assert!(collection.store.get("dead").is_none());
+    }
+
+    #[test]
+    fn expired_keys_read_as_missing() {
+        let mut collection = Collection::new(StoreKind::Hash);
+        collection.insert_expiring("k", json::to_json!({"x": 1}), Some(expiry::now_ms() - 1)).unwrap();
+        assert_eq!(collection.get("k").unwrap(), json::JSON::Null);
+        assert_eq!(collection.get("k.x").unwrap_err().code(), "MISSING_DATA");
+        assert_eq!(collection.ttl("k"), -2);
+        // it is only taken out by the next write to it or a sweep
+        assert!(collection.store.get("k").is_some());
+        assert_eq!(collection.sweep(), vec!["k"]);
+        assert!(collection.store.get("k").is_none());
+        assert!(collection.sweep().is_empty());
+
+        collection.insert_expiring("k", json::to_json!(1), Some(expiry::now_ms() - 1)).unwrap();
+        collection.insert("k", json::to_json!(2)).unwrap();
+        assert_eq!(collection.ttl("k"), -1);
+    }
+
+    #[test]
+    fn ttl_values() {
+        let mut collection = Collection::new(StoreKind::Hash);
+        collection.insert_expiring("a", json::to_json!(1), Some(expiry::now_ms() + 1500)).unwrap();
+        collection.insert_expiring("b", json::to_json!(1), Some(expiry::now_ms() + 100)).unwrap();
+        collection.insert("c", json::to_json!({"x": 1})).unwrap();
+        // seconds left are rounded up
+        assert_eq!(collection.ttl("a"), 2);
+        assert_eq!(collection.ttl("b"), 1);
+        assert_eq!(collection.ttl("c"), -1);
+        assert_eq!(collection.ttl("c.x"), -2);
+        assert_eq!(collection.ttl("missing"), -2);
+
+        assert!(collection.expire("c", 10).unwrap());
+        assert_eq!(collection.ttl("c"), 10);
+        assert!(!collection.expire("missing", 10).unwrap());
+        assert_eq!(collection.expire("c.x", 10).unwrap_err().code(), "INVALID_ARG");
+        assert!(collection.expire("c", 0).unwrap());
+        assert_eq!(collection.get("c").unwrap(), json::JSON::Null);
+    }
+
+    #[test]
+    fn persist_keeps_a_key_forever() {
+        let file = TempFile::new();
+        let mut collection = opened(&file);
+        collection.insert("k", json::to_json!({"x": 1})).unwrap();
+        assert!(!collection.persist("k").unwrap());
+        collection.expire("k", 100).unwrap();
+        assert!(!collection.persist("k.x").unwrap());
+        assert!(collection.persist("k").unwrap());
+        assert_eq!(collection.ttl("k"), -1);
+        assert!(!collection.persist("k").unwrap());
+        assert!(!collection.persist("missing").unwrap());
+        drop(collection);
+
+        assert_eq!(opened(&file).ttl("k"), -1);
+    }
+
+    #[test]
+    fn ranges_are_the_same_for_both_kinds() {
+        let (mut hash, mut ordered) = (Collection::new(StoreKind::Hash), Collection::new(StoreKind::Ordered));
+        for collection in [&mut hash, &mut ordered] {
+            for k in ["c", "a", "e", "b", "d"] {
+                collection.insert(k, json::to_json!(k)).unwrap();
+            }
+            collection.insert_expiring("bb", json::to_json!(0), Some(expiry::now_ms() - 1)).unwrap();
+        }
+        let range = |start: &str, end: &str, limit: Option<usize>, reverse: bool| {
+            let args = RangeArgs::new(start, end, limit, reverse, true).unwrap();
+            let keys = ordered.range(&args);
+            assert_eq!(hash.range(&args), keys);
+            keys
+        };
+        assert_eq!(range("-", "+", None, false), json::to_json!(["a", "b", "c", "d", "e"]));
+        assert_eq!(range("-", "+", Some(2), true), json::to_json!(["e", "d"]));
+        assert_eq!(range("b", "d", Some(0), false), json::to_json!([]));
+        assert_eq!(range("b", "+", Some(2), false), json::to_json!(["b", "c"]));
+        assert_eq!(range("d", "b", None, true), json::to_json!([]));
+        let args = RangeArgs::new("a", "b", None, false, false).unwrap();
+        assert_eq!(hash.range(&args), json::to_json!([{"key": "a", "value": "a"}, {"key": "b", "value": "b"}]));
+    }
+
+    #[test]
+    fn find_by_is_the_same_with_and_without_an_index() {
+        let mut collection = Collection::new(StoreKind::Hash);
+        collection.insert("a", json::to_json!({"n": 1})).unwrap();
+        collection.insert("b", json::to_json!({"n": 1.0})).unwrap();
+        collection.insert("c", json::to_json!({"n": 2})).unwrap();
+        let scanned = collection.find_by("n", &json::to_json!(1));
+        collection.create_index("n").unwrap();
+        assert_eq!(collection.find_by("n", &json::to_json!(1)), scanned);
+        assert_eq!(scanned.as_object().unwrap().len(), 2);
+    }
+}
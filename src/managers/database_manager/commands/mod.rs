@@ -0,0 +1,416 @@
+use super::super::{error, json, command::{self, Arg, Parsed, Spec}};
+use super::{autosave, autosave::SavePolicy, query, scan, watch};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// the collection a command runs on, named with `-c <name>` or the one picked with USE
+pub type Target = Option<String>;
+
+/// a command sent to the database, its arguments already checked
+#[derive(Debug, Clone)]
+pub enum Command {
+    Help(String),
+    New { user: String, password: String },
+    Use(String),
+    Collections,
+    Open { collection: Option<String>, file: String },
+    Load(Target),
+    Save(Target),
+    Status(Target),
+    IndexCreate { collection: String, path: String },
+    IndexDrop { collection: String, path: String },
+    IndexList(String),
+    SchemaSet { collection: String, key: String, schema: json::JSON },
+    SchemaGet { collection: String, key: String },
+    SchemaDrop { collection: String, key: String },
+    SchemaList(String),
+    Multi,
+    MultiWatch(Target, Vec<String>),
+    Discard,
+    Exec,
+    Watch(Target, watch::Pattern),
+    Unwatch(Option<u64>),
+    Autosave(Vec<SavePolicy>),
+    /// a command on the keys of a collection, these can be queued in a transaction
+    Keys(Target, Op),
+}
+
+#[derive(Debug, Clone)]
+pub enum Op {
+    Read(Read),
+    Write(Write),
+}
+
+/// commands that only read a collection
+#[derive(Debug, Clone)]
+pub enum Read {
+    Find(String),
+    FindBy { path: String, value: json::JSON },
+    Scan(scan::ScanArgs),
+    Range(scan::RangeArgs),
+    Query(query::Query),
+    Version(String),
+    Ttl(String),
+}
+
+/// commands that change a collection
+#[derive(Debug, Clone)]
+pub enum Write {
+    Insert { key: String, value: json::JSON, parents: bool, replace: bool, expires: Option<u64> },
+    Delete(String),
+    Cas { key: String, expected: u64, value: json::JSON },
+    Expire { key: String, seconds: u64 },
+    Persist(String),
+    Incr { key: String, by: json::JSON },
+    Decr { key: String, by: json::JSON },
+    Push { key: String, front: bool, values: Vec<json::JSON> },
+    Pop { key: String, front: bool },
+    Remove { key: String, value: json::JSON },
+    Append { key: String, suffix: String },
+    Merge { key: String, patch: json::JSON },
+    Patch { key: String, operations: json::JSON },
+}
+
+impl Write {
+    /// the name watchers see the change under
+    pub fn name(&self) -> &'static str {
+        match self {
+            Write::Insert { .. } => "INS",
+            Write::Delete(_) => "DEL",
+            Write::Cas { .. } => "CAS",
+            Write::Expire { .. } => "EXPIRE",
+            Write::Persist(_) => "PERSIST",
+            Write::Incr { .. } => "INCR",
+            Write::Decr { .. } => "DECR",
+            Write::Push { .. } => "PUSH",
+            Write::Pop { .. } => "POP",
+            Write::Remove { .. } => "REMOVE",
+            Write::Append { .. } => "APPEND",
+            Write::Merge { .. } => "MERGE",
+            Write::Patch { .. } => "PATCH",
+        }
+    }
+
+    /// the key the command changes
+    pub fn key(&self) -> &str {
+        match self {
+            Write::Insert { key, .. } | Write::Cas { key, .. } | Write::Expire { key, .. } | Write::Incr { key, .. } |
+            Write::Decr { key, .. } | Write::Push { key, .. } | Write::Pop { key, .. } | Write::Remove { key, .. } |
+            Write::Append { key, .. } | Write::Merge { key, .. } | Write::Patch { key, .. } => key,
+            Write::Delete(key) | Write::Persist(key) => key,
+        }
+    }
+}
+
+const COLLECTION: Arg = Arg::Flag("-c", Some("collection"));
+
+/// every command the database understands, `HELP` lists them
+pub static COMMANDS: command::Registry<Command> = command::Registry(&[
+    Spec {
+        name: "HELP",
+        args: &[Arg::Optional("command")],
+        help: "how the commands are written, all of them or the ones with this name",
+        parse: |p| Ok(Command::Help(p.string("command"))),
+    },
+    Spec {
+        name: "NEW",
+        args: &[Arg::Required("user"), Arg::Required("password")],
+        help: "logs the connection in, every other command needs it",
+        parse: |p| Ok(Command::New { user: p.text("user"), password: p.text("password") }),
+    },
+    Spec {
+        name: "USE",
+        args: &[Arg::Required("collection")],
+        help: "picks the collection commands without -c run on, creating it if there is none",
+        parse: |p| Ok(Command::Use(p.string("collection"))),
+    },
+    Spec {
+        name: "COLLECTIONS",
+        args: &[],
+        help: "the names of the collections",
+        parse: |_| Ok(Command::Collections),
+    },
+    Spec {
+        name: "OPEN",
+        args: &[Arg::Optional("collection"), Arg::Required("file")],
+        help: "binds a collection to the .json file it is saved to and loaded from",
+        parse: |p| Ok(Command::Open { collection: p.get("collection").map(|x| x.to_owned()), file: p.string("file") }),
+    },
+    Spec {
+        name: "LOAD",
+        args: &[COLLECTION],
+        help: "reads a collection back from its file and log",
+        parse: |p| Ok(Command::Load(target(p))),
+    },
+    Spec {
+        name: "SAVE",
+        args: &[COLLECTION],
+        help: "writes a snapshot of a collection to its file",
+        parse: |p| Ok(Command::Save(target(p))),
+    },
+    Spec {
+        name: "STATUS",
+        args: &[COLLECTION],
+        help: "the size, file and save state of a collection",
+        parse: |p| Ok(Command::Status(target(p))),
+    },
+    Spec {
+        name: "INDEX CREATE",
+        args: &[Arg::Required("collection"), Arg::Required("path")],
+        help: "indexes a dotted path so FND BY and QUERY on it do not scan",
+        parse: |p| Ok(Command::IndexCreate { collection: p.string("collection"), path: p.string("path") }),
+    },
+    Spec {
+        name: "INDEX DROP",
+        args: &[Arg::Required("collection"), Arg::Required("path")],
+        help: "removes an index",
+        parse: |p| Ok(Command::IndexDrop { collection: p.string("collection"), path: p.string("path") }),
+    },
+    Spec {
+        name: "INDEX LIST",
+        args: &[Arg::Required("collection")],
+        help: "the indexed paths of a collection",
+        parse: |p| Ok(Command::IndexList(p.string("collection"))),
+    },
+    Spec {
+        name: "SCHEMA SET",
+        args: &[Arg::Required("collection"), Arg::Required("key"), Arg::Rest("schema")],
+        help: "a json schema the document at key has to match, * for every document",
+        parse: |p| Ok(Command::SchemaSet { collection: p.string("collection"), key: p.string("key"), schema: p.json_text(&p.rest("schema"), "schema")? }),
+    },
+    Spec {
+        name: "SCHEMA GET",
+        args: &[Arg::Required("collection"), Arg::Required("key")],
+        help: "the schema of a key, * for the one of the collection",
+        parse: |p| Ok(Command::SchemaGet { collection: p.string("collection"), key: p.string("key") }),
+    },
+    Spec {
+        name: "SCHEMA DROP",
+        args: &[Arg::Required("collection"), Arg::Required("key")],
+        help: "removes the schema of a key, * for the one of the collection",
+        parse: |p| Ok(Command::SchemaDrop { collection: p.string("collection"), key: p.string("key") }),
+    },
+    Spec {
+        name: "SCHEMA LIST",
+        args: &[Arg::Required("collection")],
+        help: "every schema of a collection by key",
+        parse: |p| Ok(Command::SchemaList(p.string("collection"))),
+    },
+    Spec {
+        name: "MULTI",
+        args: &[],
+        help: "starts a transaction, key commands are queued until EXEC",
+        parse: |_| Ok(Command::Multi),
+    },
+    Spec {
+        name: "MULTI WATCH",
+        args: &[COLLECTION, Arg::Many("key")],
+        help: "starts a transaction that fails on EXEC if one of the keys changed in between",
+        parse: |p| Ok(Command::MultiWatch(target(p), p.all("key").into_iter().map(|x| x.to_owned()).collect())),
+    },
+    Spec {
+        name: "DISCARD",
+        args: &[],
+        help: "drops the transaction and what it queued",
+        parse: |_| Ok(Command::Discard),
+    },
+    Spec {
+        name: "EXEC",
+        args: &[],
+        help: "runs the queued commands as one, returns their results",
+        parse: |_| Ok(Command::Exec),
+    },
+    Spec {
+        name: "WATCH",
+        args: &[COLLECTION, Arg::Required("pattern")],
+        help: "pushes changes to a key and what is inside it, or to keys starting with a prefix*",
+        parse: |p| Ok(Command::Watch(target(p), watch::Pattern::parse(&p.string("pattern"))?)),
+    },
+    Spec {
+        name: "UNWATCH",
+        args: &[Arg::Optional("id")],
+        help: "stops a watch, without an id lists them",
+        parse: |p| Ok(Command::Unwatch(p.number_opt("id")?)),
+    },
+    Spec {
+        name: "AUTOSAVE",
+        args: &[Arg::Many("policy")],
+        help: "saves after <seconds> <writes> pairs like 900 1 300 10, OFF turns it off",
+        parse: |p| Ok(Command::Autosave(autosave::parse_policies(p.all("policy").into_iter())?)),
+    },
+    Spec {
+        name: "FND",
+        args: &[COLLECTION, Arg::Required("key")],
+        help: "the value at a key, dotted path or json pointer",
+        parse: |p| read(p, Read::Find(p.string("key"))),
+    },
+    Spec {
+        name: "FND BY",
+        args: &[COLLECTION, Arg::Required("path"), Arg::Required("value")],
+        help: "the keys whose document has value at the dotted path, a value that is not json is a string",
+        parse: |p| {
+            let value = match p.json("value") {
+                Ok(x) => x,
+                Err(_) => json::to_json!(p.string("value")),
+            };
+            read(p, Read::FindBy { path: p.string("path"), value })
+        },
+    },
+    Spec {
+        name: "SCAN",
+        args: &[COLLECTION, Arg::Required("cursor"), Arg::Flag("PREFIX", Some("prefix")), Arg::Flag("MATCH", Some("pattern")), Arg::Flag("COUNT", Some("count")), Arg::Flag("VALUES", None)],
+        help: "a page of keys, start with cursor 0 and pass the returned cursor back until it is 0 again",
+        parse: |p| {
+            let args = scan::ScanArgs::new(&p.string("cursor"), p.get("PREFIX"), p.get("MATCH"), p.number_opt("COUNT")?, p.has("VALUES"))?;
+            read(p, Read::Scan(args))
+        },
+    },
+    Spec {
+        name: "RANGE",
+        args: &[COLLECTION, Arg::Required("start"), Arg::Required("end"), Arg::Flag("LIMIT", Some("limit")), Arg::Flag("REV", None), Arg::Flag("KEYS", None)],
+        help: "the keys from start to end in order, - and + leave an end open",
+        parse: |p| {
+            let args = scan::RangeArgs::new(&p.string("start"), &p.string("end"), p.number_opt("LIMIT")?, p.has("REV"), p.has("KEYS"))?;
+            read(p, Read::Range(args))
+        },
+    },
+    Spec {
+        name: "QUERY",
+        args: &[COLLECTION, Arg::Rest("query")],
+        help: "the documents matching {\"filter\", \"projection\", \"sort\", \"limit\"}",
+        parse: |p| read(p, Read::Query(query::Query::parse(&p.json_text(&p.rest("query"), "query")?)?)),
+    },
+    Spec {
+        name: "VERSION",
+        args: &[COLLECTION, Arg::Required("key")],
+        help: "the version of the document a key belongs to, 0 if there is none",
+        parse: |p| read(p, Read::Version(p.string("key"))),
+    },
+    Spec {
+        name: "TTL",
+        args: &[COLLECTION, Arg::Required("key")],
+        help: "the seconds a key has left, -1 if it does not expire and -2 if it is missing",
+        parse: |p| read(p, Read::Ttl(p.string("key"))),
+    },
+    Spec {
+        name: "INS",
+        args: &[COLLECTION, Arg::Flag("-p", None), Arg::Flag("-f", None), Arg::Required("key"), Arg::Required("value"), Arg::Flag("EX", Some("seconds"))],
+        help: "puts a json value at a key, -p creates the objects missing on the way, -f replaces a whole key holding another type and EX makes it expire",
+        parse: |p| {
+            let expires = match p.number_opt::<u64>("EX")? {
+                Some(0) => return Err(p.invalid("EX has to be more than 0 seconds")),
+                x => x,
+            };
+            write(p, Write::Insert { key: p.string("key"), value: p.json("value")?, parents: p.has("-p"), replace: p.has("-f"), expires })
+        },
+    },
+    Spec {
+        name: "DEL",
+        args: &[COLLECTION, Arg::Required("key")],
+        help: "removes a key and returns what it held",
+        parse: |p| write(p, Write::Delete(p.string("key"))),
+    },
+    Spec {
+        name: "CAS",
+        args: &[COLLECTION, Arg::Required("key"), Arg::Required("version"), Arg::Required("value")],
+        help: "puts a value only if the key is still at version, 0 if it has to be missing",
+        parse: |p| write(p, Write::Cas { key: p.string("key"), expected: p.number("version")?, value: p.json("value")? }),
+    },
+    Spec {
+        name: "EXPIRE",
+        args: &[COLLECTION, Arg::Required("key"), Arg::Required("seconds")],
+        help: "deletes a key after some seconds",
+        parse: |p| write(p, Write::Expire { key: p.string("key"), seconds: p.number("seconds")? }),
+    },
+    Spec {
+        name: "PERSIST",
+        args: &[COLLECTION, Arg::Required("key")],
+        help: "keeps a key from expiring",
+        parse: |p| write(p, Write::Persist(p.string("key"))),
+    },
+    Spec {
+        name: "INCR",
+        args: &[COLLECTION, Arg::Required("key"), Arg::Optional("amount")],
+        help: "adds to a number, 1 if no amount is given",
+        parse: |p| write(p, Write::Incr { key: p.string("key"), by: amount(p)? }),
+    },
+    Spec {
+        name: "DECR",
+        args: &[COLLECTION, Arg::Required("key"), Arg::Optional("amount")],
+        help: "takes from a number, 1 if no amount is given",
+        parse: |p| write(p, Write::Decr { key: p.string("key"), by: amount(p)? }),
+    },
+    Spec {
+        name: "PUSH",
+        args: &[COLLECTION, Arg::Required("key"), Arg::Flag("FRONT", None), Arg::Many("value")],
+        help: "adds values to the end of an array, or its front",
+        parse: |p| {
+            let mut values = Vec::new();
+            for value in p.all("value") {
+                values.push(p.json_text(value, "value")?);
+            }
+            write(p, Write::Push { key: p.string("key"), front: p.has("FRONT"), values })
+        },
+    },
+    Spec {
+        name: "POP",
+        args: &[COLLECTION, Arg::Required("key"), Arg::Flag("FRONT", None)],
+        help: "takes the last value out of an array, or the first",
+        parse: |p| write(p, Write::Pop { key: p.string("key"), front: p.has("FRONT") }),
+    },
+    Spec {
+        name: "REMOVE",
+        args: &[COLLECTION, Arg::Required("key"), Arg::Required("value")],
+        help: "takes every copy of a value out of an array",
+        parse: |p| write(p, Write::Remove { key: p.string("key"), value: p.json("value")? }),
+    },
+    Spec {
+        name: "APPEND",
+        args: &[COLLECTION, Arg::Required("key"), Arg::Required("text")],
+        help: "adds text to the end of a string, a json string or bare text",
+        parse: |p| {
+            let suffix = match p.json("text") {
+                Ok(json::JSON::String(x)) => x,
+                _ => p.string("text"),
+            };
+            write(p, Write::Append { key: p.string("key"), suffix })
+        },
+    },
+    Spec {
+        name: "MERGE",
+        args: &[COLLECTION, Arg::Required("key"), Arg::Rest("patch")],
+        help: "applies an rfc 7386 merge patch to a document",
+        parse: |p| write(p, Write::Merge { key: p.string("key"), patch: p.json_text(&p.rest("patch"), "patch")? }),
+    },
+    Spec {
+        name: "PATCH",
+        args: &[COLLECTION, Arg::Required("key"), Arg::Rest("operations")],
+        help: "applies an rfc 6902 json patch to a document",
+        parse: |p| write(p, Write::Patch { key: p.string("key"), operations: p.json_text(&p.rest("operations"), "operations")? }),
+    },
+]);
+
+fn target(p: &Parsed) -> Target {
+    p.get("-c").map(|x| x.to_owned())
+}
+
+fn read(p: &Parsed, read: Read) -> Res<Command> {
+    Ok(Command::Keys(target(p), Op::Read(read)))
+}
+
+fn write(p: &Parsed, write: Write) -> Res<Command> {
+    Ok(Command::Keys(target(p), Op::Write(write)))
+}
+
+/// the amount of INCR and DECR, 1 when there is none
+fn amount(p: &Parsed) -> Res<json::JSON> {
+    match p.get("amount") {
+        Some(_) => {
+            match p.json("amount")? {
+                x if x.is_number() => Ok(x),
+                _ => Err(p.invalid("<amount> has to be a number")),
+            }
+        },
+        None => Ok(json::to_json!(1)),
+    }
+}
@@ -0,0 +1,14 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// how often the background task removes keys whose time ran out
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// expiry times are kept as milliseconds since the unix epoch so they mean the same after a restart
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_millis() as u64).unwrap_or(0)
+}
+
+/// the expiry time of a key that should live for this many seconds from now
+pub fn after_secs(seconds: u64) -> u64 {
+    now_ms().saturating_add(seconds.saturating_mul(1000))
+}
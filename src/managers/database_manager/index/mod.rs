@@ -0,0 +1,122 @@
+use std::collections::{BTreeSet, HashMap};
+
+use super::super::json;
+use super::rec_get;
+
+/// maps the value found at a dotted path inside each document to the keys of those documents,
+/// so `FND BY <path> <value>` does not have to look at every key
+#[derive(Debug)]
+pub struct Index {
+    path: String,
+    // values are keyed by their json text so numbers, strings and objects can all be looked up, see `key`
+    entries: HashMap<String, BTreeSet<String>>,
+}
+
+impl Index {
+    pub fn new(path: &str) -> Index {
+        Index { path: path.to_owned(), entries: HashMap::new() }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// the value this index files the document under, none if the document does not have the path
+    pub fn value_of(&self, doc: &json::JSON) -> Option<String> {
+        match rec_get(self.path.split(".").peekable(), doc) {
+            Ok(json::JSON::Null) | Err(_) => None,
+            Ok(x) => Some(key(&x)),
+        }
+    }
+
+    pub fn add(&mut self, key: &str, doc: &json::JSON) {
+        if let Some(value) = self.value_of(doc) {
+            self.entries.entry(value).or_default().insert(key.to_owned());
+        }
+    }
+
+    pub fn remove(&mut self, key: &str, doc: &json::JSON) {
+        if let Some(value) = self.value_of(doc) {
+            let emptied = match self.entries.get_mut(&value) {
+                Some(keys) => {
+                    keys.remove(key);
+                    keys.is_empty()
+                },
+                None => false,
+            };
+            if emptied {
+                self.entries.remove(&value);
+            }
+        }
+    }
+
+    /// keys of the documents whose value at the path equals value
+    pub fn find(&self, value: &json::JSON) -> Vec<&String> {
+        match self.entries.get(&key(value)) {
+            Some(keys) => keys.iter().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// the text a value is filed under. numbers are compared by value, so `1` and `1.0` are the same
+pub fn key(value: &json::JSON) -> String {
+    normalized(value).to_string()
+}
+
+/// the value with every whole number written as an integer
+fn normalized(value: &json::JSON) -> json::JSON {
+    // 2^53, past it not every whole number has a float of its own
+    const EXACT: f64 = 9007199254740992.0;
+    match value {
+        json::JSON::Number(x) if x.is_f64() => {
+            match x.as_f64() {
+                Some(f) if f.fract() == 0.0 && f.abs() < EXACT => json::to_json!(f as i64),
+                _ => value.clone(),
+            }
+        },
+        json::JSON::Array(x) => json::JSON::Array(x.iter().map(normalized).collect()),
+        json::JSON::Object(x) => json::JSON::Object(x.iter().map(|(k, v)| (k.clone(), normalized(v))).collect()),
+        _ => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_documents_by_nested_value() {
+        let mut index = Index::new("address.city");
+        index.add("a", &json::to_json!({"address": {"city": "x"}}));
+        index.add("b", &json::to_json!({"address": {"city": "x"}}));
+        index.add("c", &json::to_json!({"address": {"city": "y"}}));
+        index.add("d", &json::to_json!({"name": "no address"}));
+        assert_eq!(index.find(&json::to_json!("x")), vec!["a", "b"]);
+        assert_eq!(index.find(&json::to_json!("y")), vec!["c"]);
+        assert!(index.find(&json::JSON::Null).is_empty());
+
+        index.remove("a", &json::to_json!({"address": {"city": "x"}}));
+        assert_eq!(index.find(&json::to_json!("x")), vec!["b"]);
+    }
+
+    #[test]
+    fn numbers_match_by_value() {
+        let mut index = Index::new("n");
+        index.add("a", &json::to_json!({"n": 1}));
+        index.add("b", &json::to_json!({"n": 1.0}));
+        index.add("c", &json::to_json!({"n": -2.0}));
+        index.add("d", &json::to_json!({"n": 1.5}));
+        assert_eq!(index.find(&json::to_json!(1)), vec!["a", "b"]);
+        assert_eq!(index.find(&json::to_json!(1.0)), vec!["a", "b"]);
+        assert_eq!(index.find(&json::to_json!(-2)), vec!["c"]);
+        assert_eq!(index.find(&json::to_json!(1.5)), vec!["d"]);
+        assert!(index.find(&json::to_json!("1")).is_empty());
+    }
+
+    #[test]
+    fn keys_normalize_inside_containers() {
+        assert_eq!(key(&json::to_json!([1.0, {"a": 2.0}])), key(&json::to_json!([1, {"a": 2}])));
+        assert_ne!(key(&json::to_json!(1e300)), key(&json::to_json!(1)));
+    }
+}
@@ -1,136 +1,195 @@
-use std::{collections::HashMap, sync::Arc, path::Path, fs::{File, OpenOptions, self}, io::{BufReader, Seek}, fmt, str::Split, iter::Peekable};
+use std::{collections::{BTreeMap, HashMap}, sync::{Arc, Weak}, path::Path, fs::{File, OpenOptions}, io::BufReader, fmt, str::Split, iter::Peekable};
 use tokio::{io::{AsyncWriteExt}, sync::RwLockReadGuard};
+use async_trait::async_trait;
 use futures::executor::block_on;
-use tokio::{sync::RwLock};
+use tokio::{sync::{RwLock, OwnedRwLockWriteGuard, mpsc::UnboundedReceiver}};
 
 use super::{error,json, service_manager};
 
 type Res<T> = Result<T, error::ServerError>;
 
-#[derive(Debug)]
-enum FileManager {
-    Closed,
-    Open(File)
-}
+/// where the file each collection was bound to with OPEN is kept, so they are opened again on start
+const BINDINGS: &str = "./collections.json";
+
+pub mod persistence;
+pub mod autosave;
+pub mod collection;
+pub mod scan;
+pub mod store;
+pub mod index;
+pub mod query;
+pub mod expiry;
+pub mod ops;
+pub mod pointer;
+pub mod patch;
+pub mod schema;
+pub mod watch;
+pub mod commands;
+pub mod redis;
+use autosave::SavePolicy;
+use commands::{Command, Op, Read, Write};
+use collection::Collection;
+use store::{Store, StoreKind};
 
 #[derive(Debug)]
 pub struct DataBaseManager {
-    file: RwLock<FileManager>,
-    permissions: RwLock<HashMap<String,json::JSON>>,
+    permissions: RwLock<Store>,
     connections: RwLock<HashMap<String,String>>,
-    store: RwLock<HashMap<String,json::JSON>>,
+    collections: RwLock<HashMap<String,Arc<RwLock<Collection>>>>,
+    // the collection each connection has selected with USE
+    current: RwLock<HashMap<String,String>>,
+    autosave: RwLock<Vec<SavePolicy>>,
+    // the transaction each connection started with MULTI
+    transactions: RwLock<HashMap<String,Transaction>>,
+    // the map new collections keep their keys in
+    kind: StoreKind,
+    // what each connection asked to hear about with WATCH
+    watches: RwLock<watch::Watches>,
+}
+
+/// commands queued between MULTI and EXEC with the collection they run on, and the keys watched
+/// with `MULTI WATCH` with the versions they had then
+#[derive(Debug, Default)]
+struct Transaction {
+    queued: Vec<(String,Op)>,
+    watched: Vec<(String,String,u64)>,
 }
 
 // ! does not work
 impl fmt::Display for DataBaseManager {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f,"store: ({:?}), file: ({:?})",block_on(self.store.read()),block_on(self.file.read()))
+        write!(f,"collections: ({:?})",block_on(self.collections.read()).keys())
     }
 }
 
 impl DataBaseManager {
     pub fn new() -> Arc<DataBaseManager> {
+        // DB_STORE=ordered keeps keys sorted, which makes SCAN and RANGE cheap. the default is hash
+        let kind = match dotenv::var("DB_STORE") {
+            Ok(kind) => {
+                match StoreKind::parse(&kind) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        println!("ignoring DB_STORE={}, using hash", kind);
+                        StoreKind::Hash
+                    },
+                }
+            },
+            Err(_) => StoreKind::Hash,
+        };
+        let mut collections = HashMap::new();
+        collections.insert(collection::DEFAULT.to_owned(), Arc::new(RwLock::new(Collection::new(kind))));
         let res = Arc::new(DataBaseManager{
-            file: RwLock::new(FileManager::Closed),
-            permissions: RwLock::new(HashMap::new()),
+            permissions: RwLock::new(Store::new(StoreKind::Hash)),
             connections: RwLock::new(HashMap::new()),
-            store: RwLock::new(HashMap::new()),
+            collections: RwLock::new(collections),
+            current: RwLock::new(HashMap::new()),
+            autosave: RwLock::new(Vec::new()),
+            transactions: RwLock::new(HashMap::new()),
+            kind,
+            watches: RwLock::new(watch::Watches::default()),
         });
         let _ = block_on(res.load_permissions());
+        // DB_FILE is opened and replayed on startup so nothing logged before a crash is lost
+        if let Ok(path) = dotenv::var("DB_FILE") {
+            let loaded = block_on(async {
+                let default = res.collection(collection::DEFAULT).await?;
+                let mut default = default.write().await;
+                default.open(&path).and(default.load())
+            });
+            match loaded {
+                Ok(_) => println!("loaded {}", path),
+                Err(e) => println!("could not load {}: {}", path, e.produce_error()),
+            }
+        }
+        // collections bound with OPEN come back with their files, DB_FILE has the last word on the default one
+        let skip = if dotenv::var("DB_FILE").is_ok() { Some(collection::DEFAULT) } else { None };
+        block_on(res.open_bindings(skip));
+        // AUTOSAVE takes redis style pairs, "900 1 300 10" saves after 900s if 1 write or after 300s if 10 writes
+        if let Ok(policies) = dotenv::var("AUTOSAVE") {
+            match autosave::parse_policies(policies.split(' ')) {
+                Ok(policies) => *block_on(res.autosave.write()) = policies,
+                Err(e) => println!("ignoring AUTOSAVE={}: {}", policies, e.produce_error()),
+            }
+        }
+        DataBaseManager::start_autosave(Arc::downgrade(&res));
+        DataBaseManager::start_sweeper(Arc::downgrade(&res));
+        // RESP_PORT=6379 lets redis clients talk to the database on that port as well
+        if let Ok(port) = dotenv::var("RESP_PORT") {
+            redis::start(Arc::downgrade(&res), port);
+        }
         res
     }
-    
-    // async fn store_size(&self) -> usize {
-    //     let store = self.store.read().await;
-    //     store.len()
-    // }
 
-    /// if a files is open the current store is saved to the file otherwise en error is returned
-    fn save_store(&self) -> Res<String> {
-        let tuple = async {
-            let file = self.file.write();
-            let store = self.store.read();
-            futures::join!(file,store)
-        };
-        let (mut file, store) = block_on(tuple);
-        match *file {
-            FileManager::Closed => Ok(String::from("No file open to save to")),
-            FileManager::Open(ref mut x) => {
-                let _ = x.set_len(0);
-                let _ = x.rewind();
-                match serde_json::to_writer(x, &*store) {
-                    Ok(_) => Ok(String::from("Saved to file")),
-                    Err(e) => {
-                        println!("{}",e);
-                        Err(error::ServerError::INVALID_JSON)
+    /// background task that writes a snapshot of every collection that meets one of the auto-save policies.
+    /// it stops once the manager is dropped
+    fn start_autosave(manager: Weak<DataBaseManager>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(autosave::CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let manager = match manager.upgrade() {
+                    Some(x) => x,
+                    None => return,
+                };
+                let policies = manager.autosave.read().await.clone();
+                let collections: Vec<(String, Arc<RwLock<Collection>>)> = manager.collections.read().await
+                    .iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                for (name, collection) in collections {
+                    let mut collection = collection.write().await;
+                    if collection.save_due(&policies) {
+                        match collection.save() {
+                            Ok(_) => (),
+                            Err(e) => println!("auto-save of {} failed: {}", name, e.produce_error()),
+                        }
                     }
                 }
-            },
-        }
-    }
-
-    fn save_permissions(&self) -> Res<()> {
-        let mut x = OpenOptions::new().append(true).open("./permissions.json").unwrap();
-        let store = block_on(self.permissions.write());
-        let _ = x.set_len(0);
-        let _ = x.rewind();
-        match serde_json::to_writer(x, &*store) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                println!("{}",e);
-                Err(error::ServerError::INVALID_JSON)
             }
-        }
+        });
     }
-
-    /// opens a file at path or creates a new one if not present. returns error if fails to create new file.
-    async fn open(&self, path: &str) -> Res<()> {
-        let mut dir_part = String::new();
-        if !path.contains(".json") {
-            return Err(error::ServerError::INVALID_FILE);
-        }
-        if path.contains("/") {
-            let mut split = path.split("/");
+    
+    /// background task that deletes keys whose time to live ran out, so keys nobody reads again do not
+    /// stay in memory. it stops once the manager is dropped
+    fn start_sweeper(manager: Weak<DataBaseManager>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(expiry::SWEEP_INTERVAL);
             loop {
-                match split.next() {
-                    Some(x) => {
-                        if !x.contains(".json") {
-                            dir_part += x;
-                            dir_part += "/";
-                        }
-                    },
-                    None => break
+                interval.tick().await;
+                let manager = match manager.upgrade() {
+                    Some(x) => x,
+                    None => return,
                 };
-            };
-        };
+                manager.sweep().await;
+            }
+        });
+    }
 
-        let path = Path::new(path);
-        let file = OpenOptions::new().write(true).read(true).open(path);//File::open(path);
-        match file {
-            Ok(x) => {
-                let mut this_file = self.file.write().await;
-                *this_file = FileManager::Open(x);
-                Ok(())
-            },
-            Err(_) => {
-                let mut this_file = self.file.write().await;
-                if !dir_part.is_empty() && dir_part != "./" {
-                    let _ = fs::create_dir_all(dir_part);
-                }
-                let new_file = OpenOptions::new().create_new(true).append(true).open(path);
-                match new_file {
-                    Ok(x) => {
-                        *this_file = FileManager::Open(x);
-                        Ok(())
-                    },
-                    Err(_) => {
-                        Err(error::ServerError::INVALID_FILE)
-                    }
-                }
+    /// deletes the expired keys of every collection and tells the watches about them
+    async fn sweep(&self) {
+        let collections: Vec<(String, Arc<RwLock<Collection>>)> = self.collections.read().await
+            .iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        for (name, collection) in collections {
+            let expired = collection.write().await.sweep();
+            if expired.is_empty() {
+                continue;
+            }
+            let mut watches = self.watches.write().await;
+            for key in expired {
+                watches.notify(&name, "EXPIRED", &key, &json::JSON::Null);
             }
         }
     }
 
+    // async fn store_size(&self) -> usize {
+    //     let store = self.store.read().await;
+    //     store.len()
+    // }
+
+    async fn save_permissions(&self) -> Res<()> {
+        let store = self.permissions.write().await;
+        persistence::write_atomic(Path::new("./permissions.json"), &*store)
+    }
+
     async fn load_permissions(&self) -> Res<()> {
         let x = OpenOptions::new().write(true).read(true).open("./permissions.json").unwrap();
         let reader = BufReader::new(&x);
@@ -139,11 +198,12 @@ impl DataBaseManager {
             Ok(x) => {
                 match x {
                     serde_json::Value::Object(mut x) => {
+                        let mut permissions = self.permissions.write().await;
                         let keys = x.keys().cloned().collect::<Vec<_>>();
                         for k in keys {
                             match x.remove(&k) {
                                 Some(v) => {
-                                    let _ = self.insert_to_store(k, v, 1).await ;
+                                    let _ = insert_into(&mut permissions, k, v);
                                 },
                                 None => (),
                             };
@@ -163,106 +223,208 @@ impl DataBaseManager {
                 
     }
 
-    /// loads json object from file into store if file is opened.
-    async fn load_from_file(&self) -> Res<()> {
-        let file = self.file.read().await;
-        match *file {
-            FileManager::Closed => {
-                Err(error::ServerError::INVALID_FILE)
-            },
-            FileManager::Open(ref x) => {
-                let reader = BufReader::new(x);
-                let map= serde_json::from_reader::<BufReader<&File>, json::JSON>(reader);
-                match map {
-                    Ok(x) => {
-                        match x {
-                            serde_json::Value::Object(mut x) => {
-                                let keys = x.keys().cloned().collect::<Vec<_>>();
-                                for k in keys {
-                                    match x.remove(&k) {
-                                        Some(v) => {
-                                            let _ = self.insert_to_store(k, v, 0).await ;
-                                        },
-                                        None => (),
-                                    };
-                                };
-                                if x.len() != 0 {
-                                    return Err(error::ServerError::INCOMPLETE_OPERATION);
-                                }
-                                Ok(())
-                            },
-                            _ => {
-                                Err(error::ServerError::INVALID_JSON)
-                            }
-                        }
-                    },
-                    Err(_) => Err(error::ServerError::INVALID_JSON),
-                }
-            },
+    /// writes down the file every collection is bound to
+    async fn save_bindings(&self) -> Res<()> {
+        let collections: Vec<(String, Arc<RwLock<Collection>>)> = self.collections.read().await
+            .iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        let mut bindings = serde_json::Map::new();
+        for (name, collection) in collections {
+            if let Some(file) = collection.read().await.file() {
+                bindings.insert(name, json::to_json!(file));
+            }
         }
+        persistence::write_atomic(Path::new(BINDINGS), &bindings)
     }
 
-    /// inserts item into store. works with nested objects
-    async fn insert_to_store(&self, k:String, v:json::JSON, arg:u8) -> Res<()> {
-        let key;
-        let mut split;
-        let mut store;
-        if arg == 1 {
-            store = self.permissions.write().await;
-        }else {
-            store = self.store.write().await;
+    /// opens and loads every collection `save_bindings` wrote down, except skip
+    async fn open_bindings(&self, skip: Option<&str>) {
+        let bindings = match File::open(BINDINGS) {
+            Ok(x) => serde_json::from_reader::<BufReader<File>, serde_json::Map<String, json::JSON>>(BufReader::new(x)),
+            // nothing was ever opened
+            Err(_) => return,
+        };
+        let bindings = match bindings {
+            Ok(x) => x,
+            Err(_) => {
+                println!("could not read {}", BINDINGS);
+                return;
+            },
+        };
+        for (name, file) in bindings {
+            let file = match file.as_str() {
+                Some(x) if Some(name.as_str()) != skip => x.to_owned(),
+                _ => continue,
+            };
+            let collection = self.collection_or_new(&name).await;
+            let mut collection = collection.write().await;
+            match collection.open(&file).and(collection.load()) {
+                Ok(_) => println!("loaded {} into {}", file, name),
+                Err(e) => println!("could not load {} into {}: {}", file, name, e.produce_error()),
+            }
         }
+    }
 
-        let contains = k.contains(".");
-        if contains {
-            split = k.split(".").peekable();
-            key = split.next().unwrap().to_owned();
-        }else {
-            split = "err.err".split(".").peekable();
-            key = k;
+    /// returns the collection with this name
+    async fn collection(&self, name: &str) -> Res<Arc<RwLock<Collection>>> {
+        match self.collections.read().await.get(name) {
+            Some(x) => Ok(x.clone()),
+            None => Err(error::ServerError::MISSING_DATA),
         }
-        if contains {
-            match store.get_mut(&key) {
-                Some(x) => {
-                    if x.is_object() {
-                        rec_ins(split,x,v)
-                    }else {
-                        // fail
-                        Err(error::ServerError::INVALID_JSON)
+    }
+
+    /// returns the collection with this name, creating an empty one if there is none
+    async fn collection_or_new(&self, name: &str) -> Arc<RwLock<Collection>> {
+        let mut collections = self.collections.write().await;
+        collections.entry(name.to_owned()).or_insert_with(|| Arc::new(RwLock::new(Collection::new(self.kind)))).clone()
+    }
+
+    /// runs the commands of a transaction with every collection they touch locked, so other
+    /// connections see all of their changes or none. if one fails the ones before it are undone
+    async fn exec(&self, transaction: Transaction) -> Res<json::JSON> {
+        let queued = transaction.queued;
+        let mut names: Vec<String> = queued.iter().map(|(name, _)| name)
+            .chain(transaction.watched.iter().map(|(name, _, _)| name)).cloned().collect();
+        // always locking in name order keeps two transactions from waiting on each other
+        names.sort();
+        names.dedup();
+        let mut locked: BTreeMap<String, OwnedRwLockWriteGuard<Collection>> = BTreeMap::new();
+        for name in names {
+            match self.collection(&name).await {
+                Ok(x) => {
+                    let mut collection = x.write_owned().await;
+                    collection.begin();
+                    locked.insert(name, collection);
+                },
+                Err(e) => {
+                    for collection in locked.values_mut() {
+                        collection.rollback();
                     }
+                    return Err(e);
                 },
-                None => {
-                    Err(error::ServerError::INVALID_DATA)
+            }
+        }
+
+        for (name, key, version) in transaction.watched.iter() {
+            if locked[name].version(key) != *version {
+                for collection in locked.values_mut() {
+                    collection.rollback();
                 }
+                return Err(error::ServerError::CONFLICT);
             }
-        }else {
-             match store.get(&key) {
-            Some(x) => {
-                if (x.is_array() && v.is_array()) || (x.is_string() && v.is_string()) || (x.is_object() && v.is_object()) ||
-                (x.is_boolean() && v.is_boolean()) || (x.is_number() && v.is_number()) || (x.is_f64() && v.is_f64()) || 
-                (x.is_i64() && v.is_i64()) || (x.is_u64() && v.is_u64()){
-                    match store.insert(key, v) {
-                        Some(_) => Ok(()),
-                        None => Ok(()),
+        }
+
+        let mut results = Vec::with_capacity(queued.len());
+        // what each write changed, for the watches once everything is committed
+        let mut changed = Vec::new();
+        for (name, op) in queued {
+            let collection = locked.get_mut(&name).unwrap();
+            let result = match op {
+                Op::Read(read) => read_command(collection, &read),
+                Op::Write(write) => {
+                    changed.push((name, write.name(), write.key().to_owned()));
+                    write_command(collection, write)
+                },
+            };
+            match result {
+                Ok(x) => results.push(x),
+                Err(e) => {
+                    for collection in locked.values_mut() {
+                        collection.rollback();
+                    }
+                    return Err(e);
+                },
+            }
+        }
+
+        // every collection has its own log, so a failure after some of them committed is logged as
+        // those being taken back
+        let mut committed = Vec::new();
+        let mut failed = None;
+        for (name, collection) in locked.iter_mut() {
+            if failed.is_some() {
+                collection.rollback();
+                continue;
+            }
+            match collection.commit() {
+                Ok(_) => committed.push(name.clone()),
+                Err(e) => failed = Some(e),
+            }
+        }
+        if let Some(e) = failed {
+            for name in committed {
+                if let Some(collection) = locked.get_mut(&name) {
+                    if collection.revert().is_err() {
+                        println!("could not take back a failed transaction in {}", name);
                     }
-                }else {
-                    Err(error::ServerError::INCOMPATIBLE_DATA_TYPES)
                 }
-            },
+            }
+            return Err(e);
+        }
+        for collection in locked.values_mut() {
+            collection.end();
+        }
+
+        let mut watches = self.watches.write().await;
+        for (name, command, key) in changed {
+            watches.notify(&name, command, &key, &locked[&name].get(&key).unwrap_or(json::JSON::Null));
+        }
+        Ok(json::JSON::Array(results))
+    }
+
+    /// the collection a command works on. `-c <name>` picks one explicitly, otherwise it is the one
+    /// the connection selected with USE
+    async fn target(&self, id: &str, target: commands::Target) -> Res<(String, Arc<RwLock<Collection>>)> {
+        let name = match target {
+            Some(x) if !x.is_empty() => x,
+            Some(_) => return Err(error::ServerError::INVALID_ARG),
             None => {
-                match store.insert(key, v) {
-                    Some(_) => Ok(()),
-                    None => Ok(()),
+                match self.current.read().await.get(id) {
+                    Some(x) => x.clone(),
+                    None => collection::DEFAULT.to_owned(),
                 }
             },
+        };
+        match self.collection(&name).await {
+            Ok(x) => Ok((name, x)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// starts the transaction of a connection, it can only have one at a time
+    async fn begin(&self, id: &str, transaction: Transaction) -> Res<json::JSON> {
+        let mut transactions = self.transactions.write().await;
+        if transactions.contains_key(id) {
+            return Err(error::ServerError::INVALID_ARG);
         }
+        transactions.insert(id.to_owned(), transaction);
+        Ok(json::to_json!("OK"))
+    }
+
+    /// changes the indexes of a collection and returns the paths indexed after it
+    async fn index<F: FnOnce(&mut Collection) -> Res<()>>(&self, name: &str, change: F) -> Res<json::JSON> {
+        let collection = self.collection(name).await?;
+        let mut collection = collection.write().await;
+        match change(&mut collection) {
+            Ok(_) => Ok(json::to_json!(collection.index_paths())),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// changes the schemas of a collection and returns all of them after it
+    async fn schema<F: FnOnce(&mut Collection) -> Res<()>>(&self, name: &str, change: F) -> Res<json::JSON> {
+        let collection = self.collection(name).await?;
+        let mut collection = collection.write().await;
+        match change(&mut collection) {
+            Ok(_) => Ok(collection.schemas()),
+            Err(e) => Err(e),
         }
     }
 }
 
+#[async_trait]
 impl service_manager::other::Manager for DataBaseManager {
-    fn process_message(&self, message: &str, id: &str) -> Res<String>{
-        // let permissions = block_on(self.permissions.read());
+    async fn process_message(&self, message: &str, id: &str) -> Res<json::JSON>{
+        // let permissions = self.permissions.read().await;
         // let user = user.get(id);
         // let error = match user {
         //     Some(named_user) => {
@@ -276,132 +438,191 @@ impl service_manager::other::Manager for DataBaseManager {
         //     None => error::ServerError::ACCESS_DENIED,
         // };
         
-        let mut split = message.split(" ");
-        let str = split.next().unwrap();
-        if str != "NEW" {
-            let user = block_on(self.connections.read());          
-    
-            if !user.contains_key(id) {
-                return Err(error::ServerError::ACCESS_DENIED)
-            }
-        }
+        let command = commands::COMMANDS.parse(message)?;
+        match command {
+            Command::New { .. } | Command::Help(_) => (),
+            _ => {
+                let user = self.connections.read().await;
 
-        match str {
-            "NEW" =>{
-                let user = split.next().unwrap_or_else(|| "Error");
-                let password = split.next().unwrap_or_else(|| "Error");
+                if !user.contains_key(id) {
+                    return Err(error::ServerError::ACCESS_DENIED)
+                }
+            },
+        }
 
-                let permissions = block_on(self.permissions.read());
+        match command {
+            Command::Keys(target, op) => {
+                let (name, collection) = match self.target(id, target).await {
+                    Ok(x) => x,
+                    Err(e) => return Err(e),
+                };
+                let mut transactions = self.transactions.write().await;
+                if let Some(transaction) = transactions.get_mut(id) {
+                    transaction.queued.push((name, op));
+                    return Ok(json::to_json!("QUEUED"));
+                }
+                drop(transactions);
+                match op {
+                    Op::Read(read) => read_command(&*collection.read().await, &read),
+                    Op::Write(write) => {
+                        let (event, key) = (write.name(), write.key().to_owned());
+                        let mut collection = collection.write().await;
+                        let result = write_command(&mut collection, write);
+                        if result.is_ok() {
+                            self.watches.write().await.notify(&name, event, &key, &collection.get(&key).unwrap_or(json::JSON::Null));
+                        }
+                        result
+                    },
+                }
+            },
+            Command::Help(name) => commands::COMMANDS.help(&name),
+            Command::New { user, password } => {
+                let permissions = self.permissions.read().await;
                 let perm = &permissions;
                 
-                match check_permisions(perm, user, password) {
-                    Ok(ok) => {
-                        let mut connections = block_on(self.connections.write());
-                        connections.insert(id.to_owned(), user.to_owned());
-                        Ok(ok)
+                match check_permisions(perm, &user, &password) {
+                    Ok(_) => {
+                        let mut connections = self.connections.write().await;
+                        connections.insert(id.to_owned(), user.clone());
+                        Ok(json::to_json!({"user": user}))
                     },
                     Err(err) => Err(err),
                 }
-                // Ok("New connection to this db".to_owned())
             },
-            "OPEN" => {
-                let file = split.next().unwrap_or_else(|| "Error");
-                match block_on(self.open(file)) {
-                    Ok(_) => Ok(format!("opened file {}",file)),
+            Command::Use(name) => {
+                self.collection_or_new(&name).await;
+                self.current.write().await.insert(id.to_owned(), name.clone());
+                Ok(json::to_json!({"collection": name}))
+            },
+            Command::Collections => {
+                let mut names: Vec<String> = self.collections.read().await.keys().cloned().collect();
+                names.sort();
+                Ok(json::to_json!(names))
+            },
+            Command::Open { collection, file } => {
+                // OPEN <file> binds the current collection, OPEN <collection> <file> binds a named one
+                let (name, collection) = match collection {
+                    Some(name) => {
+                        let collection = self.collection_or_new(&name).await;
+                        (name, collection)
+                    },
+                    None => {
+                        match self.target(id, None).await {
+                            Ok(x) => x,
+                            Err(e) => return Err(e),
+                        }
+                    },
+                };
+                let opened = collection.write().await.open(&file);
+                match opened.and(self.save_bindings().await) {
+                    Ok(_) => Ok(json::to_json!({"collection": name, "file": file})),
                     Err(e) => Err(e)
                 }
             },
-            "LOAD" => {
-                let file_enum = block_on(self.file.read());
-                match *file_enum {
-                    FileManager::Open(_) => {
-                        match block_on(self.load_from_file()){
-                            Ok(_) => Ok("loaded file successfully".to_owned()),
+            Command::Load(target) => {
+                match self.target(id, target).await {
+                    Ok((_, collection)) => {
+                        match collection.write().await.load() {
+                            Ok(_) => Ok(json::JSON::Null),
                             Err(e) => Err(e),
                         }
                     },
-                    FileManager::Closed => {
-                        Ok("There is no file to load from, please open one with OPEN".to_owned())
-                    }
+                    Err(e) => Err(e),
                 }
             },
-            "FND" => {
-                let key = split.next().unwrap_or_else(|| "Error");
-                let store = block_on(self.store.read());
-                if key.contains(".") {
-                    let mut key_split = key.split(".").peekable();
-                    let first_k = key_split.next().unwrap_or_else(||"Error");
-                    let first_v = store.get(first_k);
-                    match first_v {
-                        Some(val) => {
-                            rec_get(key_split,val)
-                        },
-                        None => {
-                            Err(error::ServerError::MISSING_DATA)
-                        },
-                    }
-                }else {
-                    let value = store.get(key);
-                    match value {
-                        Some(x) => {
-                            Ok(x.to_string())
-                        },
-                        None => Ok(json::JSON::Null.to_string())
-                    }
+            Command::IndexCreate { collection, path } => self.index(&collection, |x| x.create_index(&path)).await,
+            Command::IndexDrop { collection, path } => self.index(&collection, |x| x.drop_index(&path)).await,
+            Command::IndexList(collection) => self.index(&collection, |_| Ok(())).await,
+            Command::SchemaSet { collection, key, schema } => self.schema(&collection, |x| x.set_schema(&key, &schema)).await,
+            Command::SchemaGet { collection, key } => {
+                match self.collection(&collection).await {
+                    Ok(x) => Ok(x.read().await.schema(&key)),
+                    Err(e) => Err(e),
                 }
             },
-            "INS" => {
-                let key = split.next().unwrap_or_else(|| "Error");
-                let value_str = split.next().unwrap_or_else(|| "Error");
-                let value = serde_json::from_str::<json::JSON>(value_str);
-                match value {
-                    Ok(x) => {
-                        let x_string = value_str;
-                        match block_on(self.insert_to_store(key.to_owned(), x, 0)) {
-                            Ok(_) => Ok(x_string.to_owned()),
+            Command::SchemaDrop { collection, key } => self.schema(&collection, |x| x.drop_schema(&key)).await,
+            Command::SchemaList(collection) => self.schema(&collection, |_| Ok(())).await,
+            Command::Save(target) => {
+                let _ = self.save_permissions().await;
+                match self.target(id, target).await {
+                    Ok((_, collection)) => {
+                        match collection.write().await.save() {
+                            Ok(_) => Ok(json::JSON::Null),
                             Err(e) => Err(e),
                         }
                     },
-                    Err(_) => Err(error::ServerError::FAILED_READ),
+                    Err(e) => Err(e),
                 }
             },
-            "DEL" => {
-                let key = split.next().unwrap_or_else(|| "Error");
-                let mut store = block_on(self.store.write());
-                if key.contains(".") {
-                    let mut key_split = key.split(".").peekable();
-                    let first_k = key_split.next().unwrap_or_else(||"Error");
-                    let first_v = store.get_mut(first_k);
-                    match first_v {
-                        Some(val) => {
-                            rec_del(key_split,val)
-                        },
-                        None => {
-                            Err(error::ServerError::MISSING_DATA)
-                        },
-                    }
-                }else {
-                    match store.remove(key) {
-                        Some(x) =>  Ok(x.to_string()),
-                        None => Err(error::ServerError::INVALID_DATA),
-                    }
+            Command::Status(target) => {
+                match self.target(id, target).await {
+                    Ok((name, collection)) => {
+                        let mut status = collection.read().await.status();
+                        status["collection"] = json::to_json!(name);
+                        status["autosave"] = json::to_json!(*self.autosave.read().await);
+                        Ok(status)
+                    },
+                    Err(e) => Err(e),
                 }
             },
-            "SAVE" => {
-                let _ = self.save_permissions();
-                self.save_store()
-            }
-            _ => {
-                Err(error::ServerError::INVALID_ARG)
-            }
+            Command::Multi => self.begin(id, Transaction::default()).await,
+            Command::MultiWatch(target, keys) => {
+                // EXEC fails if one of the keys changed in between
+                let (name, collection) = match self.target(id, target).await {
+                    Ok(x) => x,
+                    Err(e) => return Err(e),
+                };
+                let mut transaction = Transaction::default();
+                let collection = collection.read().await;
+                for key in keys {
+                    let version = collection.version(&key);
+                    transaction.watched.push((name.clone(), key, version));
+                }
+                drop(collection);
+                self.begin(id, transaction).await
+            },
+            Command::Discard => {
+                match self.transactions.write().await.remove(id) {
+                    Some(_) => Ok(json::to_json!("OK")),
+                    None => Err(error::ServerError::INVALID_ARG),
+                }
+            },
+            Command::Exec => {
+                let transaction = match self.transactions.write().await.remove(id) {
+                    Some(x) => x,
+                    None => return Err(error::ServerError::INVALID_ARG),
+                };
+                self.exec(transaction).await
+            },
+            Command::Watch(target, pattern) => {
+                // pushes every change to matching keys to this connection
+                let (name, _) = match self.target(id, target).await {
+                    Ok(x) => x,
+                    Err(e) => return Err(e),
+                };
+                match self.watches.write().await.add(id, &name, pattern) {
+                    Ok(x) => Ok(json::to_json!({"watch": x})),
+                    Err(e) => Err(e),
+                }
+            },
+            Command::Unwatch(watch) => {
+                let mut watches = self.watches.write().await;
+                match watch {
+                    Some(x) => Ok(json::to_json!(watches.remove(id, x))),
+                    None => Ok(watches.list(id)),
+                }
+            },
+            Command::Autosave(policies) => {
+                *self.autosave.write().await = policies.clone();
+                Ok(json::to_json!(policies))
+            },
         }
-     
     }
-    fn send(&self, message: &str, sender: service_manager::other::Sender) -> Res<()> {
+    async fn send(&self, message: &str, sender: service_manager::other::Sender<'_>) -> Res<()> {
         match sender {
             // this writes back data from tcp servers
-            service_manager::other::Sender::TCP(x) => {
-                match block_on(x.write_all(message.as_bytes())) {
+            service_manager::other::Sender::TCP(x, framing) => {
+                match x.write_all(&framing.encode(message)).await {
                     Ok(_) => Ok(()),
                     Err(_) => Err(error::ServerError::NO_VALUE),
                 }
@@ -409,9 +630,340 @@ impl service_manager::other::Manager for DataBaseManager {
             _ => Err(error::ServerError::INVALID_ARG)
         }
     }
+
+    async fn subscribe(&self, id: &str) -> Option<UnboundedReceiver<String>> {
+        Some(self.watches.write().await.subscribe(id))
+    }
+
+    /// logs the connection out and forgets the collection it selected and the transaction it left open
+    async fn disconnect(&self, id: &str) {
+        self.connections.write().await.remove(id);
+        self.current.write().await.remove(id);
+        self.transactions.write().await.remove(id);
+    }
+}
+/// inserts item into the map. the new value must have the same json type as the one it replaces
+fn insert_into(store: &mut Store, k:String, v:json::JSON) -> Res<()> {
+    if k.starts_with('/') {
+        // a json pointer, its first token is the top-level key
+        let tokens = pointer::parse(&k)?;
+        return match tokens.split_first() {
+            Some((first, [])) => insert_whole(store, first.clone(), v),
+            Some((first, rest)) => {
+                match store.get_mut(first) {
+                    Some(doc) => insert_at(doc, rest, v),
+                    None => Err(error::ServerError::MISSING_DATA),
+                }
+            },
+            None => Err(error::ServerError::INVALID_ARG),
+        };
+    }
+    let key;
+    let mut split;
+
+    let contains = k.contains(".");
+    if contains {
+        split = k.split(".").peekable();
+        key = split.next().unwrap().to_owned();
+    }else {
+        split = "err.err".split(".").peekable();
+        key = k.clone();
+    }
+    if contains {
+        match store.get_mut(&key) {
+            Some(x) => {
+                if x.is_object() {
+                    rec_ins(split,x,v)
+                }else {
+                    // fail
+                    Err(error::ServerError::INVALID_JSON)
+                }
+            },
+            None => {
+                Err(error::ServerError::INVALID_DATA)
+            }
+        }
+    }else {
+        insert_whole(store, key, v)
+    }
+}
+
+/// the path of a dotted key or json pointer, its first token is the top-level key
+fn tokens(k: &str) -> Res<Vec<String>> {
+    if k.starts_with('/') {
+        pointer::parse(k)
+    } else {
+        Ok(k.split('.').map(|x| x.to_owned()).collect())
+    }
+}
+
+/// inserts like insert_into but first creates the objects missing on the way to a dotted key or json
+/// pointer, like mkdir -p. nothing is created if the insert fails
+fn insert_parents(store: &mut Store, k:String, v:json::JSON) -> Res<()> {
+    let tokens = tokens(&k)?;
+    let first = match tokens.first() {
+        Some(x) => x,
+        None => return Err(error::ServerError::INVALID_ARG),
+    };
+    let old = store.get(first).cloned();
+    let inserted = make_parents(store, &tokens).and_then(|_| insert_into(store, k, v));
+    if inserted.is_err() {
+        match old {
+            Some(x) => {
+                store.insert(first.clone(), x);
+            },
+            None => {
+                store.remove(first);
+            },
+        }
+    }
+    inserted
+}
+
+fn make_parents(store: &mut Store, tokens: &[String]) -> Res<()> {
+    let (first, rest) = match tokens.split_first() {
+        Some((_, [])) => return Ok(()),
+        Some(x) => x,
+        None => return Err(error::ServerError::INVALID_ARG),
+    };
+    if store.get(first).is_none() {
+        store.insert(first.clone(), json::JSON::Object(serde_json::Map::new()));
+    }
+    let mut doc = match store.get_mut(first) {
+        Some(x) => x,
+        None => return Err(error::ServerError::MISSING_DATA),
+    };
+    for token in &rest[..rest.len() - 1] {
+        doc = match doc {
+            json::JSON::Object(x) => x.entry(token.clone()).or_insert_with(|| json::JSON::Object(serde_json::Map::new())),
+            json::JSON::Array(x) => {
+                let i = pointer::index(token, x.len())?;
+                match x.get_mut(i) {
+                    Some(x) => x,
+                    None => return Err(error::ServerError::MISSING_DATA),
+                }
+            },
+            // a value on the way that is not a container is never replaced
+            _ => return Err(error::ServerError::INCOMPATIBLE_DATA_TYPES),
+        };
+    }
+    Ok(())
+}
+
+/// puts v at a top-level key, plain or as a json pointer, whatever was there before
+fn replace_whole(store: &mut Store, k:String, v:json::JSON) -> Res<()> {
+    let key = if k.starts_with('/') {
+        match pointer::parse(&k)?.as_slice() {
+            [x] => x.clone(),
+            _ => return Err(error::ServerError::INVALID_ARG),
+        }
+    } else {
+        k
+    };
+    store.insert(key, v);
+    Ok(())
+}
+
+/// puts v at a dotted key or json pointer in the place of whatever was there. an array index that
+/// exists has its element replaced rather than shifted along
+fn set_into(store: &mut Store, k:String, v:json::JSON) -> Res<()> {
+    match tokens(&k)?.split_first() {
+        Some((first, [])) => {
+            store.insert(first.clone(), v);
+            Ok(())
+        },
+        Some((first, rest)) => {
+            match store.get_mut(first) {
+                Some(doc) => pointer::set(doc, rest, v),
+                None => Err(error::ServerError::MISSING_DATA),
+            }
+        },
+        None => Err(error::ServerError::INVALID_ARG),
+    }
+}
+
+/// inserts a top-level key, a value already there can only be replaced by one of the same type
+fn insert_whole(store: &mut Store, key:String, v:json::JSON) -> Res<()> {
+    match store.get(&key) {
+        Some(x) => {
+            if same_type(x, &v) {
+                match store.insert(key, v) {
+                    Some(_) => Ok(()),
+                    None => Ok(()),
+                }
+            }else {
+                Err(error::ServerError::INCOMPATIBLE_DATA_TYPES)
+            }
+        },
+        None => {
+            match store.insert(key, v) {
+                Some(_) => Ok(()),
+                None => Ok(()),
+            }
+        },
+    }
+}
+
+/// inserts at a json pointer inside a document. an object member can only be replaced by a value of
+/// the same type, an array index inserts before it and `-` appends
+fn insert_at(doc: &mut json::JSON, tokens: &[String], v:json::JSON) -> Res<()> {
+    if let Some((last, parent)) = tokens.split_last() {
+        if let Some(json::JSON::Object(x)) = pointer::get_mut(doc, parent) {
+            if let Some(old) = x.get(last) {
+                if !same_type(old, &v) {
+                    return Err(error::ServerError::INCOMPATIBLE_DATA_TYPES);
+                }
+            }
+        }
+    }
+    pointer::add(doc, tokens, v)
+}
+
+fn same_type(x: &json::JSON, v: &json::JSON) -> bool {
+    (x.is_array() && v.is_array()) || (x.is_string() && v.is_string()) || (x.is_object() && v.is_object()) ||
+    (x.is_boolean() && v.is_boolean()) || (x.is_number() && v.is_number()) || (x.is_f64() && v.is_f64()) ||
+    (x.is_i64() && v.is_i64()) || (x.is_u64() && v.is_u64())
+}
+
+/// removes the key from the map and returns what was there. works with nested objects
+fn delete_from(store: &mut Store, key:&str) -> Res<json::JSON> {
+    if key.starts_with('/') {
+        let tokens = pointer::parse(key)?;
+        return match tokens.split_first() {
+            Some((first, [])) => store.remove(first).ok_or(error::ServerError::MISSING_DATA),
+            Some((first, rest)) => {
+                match store.get_mut(first) {
+                    Some(doc) => pointer::remove(doc, rest),
+                    None => Err(error::ServerError::MISSING_DATA),
+                }
+            },
+            None => Err(error::ServerError::INVALID_ARG),
+        };
+    }
+    if key.contains(".") {
+        let mut key_split = key.split(".").peekable();
+        let first_k = key_split.next().unwrap_or_else(||"Error");
+        let first_v = store.get_mut(first_k);
+        match first_v {
+            Some(val) => {
+                rec_del(key_split,val)
+            },
+            None => {
+                Err(error::ServerError::MISSING_DATA)
+            },
+        }
+    }else {
+        match store.remove(key) {
+            Some(x) =>  Ok(x),
+            None => Err(error::ServerError::MISSING_DATA),
+        }
+    }
+}
+
+fn read_command(collection: &Collection, read: &Read) -> Res<json::JSON> {
+    match read {
+        Read::Find(key) => collection.get(key),
+        Read::FindBy { path, value } => Ok(collection.find_by(path, value)),
+        Read::Scan(args) => Ok(collection.scan(args)),
+        Read::Range(args) => Ok(collection.range(args)),
+        Read::Query(query) => Ok(collection.query(query)),
+        Read::Version(key) => Ok(json::to_json!(collection.version(key))),
+        Read::Ttl(key) => Ok(json::to_json!(collection.ttl(key))),
+    }
+}
+
+fn write_command(collection: &mut Collection, write: Write) -> Res<json::JSON> {
+    match write {
+        Write::Insert { key, value, parents, replace, expires } => {
+            let expires = expires.map(expiry::after_secs);
+            let inserted = if replace {
+                collection.replace(&key, value.clone(), expires)
+            } else if parents {
+                collection.insert_parents(&key, value.clone(), expires)
+            } else {
+                collection.insert_expiring(&key, value.clone(), expires)
+            };
+            match inserted {
+                Ok(_) => Ok(value),
+                Err(e) => Err(e),
+            }
+        },
+        Write::Expire { key, seconds } => collection.expire(&key, seconds).map(|x| json::to_json!(x)),
+        Write::Persist(key) => collection.persist(&key).map(|x| json::to_json!(x)),
+        Write::Incr { key, by } => collection.update(&key, |current| ops::incr(current, &by)),
+        Write::Decr { key, by } => {
+            let by = ops::negate(&by)?;
+            collection.update(&key, |current| ops::incr(current, &by))
+        },
+        Write::Push { key, front, values } => collection.update(&key, |current| ops::push(current, values, front)),
+        Write::Pop { key, front } => collection.update(&key, |current| ops::pop(current, front)),
+        Write::Remove { key, value } => collection.update(&key, |current| ops::remove(current, &value)),
+        Write::Append { key, suffix } => collection.update(&key, |current| ops::append(current, &suffix)),
+        Write::Merge { key, patch } => {
+            collection.update(&key, |current| {
+                let mut merged = current.unwrap_or(json::JSON::Null);
+                patch::merge_patch(&mut merged, patch);
+                Ok((Some(merged.clone()), merged))
+            })
+        },
+        Write::Patch { key, operations } => {
+            collection.update(&key, |current| {
+                let patched = patch::json_patch(&current.unwrap_or(json::JSON::Null), &operations)?;
+                Ok((Some(patched.clone()), patched))
+            })
+        },
+        Write::Delete(key) => collection.delete(&key),
+        Write::Cas { key, expected, value } => {
+            // version 0 expects the key to be missing
+            match collection.cas(&key, expected, value) {
+                Ok(version) => Ok(json::to_json!({"version": version})),
+                Err(e) => Err(e),
+            }
+        },
+    }
+}
+
+/// returns the value at key, null if there is none. works with nested objects
+fn get_from(store: &Store, key:&str) -> Res<json::JSON> {
+    if key.starts_with('/') {
+        // a missing top-level key reads as null, a missing path inside one is an error
+        let tokens = pointer::parse(key)?;
+        return match tokens.split_first() {
+            Some((first, rest)) => {
+                match store.get(first) {
+                    Some(doc) => pointer::get(doc, rest).cloned().ok_or(error::ServerError::MISSING_DATA),
+                    None if rest.is_empty() => Ok(json::JSON::Null),
+                    None => Err(error::ServerError::MISSING_DATA),
+                }
+            },
+            None => Err(error::ServerError::INVALID_ARG),
+        };
+    }
+    if key.contains(".") {
+        let mut key_split = key.split(".").peekable();
+        let first_k = key_split.next().unwrap_or_else(||"Error");
+        let first_v = store.get(first_k);
+        match first_v {
+            Some(val) => {
+                rec_get(key_split,val)
+            },
+            None => {
+                Err(error::ServerError::MISSING_DATA)
+            },
+        }
+    }else {
+        let value = store.get(key);
+        match value {
+            Some(x) => {
+                Ok(x.clone())
+            },
+            None => Ok(json::JSON::Null)
+        }
+    }
 }
+
 /// deletes item from database if presents. works with nested objects
-fn rec_del(mut key_split:Peekable<Split<&str>>, map: &mut json::JSON) -> Res<String> {
+fn rec_del(mut key_split:Peekable<Split<&str>>, map: &mut json::JSON) -> Res<json::JSON> {
     let key = key_split.next();
     if key_split.peek().is_some() {
         match key {
@@ -437,11 +989,11 @@ fn rec_del(mut key_split:Peekable<Split<&str>>, map: &mut json::JSON) -> Res<Str
                     Some(map) => {
                         let value = map.remove(key);
                         match value {
-                            Some(val) => Ok(val.to_string()),
-                            None => Ok(String::from("NOT_FOUND")),
+                            Some(val) => Ok(val),
+                            None => Err(error::ServerError::MISSING_DATA),
                         }
                     },
-                    None => todo!(),
+                    None => Err(error::ServerError::INCOMPATIBLE_DATA_TYPES),
                 }
             },
             None => Err(error::ServerError::INVALID_ARG),
@@ -450,7 +1002,7 @@ fn rec_del(mut key_split:Peekable<Split<&str>>, map: &mut json::JSON) -> Res<Str
 }
 
 // recursively gets the specified key
-fn rec_get(mut key_split:Peekable<Split<&str>>, map: &json::JSON) -> Res<String> {
+fn rec_get(mut key_split:Peekable<Split<&str>>, map: &json::JSON) -> Res<json::JSON> {
     let key = key_split.next();
     if key_split.peek().is_some() {
         match key {
@@ -474,8 +1026,8 @@ fn rec_get(mut key_split:Peekable<Split<&str>>, map: &json::JSON) -> Res<String>
             Some(key) => {
                 let value = map.get(key);
                 match value {
-                    Some(val) => Ok(val.to_string()),
-                    None => Ok(json::JSON::Null.to_string()),
+                    Some(val) => Ok(val.clone()),
+                    None => Ok(json::JSON::Null),
                 }
             },
             None => Err(error::ServerError::INVALID_ARG),
@@ -488,16 +1040,13 @@ fn rec_ins(mut str_iter:Peekable<Split<&str>>, map: &mut json::JSON, val:json::J
     let key = str_iter.next();
     match key {
         Some(str) => {
-            println!("{:?}",map);
             match map {
                 serde_json::Value::Object(map_obj) => {
-                    println!("map passed as object, key:{}",str);
                     let mo_v_o = map_obj.get_mut(str);
                     match str_iter.peek().is_some() {
                         true => {
                             match mo_v_o{
                                     Some(mo_v) => {
-                                        println!("value passed to func {}",mo_v);
                                         rec_ins(str_iter,mo_v,val)
                                     },
                                     None => Err(error::ServerError::INVALID_ARG),
@@ -535,7 +1084,7 @@ fn rec_ins(mut str_iter:Peekable<Split<&str>>, map: &mut json::JSON, val:json::J
     }
 }
 
-fn check_permisions(permissions:&RwLockReadGuard<HashMap<String, json::JSON>> ,user: &str, password: &str) -> Res<String> {
+fn check_permisions(permissions:&RwLockReadGuard<Store> ,user: &str, password: &str) -> Res<()> {
     let map = permissions.get("super").unwrap_or_else(||&json::JSON::Null);
     if map.is_null() {
         return Err(error::ServerError::INCOMPATIBLE_DATA_TYPES)
@@ -548,7 +1097,7 @@ fn check_permisions(permissions:&RwLockReadGuard<HashMap<String, json::JSON>> ,u
     match super_val {
         serde_json::Value::String(pass) => {
             if pass == password {
-                Ok("New connection to this db".to_owned())
+                Ok(())
             }else { 
                 Err(error::ServerError::ACCESS_DENIED)
             }
@@ -566,7 +1115,7 @@ fn check_permisions(permissions:&RwLockReadGuard<HashMap<String, json::JSON>> ,u
                 serde_json::Value::String(pass) => {
                     match super::session_manager::hash_match(password.as_bytes(), &pass) {
                         Ok(_) => {
-                            Ok("New connection to this db".to_owned())
+                            Ok(())
                         },
                         Err(_) => {
                             Err(error::ServerError::ACCESS_DENIED)
@@ -577,4 +1126,272 @@ fn check_permisions(permissions:&RwLockReadGuard<HashMap<String, json::JSON>> ,u
             }
         },
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use service_manager::other::Manager;
+
+    /// a fresh file in the temp directory, gone once the test is done with it
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new() -> TempFile {
+            let path = std::env::temp_dir().join(format!("database-{}.json", uuid::Uuid::new_v4()));
+            TempFile(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            for extension in ["", ".wal", ".meta", ".tmp"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.0, extension));
+            }
+        }
+    }
+
+    /// a manager without background tasks, with the connection `c` logged in
+    pub(super) async fn manager() -> DataBaseManager {
+        let manager = DataBaseManager {
+            permissions: RwLock::new(Store::new(StoreKind::Hash)),
+            connections: RwLock::new(HashMap::new()),
+            collections: RwLock::new(HashMap::new()),
+            current: RwLock::new(HashMap::new()),
+            autosave: RwLock::new(Vec::new()),
+            transactions: RwLock::new(HashMap::new()),
+            kind: StoreKind::Hash,
+            watches: RwLock::new(watch::Watches::default()),
+        };
+        manager.collection_or_new(collection::DEFAULT).await;
+        manager.connections.write().await.insert("c".to_owned(), "admin".to_owned());
+        manager
+    }
+
+    async fn opened(manager: &DataBaseManager, name: &str, file: &TempFile) -> Arc<RwLock<Collection>> {
+        let collection = manager.collection_or_new(name).await;
+        collection.write().await.open(&file.0).unwrap();
+        collection.write().await.load().unwrap();
+        collection
+    }
+
+    fn reloaded(file: &TempFile) -> Collection {
+        let mut collection = Collection::new(StoreKind::Hash);
+        collection.open(&file.0).unwrap();
+        collection.load().unwrap();
+        collection
+    }
+
+    async fn run(manager: &DataBaseManager, id: &str, command: &str) -> Res<json::JSON> {
+        manager.process_message(command, id).await
+    }
+
+    #[tokio::test]
+    async fn exec_commits_every_collection() {
+        let (file_a, file_b) = (TempFile::new(), TempFile::new());
+        let manager = manager().await;
+        opened(&manager, "a", &file_a).await;
+        opened(&manager, "b", &file_b).await;
+
+        run(&manager, "c", "MULTI").await.unwrap();
+        assert_eq!(run(&manager, "c", "INS -c a k 1").await.unwrap(), json::to_json!("QUEUED"));
+        assert_eq!(run(&manager, "c", "INS -c b k 2").await.unwrap(), json::to_json!("QUEUED"));
+        assert_eq!(run(&manager, "c", "EXEC").await.unwrap(), json::to_json!([1, 2]));
+
+        assert_eq!(reloaded(&file_a).get("k").unwrap(), json::to_json!(1));
+        assert_eq!(reloaded(&file_b).get("k").unwrap(), json::to_json!(2));
+    }
+
+    #[tokio::test]
+    async fn ins_f_replaces_a_value_of_another_type() {
+        let file = TempFile::new();
+        let manager = manager().await;
+        opened(&manager, "a", &file).await;
+        run(&manager, "c", "INS -c a k {\"x\": 1}").await.unwrap();
+        assert_eq!(run(&manager, "c", "INS -c a k 2").await.unwrap_err().code(), "INCOMPATIBLE_DATA_TYPES");
+        run(&manager, "c", "INS -c a -f k 2").await.unwrap();
+        run(&manager, "c", "INS -f -c a /k \"text\" EX 100").await.unwrap();
+        assert_eq!(run(&manager, "c", "INS -c a -f k.x 2").await.unwrap_err().code(), "INVALID_ARG");
+
+        let collection = reloaded(&file);
+        assert_eq!(collection.get("k").unwrap(), json::to_json!("text"));
+        assert!(collection.ttl("k") > 0);
+    }
+
+    #[tokio::test]
+    async fn updates_replace_array_elements_in_place() {
+        let file = TempFile::new();
+        let manager = manager().await;
+        opened(&manager, "a", &file).await;
+        run(&manager, "c", "INS -c a arr [[1], 5]").await.unwrap();
+        assert_eq!(run(&manager, "c", "INCR -c a /arr/1 2").await.unwrap(), json::to_json!(7));
+        run(&manager, "c", "PUSH -c a /arr/0 2").await.unwrap();
+        assert_eq!(run(&manager, "c", "FND -c a arr").await.unwrap(), json::to_json!([[1, 2], 7]));
+        run(&manager, "c", "POP -c a /arr/0").await.unwrap();
+        assert_eq!(run(&manager, "c", "FND -c a arr").await.unwrap(), json::to_json!([[1], 7]));
+
+        // the log puts them back in place too
+        assert_eq!(reloaded(&file).get("arr").unwrap(), json::to_json!([[1], 7]));
+    }
+
+    #[tokio::test]
+    async fn patches_can_change_types() {
+        let file = TempFile::new();
+        let manager = manager().await;
+        opened(&manager, "a", &file).await;
+        run(&manager, "c", "INS -c a k {\"x\": 1}").await.unwrap();
+        assert_eq!(run(&manager, "c", "MERGE -c a k 5").await.unwrap(), json::to_json!(5));
+        assert_eq!(run(&manager, "c", "MERGE -c a k {\"y\": [1]}").await.unwrap(), json::to_json!({"y": [1]}));
+        assert_eq!(run(&manager, "c", "MERGE -c a k [1, 2]").await.unwrap(), json::to_json!([1, 2]));
+        assert_eq!(run(&manager, "c", "PATCH -c a k [{\"op\": \"replace\", \"path\": \"\", \"value\": \"s\"}]").await.unwrap(), json::to_json!("s"));
+        run(&manager, "c", "INS -c a d {\"x\": 1}").await.unwrap();
+        run(&manager, "c", "PATCH -c a d [{\"op\": \"replace\", \"path\": \"/x\", \"value\": \"one\"}]").await.unwrap();
+        assert_eq!(run(&manager, "c", "MERGE -c a /d/x {\"y\": 2}").await.unwrap(), json::to_json!({"y": 2}));
+
+        // a document merged into null is gone
+        run(&manager, "c", "INS -c a gone {\"x\": 1}").await.unwrap();
+        assert_eq!(run(&manager, "c", "MERGE -c a gone null").await.unwrap(), json::JSON::Null);
+        assert_eq!(run(&manager, "c", "MERGE -c a missing null").await.unwrap(), json::JSON::Null);
+
+        let collection = reloaded(&file);
+        assert_eq!(collection.get("k").unwrap(), json::to_json!("s"));
+        assert_eq!(collection.get("d").unwrap(), json::to_json!({"x": {"y": 2}}));
+        assert_eq!(collection.ttl("gone"), -2);
+        assert_eq!(collection.ttl("missing"), -2);
+    }
+
+    #[tokio::test]
+    async fn patches_still_match_the_schema() {
+        let manager = manager().await;
+        run(&manager, "c", "INS k {\"x\": 1}").await.unwrap();
+        run(&manager, "c", "SCHEMA SET default k {\"type\": \"object\"}").await.unwrap();
+        assert_eq!(run(&manager, "c", "MERGE k 5").await.unwrap_err().code(), "SCHEMA_VIOLATION");
+        assert_eq!(run(&manager, "c", "PATCH k [{\"op\": \"replace\", \"path\": \"\", \"value\": 5}]").await.unwrap_err().code(), "SCHEMA_VIOLATION");
+        assert_eq!(run(&manager, "c", "FND k").await.unwrap(), json::to_json!({"x": 1}));
+        run(&manager, "c", "MERGE k {\"x\": \"one\"}").await.unwrap();
+        assert_eq!(run(&manager, "c", "FND k").await.unwrap(), json::to_json!({"x": "one"}));
+    }
+
+    #[tokio::test]
+    async fn ins_p_makes_the_parents() {
+        let file = TempFile::new();
+        let manager = manager().await;
+        opened(&manager, "a", &file).await;
+        run(&manager, "c", "INS -c a -p settings.ui.theme \"dark\"").await.unwrap();
+        run(&manager, "c", "INS -c a -p settings.ui.size 12").await.unwrap();
+        run(&manager, "c", "INS -c a -p /paths/a~1b/~0c true").await.unwrap();
+        run(&manager, "c", "INS -c a list [{}]").await.unwrap();
+        run(&manager, "c", "INS -c a -p /list/0/x/y 1").await.unwrap();
+        assert_eq!(run(&manager, "c", "FND -c a settings").await.unwrap(), json::to_json!({"ui": {"theme": "dark", "size": 12}}));
+        assert_eq!(run(&manager, "c", "FND -c a paths").await.unwrap(), json::to_json!({"a/b": {"~c": true}}));
+
+        let collection = reloaded(&file);
+        assert_eq!(collection.get("settings.ui.theme").unwrap(), json::to_json!("dark"));
+        assert_eq!(collection.get("/paths/a~1b/~0c").unwrap(), json::to_json!(true));
+        assert_eq!(collection.get("list").unwrap(), json::to_json!([{"x": {"y": 1}}]));
+    }
+
+    #[test]
+    fn parents_are_taken_back_when_the_insert_fails() {
+        let mut store = Store::new(StoreKind::Hash);
+        store.insert("s".to_owned(), json::to_json!({"a": {"b": 1}, "list": []}));
+        store.insert("n".to_owned(), json::to_json!(1));
+        // a scalar on the way is never replaced
+        for key in ["s.a.b.c.d", "/s/a/b/c/d", "n.a.b", "/n/a/b"] {
+            assert_eq!(insert_parents(&mut store, key.to_owned(), json::to_json!(1)).unwrap_err().code(), "INCOMPATIBLE_DATA_TYPES");
+        }
+        // and an array is never grown
+        assert_eq!(insert_parents(&mut store, "/s/list/0/x".to_owned(), json::to_json!(1)).unwrap_err().code(), "MISSING_DATA");
+        assert_eq!(insert_parents(&mut store, "/s/list/x/y".to_owned(), json::to_json!(1)).unwrap_err().code(), "INVALID_ARG");
+        // the value at the end has to fit what is there
+        assert_eq!(insert_parents(&mut store, "s.a.b".to_owned(), json::to_json!("x")).unwrap_err().code(), "INCOMPATIBLE_DATA_TYPES");
+        assert_eq!(store.get("s"), Some(&json::to_json!({"a": {"b": 1}, "list": []})));
+        assert_eq!(store.get("n"), Some(&json::to_json!(1)));
+        assert_eq!(store.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn ins_p_takes_back_the_parents_it_made() {
+        let manager = manager().await;
+        run(&manager, "c", "SCHEMA SET default settings {\"properties\": {\"ui\": {\"required\": [\"size\"]}}}").await.unwrap();
+        assert_eq!(run(&manager, "c", "INS -p settings.ui.theme \"dark\"").await.unwrap_err().code(), "SCHEMA_VIOLATION");
+        assert_eq!(run(&manager, "c", "INS -p /settings/ui/theme \"dark\"").await.unwrap_err().code(), "SCHEMA_VIOLATION");
+        assert_eq!(run(&manager, "c", "FND settings").await.unwrap(), json::JSON::Null);
+
+        run(&manager, "c", "INS settings {\"other\": 1}").await.unwrap();
+        assert_eq!(run(&manager, "c", "INS -p settings.ui.theme \"dark\"").await.unwrap_err().code(), "SCHEMA_VIOLATION");
+        assert_eq!(run(&manager, "c", "FND settings").await.unwrap(), json::to_json!({"other": 1}));
+        run(&manager, "c", "INS -p settings.ui.size 12").await.unwrap();
+        assert_eq!(run(&manager, "c", "FND settings").await.unwrap(), json::to_json!({"other": 1, "ui": {"size": 12}}));
+    }
+
+    #[tokio::test]
+    async fn exec_rolls_back_when_a_command_fails() {
+        let manager = manager().await;
+        run(&manager, "c", "INS s \"text\"").await.unwrap();
+        run(&manager, "c", "MULTI").await.unwrap();
+        run(&manager, "c", "INS k 1").await.unwrap();
+        run(&manager, "c", "INCR s").await.unwrap();
+        assert!(run(&manager, "c", "EXEC").await.is_err());
+        assert_eq!(run(&manager, "c", "FND k").await.unwrap(), json::JSON::Null);
+        assert_eq!(run(&manager, "c", "FND s").await.unwrap(), json::to_json!("text"));
+    }
+
+    #[tokio::test]
+    async fn exec_takes_back_every_collection_when_a_log_fails() {
+        let (file_a, file_b) = (TempFile::new(), TempFile::new());
+        let manager = manager().await;
+        let a = opened(&manager, "a", &file_a).await;
+        let b = opened(&manager, "b", &file_b).await;
+        run(&manager, "c", "INS -c a k 1").await.unwrap();
+        run(&manager, "c", "INS -c b k 1").await.unwrap();
+        // collections commit in name order, so a is already in its log when b fails
+        b.write().await.break_log();
+
+        run(&manager, "c", "MULTI").await.unwrap();
+        run(&manager, "c", "INS -c a k 2").await.unwrap();
+        run(&manager, "c", "INS -c a n 3").await.unwrap();
+        run(&manager, "c", "INS -c b k 2").await.unwrap();
+        assert_eq!(run(&manager, "c", "EXEC").await.unwrap_err().code(), "FAILED_WRITE");
+
+        assert_eq!(a.read().await.get("k").unwrap(), json::to_json!(1));
+        assert_eq!(a.read().await.get("n").unwrap(), json::JSON::Null);
+        assert_eq!(b.read().await.get("k").unwrap(), json::to_json!(1));
+        let replayed = reloaded(&file_a);
+        assert_eq!(replayed.get("k").unwrap(), json::to_json!(1));
+        assert_eq!(replayed.get("n").unwrap(), json::JSON::Null);
+
+        // no collection is left holding back its writes for a transaction
+        run(&manager, "c", "INS -c a later 4").await.unwrap();
+        assert_eq!(reloaded(&file_a).get("later").unwrap(), json::to_json!(4));
+        assert_eq!(run(&manager, "c", "INS -c b later 4").await.unwrap_err().code(), "FAILED_WRITE");
+    }
+
+    #[tokio::test]
+    async fn transactions_belong_to_their_connection() {
+        let manager = manager().await;
+        manager.connections.write().await.insert("d".to_owned(), "admin".to_owned());
+        run(&manager, "c", "MULTI").await.unwrap();
+        assert_eq!(run(&manager, "d", "INS k 1").await.unwrap(), json::to_json!(1));
+        assert_eq!(run(&manager, "d", "EXEC").await.unwrap_err().code(), "INVALID_ARG");
+
+        manager.disconnect("c").await;
+        assert!(manager.transactions.read().await.is_empty());
+        assert_eq!(run(&manager, "c", "FND k").await.unwrap_err().code(), "ACCESS_DENIED");
+    }
+
+    #[tokio::test]
+    async fn sweeping_notifies_watches() {
+        let manager = manager().await;
+        let mut events = manager.watches.write().await.subscribe("c");
+        run(&manager, "c", "WATCH k*").await.unwrap();
+        let collection = manager.collection_or_new(collection::DEFAULT).await;
+        collection.write().await.insert_expiring("k1", json::to_json!(1), Some(expiry::now_ms() - 1)).unwrap();
+        collection.write().await.insert_expiring("k2", json::to_json!(2), Some(expiry::now_ms() + 60_000)).unwrap();
+
+        manager.sweep().await;
+        let event = events.try_recv().unwrap();
+        assert!(event.contains(r#""event":"EXPIRED""#) && event.contains(r#""key":"k1""#));
+        assert!(events.try_recv().is_err());
+        assert_eq!(collection.read().await.get("k1").unwrap(), json::JSON::Null);
+    }
+}
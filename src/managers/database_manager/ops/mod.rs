@@ -0,0 +1,165 @@
+use super::super::{error, json};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// what an operation leaves at the key, none if it did not change anything, and what it replies with
+pub type Outcome = (Option<json::JSON>, json::JSON);
+
+/// adds by to the number at the key, a missing key counts as 0. integers stay integers
+pub fn incr(current: Option<json::JSON>, by: &json::JSON) -> Res<Outcome> {
+    let current = current.unwrap_or_else(|| json::to_json!(0));
+    let sum = match (current.as_i64(), by.as_i64()) {
+        (Some(a), Some(b)) => {
+            match a.checked_add(b) {
+                Some(x) => json::to_json!(x),
+                None => return Err(error::ServerError::INVALID_DATA),
+            }
+        },
+        _ => {
+            match (current.as_f64(), by.as_f64()) {
+                (Some(a), Some(b)) => {
+                    match serde_json::Number::from_f64(a + b) {
+                        Some(x) => json::JSON::Number(x),
+                        None => return Err(error::ServerError::INVALID_DATA),
+                    }
+                },
+                _ => return Err(error::ServerError::INCOMPATIBLE_DATA_TYPES),
+            }
+        },
+    };
+    Ok((Some(sum.clone()), sum))
+}
+
+/// the amount with its sign flipped, for DECR
+pub fn negate(by: &json::JSON) -> Res<json::JSON> {
+    match by.as_i64() {
+        Some(x) => {
+            match x.checked_neg() {
+                Some(x) => Ok(json::to_json!(x)),
+                None => Err(error::ServerError::INVALID_DATA),
+            }
+        },
+        None => {
+            match by.as_f64().and_then(|x| serde_json::Number::from_f64(-x)) {
+                Some(x) => Ok(json::JSON::Number(x)),
+                None => Err(error::ServerError::INVALID_ARG),
+            }
+        },
+    }
+}
+
+/// adds the values to the end of the array at the key, or to its front. replies with the new length
+pub fn push(current: Option<json::JSON>, values: Vec<json::JSON>, front: bool) -> Res<Outcome> {
+    let mut array = match current {
+        Some(json::JSON::Array(x)) => x,
+        Some(_) => return Err(error::ServerError::INCOMPATIBLE_DATA_TYPES),
+        None => Vec::new(),
+    };
+    if front {
+        array.splice(0..0, values);
+    } else {
+        array.extend(values);
+    }
+    let len = array.len();
+    Ok((Some(json::JSON::Array(array)), json::to_json!(len)))
+}
+
+/// takes the last value out of the array at the key, or the first. replies with it, null if the array is empty
+pub fn pop(current: Option<json::JSON>, front: bool) -> Res<Outcome> {
+    let mut array = match current {
+        Some(json::JSON::Array(x)) => x,
+        Some(_) => return Err(error::ServerError::INCOMPATIBLE_DATA_TYPES),
+        None => return Ok((None, json::JSON::Null)),
+    };
+    let popped = if front && !array.is_empty() { Some(array.remove(0)) } else { array.pop() };
+    match popped {
+        Some(x) => Ok((Some(json::JSON::Array(array)), x)),
+        None => Ok((None, json::JSON::Null)),
+    }
+}
+
+/// takes every element equal to value out of the array at the key. replies with how many there were
+pub fn remove(current: Option<json::JSON>, value: &json::JSON) -> Res<Outcome> {
+    let mut array = match current {
+        Some(json::JSON::Array(x)) => x,
+        Some(_) => return Err(error::ServerError::INCOMPATIBLE_DATA_TYPES),
+        None => return Ok((None, json::to_json!(0))),
+    };
+    let before = array.len();
+    array.retain(|x| x != value);
+    let removed = before - array.len();
+    if removed == 0 {
+        return Ok((None, json::to_json!(0)));
+    }
+    Ok((Some(json::JSON::Array(array)), json::to_json!(removed)))
+}
+
+/// adds suffix to the end of the string at the key, a missing key counts as empty. replies with the new length
+pub fn append(current: Option<json::JSON>, suffix: &str) -> Res<Outcome> {
+    let mut string = match current {
+        Some(json::JSON::String(x)) => x,
+        Some(_) => return Err(error::ServerError::INCOMPATIBLE_DATA_TYPES),
+        None => String::new(),
+    };
+    string.push_str(suffix);
+    let len = string.chars().count();
+    Ok((Some(json::JSON::String(string)), json::to_json!(len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incr_keeps_integers() {
+        assert_eq!(incr(None, &json::to_json!(2)).unwrap(), (Some(json::to_json!(2)), json::to_json!(2)));
+        assert_eq!(incr(Some(json::to_json!(5)), &json::to_json!(-7)).unwrap().1, json::to_json!(-2));
+        assert!(incr(Some(json::to_json!(1)), &json::to_json!(1)).unwrap().1.is_i64());
+        assert_eq!(incr(Some(json::to_json!(1)), &json::to_json!(0.5)).unwrap().1, json::to_json!(1.5));
+        assert_eq!(incr(Some(json::to_json!(i64::MAX)), &json::to_json!(1)).unwrap_err().code(), "INVALID_DATA");
+        assert_eq!(incr(Some(json::to_json!("a")), &json::to_json!(1)).unwrap_err().code(), "INCOMPATIBLE_DATA_TYPES");
+    }
+
+    #[test]
+    fn negate_flips_the_sign() {
+        assert_eq!(negate(&json::to_json!(3)).unwrap(), json::to_json!(-3));
+        assert_eq!(negate(&json::to_json!(1.5)).unwrap(), json::to_json!(-1.5));
+        assert_eq!(negate(&json::to_json!(i64::MIN)).unwrap_err().code(), "INVALID_DATA");
+        assert_eq!(negate(&json::to_json!("3")).unwrap_err().code(), "INVALID_ARG");
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let (array, len) = push(None, vec![json::to_json!(1), json::to_json!(2)], false).unwrap();
+        assert_eq!(len, json::to_json!(2));
+        let (array, len) = push(array, vec![json::to_json!(0)], true).unwrap();
+        assert_eq!(array, Some(json::to_json!([0, 1, 2])));
+        assert_eq!(len, json::to_json!(3));
+
+        let (array, popped) = pop(array, true).unwrap();
+        assert_eq!(popped, json::to_json!(0));
+        let (array, popped) = pop(array, false).unwrap();
+        assert_eq!((array, popped), (Some(json::to_json!([1])), json::to_json!(2)));
+
+        assert_eq!(pop(Some(json::to_json!([])), false).unwrap(), (None, json::JSON::Null));
+        assert_eq!(pop(None, true).unwrap(), (None, json::JSON::Null));
+        assert_eq!(push(Some(json::to_json!({})), vec![], false).unwrap_err().code(), "INCOMPATIBLE_DATA_TYPES");
+        assert_eq!(pop(Some(json::to_json!(1)), false).unwrap_err().code(), "INCOMPATIBLE_DATA_TYPES");
+    }
+
+    #[test]
+    fn remove_every_match() {
+        let (array, removed) = remove(Some(json::to_json!([1, 2, 1, 3])), &json::to_json!(1)).unwrap();
+        assert_eq!((array, removed), (Some(json::to_json!([2, 3])), json::to_json!(2)));
+        assert_eq!(remove(Some(json::to_json!([2])), &json::to_json!(1)).unwrap(), (None, json::to_json!(0)));
+        assert_eq!(remove(None, &json::to_json!(1)).unwrap(), (None, json::to_json!(0)));
+        assert_eq!(remove(Some(json::to_json!("1")), &json::to_json!(1)).unwrap_err().code(), "INCOMPATIBLE_DATA_TYPES");
+    }
+
+    #[test]
+    fn append_counts_characters() {
+        assert_eq!(append(None, "ab").unwrap(), (Some(json::to_json!("ab")), json::to_json!(2)));
+        assert_eq!(append(Some(json::to_json!("é")), "ü").unwrap(), (Some(json::to_json!("éü")), json::to_json!(2)));
+        assert_eq!(append(Some(json::to_json!(1)), "a").unwrap_err().code(), "INCOMPATIBLE_DATA_TYPES");
+    }
+}
@@ -0,0 +1,162 @@
+use super::super::{error, json};
+use super::pointer;
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// applies an rfc 7386 merge patch. fields set to null are removed, objects are merged field by
+/// field and anything else replaces what was there
+pub fn merge_patch(target: &mut json::JSON, patch: json::JSON) {
+    match patch {
+        json::JSON::Object(patch) => {
+            if !target.is_object() {
+                *target = json::JSON::Object(serde_json::Map::new());
+            }
+            if let json::JSON::Object(target) = target {
+                for (k, v) in patch {
+                    if v.is_null() {
+                        target.remove(&k);
+                    } else {
+                        merge_patch(target.entry(k).or_insert(json::JSON::Null), v);
+                    }
+                }
+            }
+        },
+        patch => *target = patch,
+    }
+}
+
+/// applies an rfc 6902 json patch, a list of add, remove, replace, move, copy and test operations.
+/// the operations run on a copy so the document is only changed if every one of them succeeds
+pub fn json_patch(doc: &json::JSON, operations: &json::JSON) -> Res<json::JSON> {
+    let operations = match operations.as_array() {
+        Some(x) => x,
+        None => return Err(error::ServerError::INVALID_ARG),
+    };
+    let mut patched = doc.clone();
+    for operation in operations {
+        apply(&mut patched, operation)?;
+    }
+    Ok(patched)
+}
+
+fn apply(doc: &mut json::JSON, operation: &json::JSON) -> Res<()> {
+    let op = match operation.get("op").and_then(|x| x.as_str()) {
+        Some(x) => x,
+        None => return Err(error::ServerError::INVALID_ARG),
+    };
+    let path = pointer_at(operation, "path")?;
+    match op {
+        "add" => pointer::add(doc, &path, value_of(operation)?),
+        "remove" => pointer::remove(doc, &path).map(|_| ()),
+        "replace" => {
+            match pointer::get_mut(doc, &path) {
+                Some(x) => {
+                    *x = value_of(operation)?;
+                    Ok(())
+                },
+                None => Err(error::ServerError::MISSING_DATA),
+            }
+        },
+        "move" => {
+            let from = pointer_at(operation, "from")?;
+            // a value can not be moved into itself
+            if path.len() > from.len() && path.starts_with(&from) {
+                return Err(error::ServerError::INVALID_ARG);
+            }
+            if from == path {
+                return match pointer::get(doc, &from) {
+                    Some(_) => Ok(()),
+                    None => Err(error::ServerError::MISSING_DATA),
+                };
+            }
+            let value = pointer::remove(doc, &from)?;
+            pointer::add(doc, &path, value)
+        },
+        "copy" => {
+            let from = pointer_at(operation, "from")?;
+            match pointer::get(doc, &from) {
+                Some(x) => {
+                    let value = x.clone();
+                    pointer::add(doc, &path, value)
+                },
+                None => Err(error::ServerError::MISSING_DATA),
+            }
+        },
+        "test" => {
+            let expected = value_of(operation)?;
+            match pointer::get(doc, &path) {
+                Some(x) if *x == expected => Ok(()),
+                Some(_) => Err(error::ServerError::INVALID_DATA),
+                None => Err(error::ServerError::MISSING_DATA),
+            }
+        },
+        _ => Err(error::ServerError::INVALID_ARG),
+    }
+}
+
+fn pointer_at(operation: &json::JSON, field: &str) -> Res<Vec<String>> {
+    match operation.get(field).and_then(|x| x.as_str()) {
+        Some(x) => pointer::parse(x),
+        None => Err(error::ServerError::INVALID_ARG),
+    }
+}
+
+fn value_of(operation: &json::JSON) -> Res<json::JSON> {
+    match operation.get("value") {
+        Some(x) => Ok(x.clone()),
+        None => Err(error::ServerError::INVALID_ARG),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(target: json::JSON, patch: json::JSON) -> json::JSON {
+        let mut target = target;
+        merge_patch(&mut target, patch);
+        target
+    }
+
+    #[test]
+    fn merge_patch_follows_the_rfc() {
+        assert_eq!(merged(json::to_json!({"a": "b"}), json::to_json!({"a": "c"})), json::to_json!({"a": "c"}));
+        assert_eq!(merged(json::to_json!({"a": "b"}), json::to_json!({"b": "c"})), json::to_json!({"a": "b", "b": "c"}));
+        assert_eq!(merged(json::to_json!({"a": "b", "b": "c"}), json::to_json!({"a": null})), json::to_json!({"b": "c"}));
+        assert_eq!(merged(json::to_json!({"a": ["b"]}), json::to_json!({"a": "c"})), json::to_json!({"a": "c"}));
+        assert_eq!(merged(json::to_json!({"a": {"b": "c"}}), json::to_json!({"a": {"b": "d", "c": null}})), json::to_json!({"a": {"b": "d"}}));
+        assert_eq!(merged(json::to_json!(["a"]), json::to_json!({"a": "b"})), json::to_json!({"a": "b"}));
+        assert_eq!(merged(json::to_json!({"e": null}), json::to_json!({"a": 1})), json::to_json!({"e": null, "a": 1}));
+        assert_eq!(merged(json::to_json!({"a": "b"}), json::to_json!(null)), json::to_json!(null));
+        assert_eq!(merged(json::to_json!({}), json::to_json!({"a": {"bb": {"ccc": null}}})), json::to_json!({"a": {"bb": {}}}));
+    }
+
+    #[test]
+    fn json_patch_operations() {
+        let doc = json::to_json!({"a": [1, 2], "b": {"c": 3}});
+        let operations = json::to_json!([
+            {"op": "test", "path": "/b/c", "value": 3},
+            {"op": "add", "path": "/a/-", "value": 3},
+            {"op": "replace", "path": "/a/0", "value": 0},
+            {"op": "remove", "path": "/a/1"},
+            {"op": "copy", "from": "/b", "path": "/d"},
+            {"op": "move", "from": "/b/c", "path": "/e"},
+            {"op": "move", "from": "/e", "path": "/e"},
+        ]);
+        assert_eq!(json_patch(&doc, &operations).unwrap(), json::to_json!({"a": [0, 3], "b": {}, "d": {"c": 3}, "e": 3}));
+    }
+
+    #[test]
+    fn json_patch_fails_as_a_whole() {
+        let doc = json::to_json!({"a": {"b": 1}});
+        let failing = |operations: json::JSON| json_patch(&doc, &operations).unwrap_err().code();
+        assert_eq!(failing(json::to_json!([{"op": "add", "path": "/c", "value": 1}, {"op": "test", "path": "/a/b", "value": 2}])), "INVALID_DATA");
+        assert_eq!(failing(json::to_json!([{"op": "test", "path": "/x", "value": 1}])), "MISSING_DATA");
+        assert_eq!(failing(json::to_json!([{"op": "replace", "path": "/x", "value": 1}])), "MISSING_DATA");
+        assert_eq!(failing(json::to_json!([{"op": "move", "from": "/a", "path": "/a/c"}])), "INVALID_ARG");
+        assert_eq!(failing(json::to_json!([{"op": "add", "path": "/c"}])), "INVALID_ARG");
+        assert_eq!(failing(json::to_json!([{"op": "frobnicate", "path": "/a"}])), "INVALID_ARG");
+        assert_eq!(failing(json::to_json!([{"path": "/a"}])), "INVALID_ARG");
+        assert_eq!(failing(json::to_json!({"op": "remove", "path": "/a"})), "INVALID_ARG");
+    }
+}
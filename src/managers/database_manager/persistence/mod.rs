@@ -0,0 +1,232 @@
+use std::{fs::{self, File, OpenOptions}, io::{BufRead, BufReader, Read, Write}, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+
+use super::super::{error, json};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// one change to the store as written to the write-ahead log
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op")]
+pub enum WalEntry {
+    #[serde(rename = "INS")]
+    Ins {
+        key: String,
+        value: json::JSON,
+        // when the key expires, in milliseconds since the unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+        // the version the change left the document at
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+    },
+    /// an update that put the value in the place of what was at the key, whatever its type
+    #[serde(rename = "SET")]
+    Set {
+        key: String,
+        value: json::JSON,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+    },
+    #[serde(rename = "DEL")]
+    Del {
+        key: String,
+        // none when the whole document is gone
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+    },
+    /// sets when a key expires, none makes it live forever
+    #[serde(rename = "EXP")]
+    Expire {
+        key: String,
+        at: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+    },
+    /// the changes of a transaction. they share a line so a crash while writing it loses all of them
+    #[serde(rename = "TXN")]
+    Txn { entries: Vec<WalEntry> },
+}
+
+impl WalEntry {
+    pub fn ins(key: String, value: json::JSON, expires: Option<u64>) -> WalEntry {
+        WalEntry::Ins { key, value, expires, version: None }
+    }
+
+    pub fn set(key: String, value: json::JSON, expires: Option<u64>) -> WalEntry {
+        WalEntry::Set { key, value, expires, version: None }
+    }
+
+    /// the entry recording that the change left its document at version
+    pub fn with_version(self, version: Option<u64>) -> WalEntry {
+        match self {
+            WalEntry::Ins { key, value, expires, .. } => WalEntry::Ins { key, value, expires, version },
+            WalEntry::Set { key, value, expires, .. } => WalEntry::Set { key, value, expires, version },
+            WalEntry::Del { key, .. } => WalEntry::Del { key, version },
+            WalEntry::Expire { key, at, .. } => WalEntry::Expire { key, at, version },
+            x => x,
+        }
+    }
+}
+
+/// the snapshot file a store is saved to and the write-ahead log kept next to it at `<path>.wal`.
+/// every change is appended to the log before it is acknowledged and the log is emptied once a
+/// snapshot containing those changes has been written
+#[derive(Debug)]
+pub struct Persistence {
+    path: PathBuf,
+    wal: File,
+}
+
+impl Persistence {
+    /// opens the snapshot at path or creates a new one if not present
+    pub fn open(path: &str) -> Res<Persistence> {
+        if !path.ends_with(".json") {
+            return Err(error::ServerError::INVALID_FILE);
+        }
+        let path = PathBuf::from(path);
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => {
+                let _ = fs::create_dir_all(dir);
+            },
+            _ => (),
+        }
+        if !path.exists() && OpenOptions::new().create_new(true).write(true).open(&path).is_err() {
+            return Err(error::ServerError::INVALID_FILE);
+        }
+        match OpenOptions::new().create(true).append(true).open(wal_path(&path)) {
+            Ok(wal) => Ok(Persistence { path, wal }),
+            Err(_) => Err(error::ServerError::INVALID_FILE),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// appends the change to the log and waits for it to reach the disk
+    pub fn log(&mut self, entry: &WalEntry) -> Res<()> {
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(x) => x,
+            Err(_) => return Err(error::ServerError::INVALID_JSON),
+        };
+        line.push(b'\n');
+        match self.wal.write_all(&line).and_then(|_| self.wal.sync_data()) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(error::ServerError::FAILED_WRITE),
+        }
+    }
+
+    /// makes every append to the log fail from now on, the way a full disk would
+    #[cfg(test)]
+    pub fn break_log(&mut self) {
+        self.wal = File::open(wal_path(&self.path)).unwrap();
+    }
+
+    /// writes the whole store as the new snapshot with its settings and empties the log. the log is
+    /// only emptied once both are written, a crash before that replays it on top of them
+    pub fn snapshot<T: Serialize>(&mut self, store: &T, meta: &json::JSON) -> Res<()> {
+        write_atomic(&self.path, store)?;
+        self.save_meta(meta)?;
+        match self.wal.set_len(0).and_then(|_| self.wal.sync_all()) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(error::ServerError::FAILED_WRITE),
+        }
+    }
+
+    /// settings of the store that are not data, like index definitions. they are kept next to the snapshot at `<path>.meta`
+    pub fn save_meta(&self, meta: &json::JSON) -> Res<()> {
+        write_atomic(&sibling(&self.path, ".meta"), meta)
+    }
+
+    /// reads what `save_meta` wrote, null if nothing was saved yet
+    pub fn load_meta(&self) -> Res<json::JSON> {
+        match File::open(sibling(&self.path, ".meta")) {
+            Ok(x) => {
+                match serde_json::from_reader(BufReader::new(x)) {
+                    Ok(x) => Ok(x),
+                    Err(_) => Err(error::ServerError::INVALID_JSON),
+                }
+            },
+            Err(_) => Ok(json::JSON::Null),
+        }
+    }
+
+    /// reads the snapshot and every change logged since it was written
+    pub fn load(&self) -> Res<(serde_json::Map<String, json::JSON>, Vec<WalEntry>)> {
+        let mut contents = String::new();
+        match File::open(&self.path).and_then(|mut x| x.read_to_string(&mut contents)) {
+            Ok(_) => (),
+            Err(_) => return Err(error::ServerError::FAILED_READ),
+        }
+        let snapshot = if contents.trim().is_empty() {
+            serde_json::Map::new()
+        } else {
+            match json::to_value_from_str(&contents) {
+                Ok(json::JSON::Object(x)) => x,
+                _ => return Err(error::ServerError::INVALID_JSON),
+            }
+        };
+
+        let mut entries = Vec::new();
+        if let Ok(wal) = File::open(wal_path(&self.path)) {
+            for line in BufReader::new(wal).lines() {
+                let line = match line {
+                    Ok(x) => x,
+                    Err(_) => break,
+                };
+                // a crash halfway through an append leaves a torn last line, nothing after it was acknowledged
+                match serde_json::from_str::<WalEntry>(&line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(_) => break,
+                }
+            }
+        }
+        Ok((snapshot, entries))
+    }
+}
+
+fn wal_path(path: &Path) -> PathBuf {
+    sibling(path, ".wal")
+}
+
+/// path with the extension added to it, `db.json` becomes `db.json.wal`
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(extension);
+    PathBuf::from(sibling)
+}
+
+/// writes the value to a temporary file next to path and renames it over path, so a crash
+/// leaves either the old or the new contents but never half of each
+pub fn write_atomic<T: Serialize>(path: &Path, value: &T) -> Res<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let written = File::create(&tmp).and_then(|mut x| {
+        serde_json::to_writer(&mut x, value)?;
+        x.sync_all()
+    });
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return Err(error::ServerError::FAILED_WRITE);
+    }
+    match fs::rename(&tmp, path) {
+        Ok(_) => {
+            // make the rename itself durable
+            match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => {
+                    let _ = File::open(dir).and_then(|x| x.sync_all());
+                },
+                _ => {
+                    let _ = File::open(".").and_then(|x| x.sync_all());
+                },
+            }
+            Ok(())
+        },
+        Err(_) => Err(error::ServerError::FAILED_WRITE),
+    }
+}
@@ -0,0 +1,223 @@
+use super::super::{error, json};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// splits an rfc 6901 json pointer like `/a/b~1c/0` into its reference tokens, `~1` standing for `/`
+/// and `~0` for `~`. the empty pointer points at the whole document
+pub fn parse(pointer: &str) -> Res<Vec<String>> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rest = match pointer.strip_prefix('/') {
+        Some(x) => x,
+        None => return Err(error::ServerError::INVALID_ARG),
+    };
+    let mut tokens = Vec::new();
+    for token in rest.split('/') {
+        let mut unescaped = String::with_capacity(token.len());
+        let mut chars = token.chars();
+        while let Some(c) = chars.next() {
+            if c == '~' {
+                match chars.next() {
+                    Some('0') => unescaped.push('~'),
+                    Some('1') => unescaped.push('/'),
+                    _ => return Err(error::ServerError::INVALID_ARG),
+                }
+            } else {
+                unescaped.push(c);
+            }
+        }
+        tokens.push(unescaped);
+    }
+    Ok(tokens)
+}
+
+/// the pointer to a top-level key, escaping `~` and `/`
+pub fn escape(key: &str) -> String {
+    format!("/{}", key.replace('~', "~0").replace('/', "~1"))
+}
+
+/// the position a token names in an array of len elements. `-` names the slot after the last one
+pub fn index(token: &str, len: usize) -> Res<usize> {
+    if token == "-" {
+        return Ok(len);
+    }
+    // leading zeros are not allowed so every index has one spelling
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) || !token.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(error::ServerError::INVALID_ARG);
+    }
+    match token.parse::<usize>() {
+        Ok(x) => Ok(x),
+        Err(_) => Err(error::ServerError::INVALID_ARG),
+    }
+}
+
+pub fn get<'a>(doc: &'a json::JSON, tokens: &[String]) -> Option<&'a json::JSON> {
+    let mut value = doc;
+    for token in tokens {
+        value = match value {
+            json::JSON::Object(x) => x.get(token)?,
+            json::JSON::Array(x) => x.get(index(token, x.len()).ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+pub fn get_mut<'a>(doc: &'a mut json::JSON, tokens: &[String]) -> Option<&'a mut json::JSON> {
+    let mut value = doc;
+    for token in tokens {
+        value = match value {
+            json::JSON::Object(x) => x.get_mut(token)?,
+            json::JSON::Array(x) => {
+                let i = index(token, x.len()).ok()?;
+                x.get_mut(i)?
+            },
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// puts value at the location, which has to be in an existing object or array. on an array it is
+/// inserted before the index, `-` appends it
+pub fn add(doc: &mut json::JSON, tokens: &[String], value: json::JSON) -> Res<()> {
+    let (last, parent) = match tokens.split_last() {
+        Some(x) => x,
+        None => {
+            *doc = value;
+            return Ok(());
+        },
+    };
+    match get_mut(doc, parent) {
+        Some(json::JSON::Object(x)) => {
+            x.insert(last.clone(), value);
+            Ok(())
+        },
+        Some(json::JSON::Array(x)) => {
+            let i = index(last, x.len())?;
+            if i > x.len() {
+                return Err(error::ServerError::MISSING_DATA);
+            }
+            x.insert(i, value);
+            Ok(())
+        },
+        Some(_) => Err(error::ServerError::INCOMPATIBLE_DATA_TYPES),
+        None => Err(error::ServerError::MISSING_DATA),
+    }
+}
+
+/// puts value at the location like add, but an array index that exists has its element replaced
+/// instead of shifted along
+pub fn set(doc: &mut json::JSON, tokens: &[String], value: json::JSON) -> Res<()> {
+    if let Some((last, parent)) = tokens.split_last() {
+        if let Some(json::JSON::Array(x)) = get_mut(doc, parent) {
+            let i = index(last, x.len())?;
+            if let Some(slot) = x.get_mut(i) {
+                *slot = value;
+                return Ok(());
+            }
+        }
+    }
+    add(doc, tokens, value)
+}
+
+/// takes the value at the location out of its object or array and returns it
+pub fn remove(doc: &mut json::JSON, tokens: &[String]) -> Res<json::JSON> {
+    let (last, parent) = match tokens.split_last() {
+        Some(x) => x,
+        None => return Err(error::ServerError::INVALID_ARG),
+    };
+    match get_mut(doc, parent) {
+        Some(json::JSON::Object(x)) => {
+            match x.remove(last) {
+                Some(x) => Ok(x),
+                None => Err(error::ServerError::MISSING_DATA),
+            }
+        },
+        Some(json::JSON::Array(x)) => {
+            let i = index(last, x.len())?;
+            if i >= x.len() {
+                return Err(error::ServerError::MISSING_DATA);
+            }
+            Ok(x.remove(i))
+        },
+        Some(_) => Err(error::ServerError::INCOMPATIBLE_DATA_TYPES),
+        None => Err(error::ServerError::MISSING_DATA),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(pointer: &str) -> Vec<String> {
+        parse(pointer).unwrap()
+    }
+
+    #[test]
+    fn parse_unescapes_tokens() {
+        assert_eq!(tokens(""), Vec::<String>::new());
+        assert_eq!(tokens("/"), vec![""]);
+        assert_eq!(tokens("/a/b~1c/~01"), vec!["a", "b/c", "~1"]);
+        assert_eq!(parse("a").unwrap_err().code(), "INVALID_ARG");
+        assert_eq!(parse("/a~").unwrap_err().code(), "INVALID_ARG");
+        assert_eq!(parse("/a~2").unwrap_err().code(), "INVALID_ARG");
+        assert_eq!(tokens(&escape("a/~b")), vec!["a/~b"]);
+    }
+
+    #[test]
+    fn set_replaces_array_elements() {
+        let mut doc = json::to_json!({"a": [1, 2], "b": {"c": 1}});
+        set(&mut doc, &tokens("/a/0"), json::to_json!(5)).unwrap();
+        set(&mut doc, &tokens("/a/-"), json::to_json!(3)).unwrap();
+        set(&mut doc, &tokens("/a/3"), json::to_json!(4)).unwrap();
+        set(&mut doc, &tokens("/b/c"), json::to_json!("x")).unwrap();
+        assert_eq!(doc, json::to_json!({"a": [5, 2, 3, 4], "b": {"c": "x"}}));
+        assert_eq!(set(&mut doc, &tokens("/a/9"), json::to_json!(1)).unwrap_err().code(), "MISSING_DATA");
+        assert_eq!(set(&mut doc, &tokens("/a/x"), json::to_json!(1)).unwrap_err().code(), "INVALID_ARG");
+    }
+
+    #[test]
+    fn array_indices() {
+        assert_eq!(index("0", 2).unwrap(), 0);
+        assert_eq!(index("10", 2).unwrap(), 10);
+        assert_eq!(index("-", 2).unwrap(), 2);
+        for token in ["", "01", "-1", "1a", "+1"] {
+            assert_eq!(index(token, 2).unwrap_err().code(), "INVALID_ARG");
+        }
+    }
+
+    #[test]
+    fn get_follows_objects_and_arrays() {
+        let doc = json::to_json!({"a": [{"b": 1}], "c/d": 2});
+        assert_eq!(get(&doc, &tokens("")), Some(&doc));
+        assert_eq!(get(&doc, &tokens("/a/0/b")), Some(&json::to_json!(1)));
+        assert_eq!(get(&doc, &tokens("/c~1d")), Some(&json::to_json!(2)));
+        assert_eq!(get(&doc, &tokens("/a/1")), None);
+        assert_eq!(get(&doc, &tokens("/a/-")), None);
+        assert_eq!(get(&doc, &tokens("/a/0/b/c")), None);
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let mut doc = json::to_json!({"a": [1, 3]});
+        add(&mut doc, &tokens("/a/1"), json::to_json!(2)).unwrap();
+        add(&mut doc, &tokens("/a/-"), json::to_json!(4)).unwrap();
+        add(&mut doc, &tokens("/b"), json::to_json!({})).unwrap();
+        assert_eq!(doc, json::to_json!({"a": [1, 2, 3, 4], "b": {}}));
+        assert_eq!(add(&mut doc, &tokens("/a/5"), json::to_json!(0)).unwrap_err().code(), "MISSING_DATA");
+        assert_eq!(add(&mut doc, &tokens("/x/y"), json::to_json!(0)).unwrap_err().code(), "MISSING_DATA");
+        assert_eq!(add(&mut doc, &tokens("/a/0/y"), json::to_json!(0)).unwrap_err().code(), "INCOMPATIBLE_DATA_TYPES");
+
+        assert_eq!(remove(&mut doc, &tokens("/a/0")).unwrap(), json::to_json!(1));
+        assert_eq!(remove(&mut doc, &tokens("/b")).unwrap(), json::to_json!({}));
+        assert_eq!(doc, json::to_json!({"a": [2, 3, 4]}));
+        assert_eq!(remove(&mut doc, &tokens("/a/-")).unwrap_err().code(), "MISSING_DATA");
+        assert_eq!(remove(&mut doc, &tokens("/b")).unwrap_err().code(), "MISSING_DATA");
+        assert_eq!(remove(&mut doc, &tokens("")).unwrap_err().code(), "INVALID_ARG");
+
+        add(&mut doc, &tokens(""), json::to_json!(1)).unwrap();
+        assert_eq!(doc, json::to_json!(1));
+    }
+}
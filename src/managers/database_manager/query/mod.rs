@@ -0,0 +1,390 @@
+use std::cmp::Ordering;
+use regex::Regex;
+
+use super::super::{error, json};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// a parsed `QUERY` document
+/// `{"filter": {...}, "projection": ["a", "b.c"], "sort": ["-age", "name"], "limit": 10}`.
+/// every part is optional, an empty document returns every key
+#[derive(Debug, Clone)]
+pub struct Query {
+    filter: Filter,
+    projection: Option<Vec<String>>,
+    // dotted path and whether it sorts descending
+    sort: Vec<(String, bool)>,
+    limit: Option<usize>,
+}
+
+/// a filter is an implicit and of its fields, `{"age": {"$gt": 30}, "name": "Ann"}`.
+/// `$and` and `$or` take a list of filters
+#[derive(Debug, Clone)]
+enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Field(String, Vec<Condition>),
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Eq(json::JSON),
+    Ne(json::JSON),
+    Gt(json::JSON),
+    Gte(json::JSON),
+    Lt(json::JSON),
+    Lte(json::JSON),
+    In(Vec<json::JSON>),
+    Nin(Vec<json::JSON>),
+    Exists(bool),
+    Regex(Regex),
+}
+
+impl Query {
+    pub fn parse(query: &json::JSON) -> Res<Query> {
+        let query = match query.as_object() {
+            Some(x) => x,
+            None => return Err(error::ServerError::INVALID_ARG),
+        };
+        let mut parsed = Query { filter: Filter::And(Vec::new()), projection: None, sort: Vec::new(), limit: None };
+        for (k, v) in query {
+            match k.as_str() {
+                "filter" => parsed.filter = parse_filter(v)?,
+                "projection" => {
+                    match v.as_array() {
+                        Some(paths) => {
+                            let mut projection = Vec::new();
+                            for path in paths {
+                                match path.as_str() {
+                                    Some(x) if !x.is_empty() => projection.push(x.to_owned()),
+                                    _ => return Err(error::ServerError::INVALID_ARG),
+                                }
+                            }
+                            parsed.projection = Some(projection);
+                        },
+                        None => return Err(error::ServerError::INVALID_ARG),
+                    }
+                },
+                "sort" => {
+                    // "-age" sorts by age descending, a list sorts by each field in turn
+                    let fields = match v {
+                        json::JSON::String(_) => vec![v.clone()],
+                        json::JSON::Array(x) => x.clone(),
+                        _ => return Err(error::ServerError::INVALID_ARG),
+                    };
+                    for field in fields {
+                        match field.as_str() {
+                            Some(x) => {
+                                match x.strip_prefix('-') {
+                                    Some(path) if !path.is_empty() => parsed.sort.push((path.to_owned(), true)),
+                                    Some(_) => return Err(error::ServerError::INVALID_ARG),
+                                    None if !x.is_empty() => parsed.sort.push((x.to_owned(), false)),
+                                    None => return Err(error::ServerError::INVALID_ARG),
+                                }
+                            },
+                            None => return Err(error::ServerError::INVALID_ARG),
+                        }
+                    }
+                },
+                "limit" => {
+                    match v.as_u64() {
+                        Some(x) => parsed.limit = Some(x as usize),
+                        None => return Err(error::ServerError::INVALID_ARG),
+                    }
+                },
+                _ => return Err(error::ServerError::INVALID_ARG),
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn matches(&self, doc: &json::JSON) -> bool {
+        self.filter.matches(doc)
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub fn is_sorted(&self) -> bool {
+        !self.sort.is_empty()
+    }
+
+    /// orders two documents by the sort fields
+    pub fn compare(&self, a: &json::JSON, b: &json::JSON) -> Ordering {
+        for (path, descending) in self.sort.iter() {
+            let order = sort_order(lookup(a, path), lookup(b, path));
+            let order = if *descending { order.reverse() } else { order };
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+        Ordering::Equal
+    }
+
+    /// the parts of the document the projection asked for, the whole document without one
+    pub fn project(&self, doc: &json::JSON) -> json::JSON {
+        match self.projection {
+            Some(ref paths) => {
+                let mut projected = json::JSON::Object(serde_json::Map::new());
+                for path in paths {
+                    if let Some(value) = lookup(doc, path) {
+                        set(&mut projected, path, value.clone());
+                    }
+                }
+                projected
+            },
+            None => doc.clone(),
+        }
+    }
+}
+
+impl Filter {
+    fn matches(&self, doc: &json::JSON) -> bool {
+        match self {
+            Filter::And(filters) => filters.iter().all(|x| x.matches(doc)),
+            Filter::Or(filters) => filters.iter().any(|x| x.matches(doc)),
+            Filter::Field(path, conditions) => {
+                let value = lookup(doc, path);
+                conditions.iter().all(|x| x.matches(value))
+            },
+        }
+    }
+}
+
+impl Condition {
+    fn matches(&self, value: Option<&json::JSON>) -> bool {
+        match self {
+            Condition::Exists(exists) => value.is_some() == *exists,
+            Condition::Eq(x) => value.is_some_and(|v| equals(v, x)),
+            Condition::Ne(x) => !value.is_some_and(|v| equals(v, x)),
+            Condition::In(xs) => value.is_some_and(|v| xs.iter().any(|x| equals(v, x))),
+            Condition::Nin(xs) => !value.is_some_and(|v| xs.iter().any(|x| equals(v, x))),
+            Condition::Gt(x) => value.and_then(|v| compare(v, x)) == Some(Ordering::Greater),
+            Condition::Gte(x) => matches!(value.and_then(|v| compare(v, x)), Some(Ordering::Greater) | Some(Ordering::Equal)),
+            Condition::Lt(x) => value.and_then(|v| compare(v, x)) == Some(Ordering::Less),
+            Condition::Lte(x) => matches!(value.and_then(|v| compare(v, x)), Some(Ordering::Less) | Some(Ordering::Equal)),
+            Condition::Regex(re) => {
+                match value {
+                    Some(json::JSON::String(s)) => re.is_match(s),
+                    _ => false,
+                }
+            },
+        }
+    }
+}
+
+fn parse_filter(filter: &json::JSON) -> Res<Filter> {
+    let filter = match filter.as_object() {
+        Some(x) => x,
+        None => return Err(error::ServerError::INVALID_ARG),
+    };
+    let mut parts = Vec::new();
+    for (k, v) in filter {
+        match k.as_str() {
+            "$and" | "$or" => {
+                let filters = match v.as_array() {
+                    Some(x) if !x.is_empty() => x,
+                    _ => return Err(error::ServerError::INVALID_ARG),
+                };
+                let mut parsed = Vec::new();
+                for filter in filters {
+                    parsed.push(parse_filter(filter)?);
+                }
+                if k == "$and" {
+                    parts.push(Filter::And(parsed));
+                } else {
+                    parts.push(Filter::Or(parsed));
+                }
+            },
+            _ if k.starts_with('$') => return Err(error::ServerError::INVALID_ARG),
+            _ => parts.push(Filter::Field(k.clone(), parse_conditions(v)?)),
+        }
+    }
+    Ok(Filter::And(parts))
+}
+
+/// an object whose keys are all operators is a list of conditions, anything else has to be equal
+fn parse_conditions(value: &json::JSON) -> Res<Vec<Condition>> {
+    let operators = match value.as_object() {
+        Some(x) if !x.is_empty() && x.keys().all(|k| k.starts_with('$')) => x,
+        _ => return Ok(vec![Condition::Eq(value.clone())]),
+    };
+    let mut conditions = Vec::new();
+    for (op, arg) in operators {
+        let condition = match op.as_str() {
+            "$eq" => Condition::Eq(arg.clone()),
+            "$ne" => Condition::Ne(arg.clone()),
+            "$gt" => Condition::Gt(arg.clone()),
+            "$gte" => Condition::Gte(arg.clone()),
+            "$lt" => Condition::Lt(arg.clone()),
+            "$lte" => Condition::Lte(arg.clone()),
+            "$in" | "$nin" => {
+                let values = match arg.as_array() {
+                    Some(x) => x.clone(),
+                    None => return Err(error::ServerError::INVALID_ARG),
+                };
+                if op == "$in" { Condition::In(values) } else { Condition::Nin(values) }
+            },
+            "$exists" => {
+                match arg.as_bool() {
+                    Some(x) => Condition::Exists(x),
+                    None => return Err(error::ServerError::INVALID_ARG),
+                }
+            },
+            "$regex" => {
+                match arg.as_str().map(Regex::new) {
+                    Some(Ok(x)) => Condition::Regex(x),
+                    _ => return Err(error::ServerError::INVALID_ARG),
+                }
+            },
+            _ => return Err(error::ServerError::INVALID_ARG),
+        };
+        conditions.push(condition);
+    }
+    Ok(conditions)
+}
+
+/// the value at a dotted path, none if any part of it is missing
+pub fn lookup<'a>(doc: &'a json::JSON, path: &str) -> Option<&'a json::JSON> {
+    let mut value = doc;
+    for key in path.split('.') {
+        value = match value {
+            json::JSON::Object(x) => x.get(key)?,
+            json::JSON::Array(x) => x.get(key.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// puts value at a dotted path, creating the objects on the way
+fn set(doc: &mut json::JSON, path: &str, value: json::JSON) {
+    let mut target = doc;
+    let mut keys = path.split('.').peekable();
+    while let Some(key) = keys.next() {
+        let object = match target {
+            json::JSON::Object(x) => x,
+            _ => return,
+        };
+        if keys.peek().is_none() {
+            object.insert(key.to_owned(), value);
+            return;
+        }
+        target = object.entry(key.to_owned()).or_insert_with(|| json::JSON::Object(serde_json::Map::new()));
+    }
+}
+
+/// numbers compare by value so 1 and 1.0 are equal. an array field equals a value it contains
+fn equals(value: &json::JSON, wanted: &json::JSON) -> bool {
+    if compare(value, wanted) == Some(Ordering::Equal) || value == wanted {
+        return true;
+    }
+    match value {
+        json::JSON::Array(x) if !wanted.is_array() => x.iter().any(|v| equals(v, wanted)),
+        _ => false,
+    }
+}
+
+/// orders numbers, strings and booleans against values of the same type, none for anything else
+fn compare(a: &json::JSON, b: &json::JSON) -> Option<Ordering> {
+    match (a, b) {
+        (json::JSON::Number(a), json::JSON::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (json::JSON::String(a), json::JSON::String(b)) => Some(a.cmp(b)),
+        (json::JSON::Bool(a), json::JSON::Bool(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// total order used by sort. missing and null come first, then booleans, numbers, strings, arrays and objects
+fn sort_order(a: Option<&json::JSON>, b: Option<&json::JSON>) -> Ordering {
+    fn rank(value: Option<&json::JSON>) -> u8 {
+        match value {
+            None | Some(json::JSON::Null) => 0,
+            Some(json::JSON::Bool(_)) => 1,
+            Some(json::JSON::Number(_)) => 2,
+            Some(json::JSON::String(_)) => 3,
+            Some(json::JSON::Array(_)) => 4,
+            Some(json::JSON::Object(_)) => 5,
+        }
+    }
+    match rank(a).cmp(&rank(b)) {
+        Ordering::Equal => {
+            match (a, b) {
+                (Some(a), Some(b)) => compare(a, b).unwrap_or(Ordering::Equal),
+                _ => Ordering::Equal,
+            }
+        },
+        order => order,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(source: json::JSON) -> Query {
+        Query::parse(&source).unwrap()
+    }
+
+    fn matches(filter: json::JSON, doc: json::JSON) -> bool {
+        query(json::to_json!({"filter": filter})).matches(&doc)
+    }
+
+    #[test]
+    fn equality() {
+        let doc = json::to_json!({"name": "Ann", "age": 31, "tags": ["a", "b"], "address": {"city": "x"}});
+        assert!(matches(json::to_json!({"name": "Ann"}), doc.clone()));
+        assert!(matches(json::to_json!({"age": 31.0}), doc.clone()));
+        assert!(matches(json::to_json!({"tags": "b"}), doc.clone()));
+        assert!(matches(json::to_json!({"address.city": "x"}), doc.clone()));
+        assert!(matches(json::to_json!({"address": {"city": "x"}}), doc.clone()));
+        assert!(!matches(json::to_json!({"name": "Bob"}), doc.clone()));
+        assert!(!matches(json::to_json!({"missing": null}), doc));
+    }
+
+    #[test]
+    fn operators() {
+        let doc = json::to_json!({"age": 31, "name": "Ann"});
+        assert!(matches(json::to_json!({"age": {"$gt": 30, "$lte": 31}}), doc.clone()));
+        assert!(!matches(json::to_json!({"age": {"$lt": 31}}), doc.clone()));
+        assert!(matches(json::to_json!({"age": {"$ne": 30}}), doc.clone()));
+        assert!(matches(json::to_json!({"age": {"$in": [1, 31]}}), doc.clone()));
+        assert!(matches(json::to_json!({"age": {"$nin": [1, 2]}}), doc.clone()));
+        assert!(matches(json::to_json!({"name": {"$regex": "^A"}}), doc.clone()));
+        assert!(matches(json::to_json!({"email": {"$exists": false}}), doc.clone()));
+        // values of different types never order against each other
+        assert!(!matches(json::to_json!({"name": {"$gt": 1}}), doc.clone()));
+        assert!(matches(json::to_json!({"$or": [{"age": 1}, {"name": "Ann"}]}), doc.clone()));
+        assert!(!matches(json::to_json!({"$and": [{"age": 31}, {"name": "Bob"}]}), doc));
+    }
+
+    #[test]
+    fn invalid_queries() {
+        for source in [
+            json::to_json!([]),
+            json::to_json!({"filter": {"$nor": []}}),
+            json::to_json!({"filter": {"$or": []}}),
+            json::to_json!({"filter": {"a": {"$in": 1}}}),
+            json::to_json!({"filter": {"a": {"$regex": "("}}}),
+            json::to_json!({"sort": ["-"]}),
+            json::to_json!({"limit": -1}),
+            json::to_json!({"other": 1}),
+        ] {
+            assert_eq!(Query::parse(&source).unwrap_err().code(), "INVALID_ARG");
+        }
+    }
+
+    #[test]
+    fn sort_and_projection() {
+        let q = query(json::to_json!({"sort": ["-age", "name"], "projection": ["name", "address.city"]}));
+        let a = json::to_json!({"name": "a", "age": 1, "address": {"city": "x", "zip": 1}});
+        let b = json::to_json!({"name": "b", "age": 2});
+        let c = json::to_json!({"name": "c", "age": 2});
+        let mut docs = vec![&a, &c, &b];
+        docs.sort_by(|x, y| q.compare(x, y));
+        assert_eq!(docs, vec![&b, &c, &a]);
+        assert_eq!(q.project(&a), json::to_json!({"name": "a", "address": {"city": "x"}}));
+        assert_eq!(q.project(&b), json::to_json!({"name": "b"}));
+    }
+}
@@ -0,0 +1,401 @@
+use std::sync::{Arc, Weak};
+use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}};
+use uuid::Uuid;
+
+use super::super::{error, json, resp::{self, Value}, service_manager::other::Manager};
+use super::{pointer, scan, DataBaseManager};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// the commands a redis client can send, listed when it sends another one
+const COMMANDS: [&str; 14] = ["AUTH", "HELLO", "PING", "SELECT", "CLIENT", "COMMAND", "QUIT", "GET", "SET", "DEL", "EXISTS", "KEYS", "EXPIRE", "TTL"];
+
+/// what a redis connection agreed on with `HELLO`
+struct Connection {
+    id: String,
+    version: u8,
+}
+
+/// serves RESP2 and RESP3 on addr, a port on localhost or host:port, while the manager lives. every
+/// command runs as the database command it stands for, keys are top-level keys and values are json
+/// when they parse as json and strings otherwise
+pub fn start(manager: Weak<DataBaseManager>, addr: String) {
+    let addr = if addr.contains(':') { addr } else { format!("127.0.0.1:{}", addr) };
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(&addr).await {
+            Ok(x) => x,
+            Err(_) => {
+                println!("Error starting the redis server, something is already running on {}", &addr);
+                return;
+            },
+        };
+        loop {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(_) => continue,
+            };
+            match manager.upgrade() {
+                Some(manager) => {
+                    tokio::spawn(serve(manager, socket));
+                },
+                None => return,
+            }
+        }
+    });
+}
+
+async fn serve(manager: Arc<DataBaseManager>, socket: TcpStream) {
+    let mut connection = Connection { id: Uuid::to_string(&Uuid::new_v4()), version: 2 };
+    answer(&manager, &mut connection, socket).await;
+    manager.disconnect(&connection.id).await;
+}
+
+/// runs the commands the client sends until it hangs up
+async fn answer(manager: &DataBaseManager, connection: &mut Connection, mut socket: TcpStream) {
+    let mut decoder = resp::Decoder::default();
+    loop {
+        let args = match resp::read_command(&mut socket, &mut decoder).await {
+            Ok(Some(x)) => x,
+            Ok(None) => return,
+            Err(_) => {
+                let mut out = Vec::new();
+                Value::err("Protocol error").encode(connection.version, &mut out);
+                let _ = socket.write_all(&out).await;
+                return;
+            },
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case("QUIT");
+        let reply = run(manager, connection, &args).await;
+        let mut out = Vec::new();
+        reply.encode(connection.version, &mut out);
+        if socket.write_all(&out).await.is_err() || quit {
+            return;
+        }
+    }
+}
+
+async fn run(manager: &DataBaseManager, connection: &mut Connection, args: &[String]) -> Value {
+    let name = args[0].to_uppercase();
+    let args: Vec<&str> = args[1..].iter().map(|x| x.as_str()).collect();
+    let arity = match name.as_str() {
+        "PING" => args.len() <= 1,
+        "AUTH" => args.len() == 1 || args.len() == 2,
+        "SELECT" | "GET" | "KEYS" | "TTL" => args.len() == 1,
+        "SET" => args.len() >= 2,
+        "DEL" | "EXISTS" => !args.is_empty(),
+        "EXPIRE" => args.len() == 2,
+        _ => true,
+    };
+    if !arity {
+        return Value::err(&format!("wrong number of arguments for '{}' command", name.to_lowercase()));
+    }
+    let id = &connection.id;
+    match name.as_str() {
+        "PING" => {
+            match args.first() {
+                Some(x) => Value::Bulk(x.to_string()),
+                None => Value::Simple("PONG".to_owned()),
+            }
+        },
+        "AUTH" => {
+            match auth(manager, id, &args).await {
+                Ok(_) => Value::Simple("OK".to_owned()),
+                Err(e) => e,
+            }
+        },
+        "HELLO" => {
+            // HELLO [version [AUTH user password] [SETNAME name]]
+            let version = match args.first().map(|x| x.parse::<u8>()) {
+                Some(Ok(x)) if x == 2 || x == 3 => x,
+                Some(_) => return Value::Error("NOPROTO unsupported protocol version".to_owned()),
+                None => connection.version,
+            };
+            if let Some(i) = args.iter().position(|x| x.eq_ignore_ascii_case("AUTH")) {
+                match args.get(i + 1..i + 3) {
+                    Some(credentials) => {
+                        if let Err(e) = auth(manager, id, credentials).await {
+                            return e;
+                        }
+                    },
+                    None => return Value::err("syntax error"),
+                }
+            }
+            connection.version = version;
+            Value::Map(vec![
+                (Value::Bulk("server".to_owned()), Value::Bulk(env!("CARGO_PKG_NAME").to_owned())),
+                (Value::Bulk("version".to_owned()), Value::Bulk(env!("CARGO_PKG_VERSION").to_owned())),
+                (Value::Bulk("proto".to_owned()), Value::Integer(version as i64)),
+                (Value::Bulk("mode".to_owned()), Value::Bulk("standalone".to_owned())),
+                (Value::Bulk("role".to_owned()), Value::Bulk("master".to_owned())),
+                (Value::Bulk("modules".to_owned()), Value::Array(Vec::new())),
+            ])
+        },
+        // clients send these when they connect, there is only one database and nothing to set
+        "SELECT" => {
+            match args[0] {
+                "0" => Value::Simple("OK".to_owned()),
+                _ => Value::err("DB index is out of range"),
+            }
+        },
+        "CLIENT" | "QUIT" => Value::Simple("OK".to_owned()),
+        "COMMAND" => Value::Array(Vec::new()),
+        "GET" => {
+            match manager.process_message(&line(&["FND", &key(args[0])]), id).await {
+                Ok(json::JSON::Null) => Value::Null,
+                Ok(json::JSON::String(x)) => Value::Bulk(x),
+                Ok(x) => Value::Bulk(x.to_string()),
+                Err(error::ServerError::MISSING_DATA) => Value::Null,
+                Err(e) => reply_err(e),
+            }
+        },
+        "SET" => {
+            // SET key value [EX seconds | PX milliseconds]
+            let seconds = match args[2..] {
+                [] => None,
+                [option, x] if option.eq_ignore_ascii_case("EX") || option.eq_ignore_ascii_case("PX") => {
+                    // the database counts in seconds, milliseconds are rounded up
+                    let scale = if option.eq_ignore_ascii_case("PX") { 1000 } else { 1 };
+                    match x.parse::<u64>() {
+                        Ok(x) if x > 0 => Some(x.div_ceil(scale).to_string()),
+                        _ => return Value::err("invalid expire time in 'set' command"),
+                    }
+                },
+                _ => return Value::err("syntax error"),
+            };
+            let value = match serde_json::from_str::<json::JSON>(args[1]) {
+                Ok(x) => x.to_string(),
+                Err(_) => json::to_json!(args[1]).to_string(),
+            };
+            // a redis SET replaces whatever the key held
+            let key = key(args[0]);
+            let mut command = vec!["INS", "-f", &key, &value];
+            if let Some(ref x) = seconds {
+                command.extend(["EX", x]);
+            }
+            match manager.process_message(&line(&command), id).await {
+                Ok(_) => Value::Simple("OK".to_owned()),
+                Err(e) => reply_err(e),
+            }
+        },
+        "DEL" => {
+            let mut deleted = 0;
+            for k in args {
+                match manager.process_message(&line(&["DEL", &key(k)]), id).await {
+                    Ok(_) => deleted += 1,
+                    Err(error::ServerError::MISSING_DATA) => (),
+                    Err(e) => return reply_err(e),
+                }
+            }
+            Value::Integer(deleted)
+        },
+        "EXISTS" => {
+            let mut found = 0;
+            for k in args {
+                match ttl(manager, id, k).await {
+                    Ok(-2) => (),
+                    Ok(_) => found += 1,
+                    Err(e) => return e,
+                }
+            }
+            Value::Integer(found)
+        },
+        "TTL" => {
+            match ttl(manager, id, args[0]).await {
+                Ok(x) => Value::Integer(x),
+                Err(e) => e,
+            }
+        },
+        "EXPIRE" => {
+            match manager.process_message(&line(&["EXPIRE", &key(args[0]), args[1]]), id).await {
+                Ok(json::JSON::Bool(x)) => Value::Integer(x as i64),
+                Ok(_) => Value::Integer(0),
+                Err(error::ServerError::SYNTAX_ERROR(_)) => Value::err("value is not an integer or out of range"),
+                Err(e) => reply_err(e),
+            }
+        },
+        "KEYS" => {
+            match keys(manager, id, args[0]).await {
+                Ok(keys) => Value::Array(keys.into_iter().map(Value::Bulk).collect()),
+                Err(e) => reply_err(e),
+            }
+        },
+        _ => {
+            Value::err(&format!("unknown command '{}', valid commands are {}", name.to_lowercase(), COMMANDS.join(", ")))
+        },
+    }
+}
+
+/// AUTH [user] password, a password alone logs in as RESP_USER or `default` like redis does
+async fn auth(manager: &DataBaseManager, id: &str, args: &[&str]) -> Result<(), Value> {
+    let (user, password) = match args {
+        [password] => (dotenv::var("RESP_USER").unwrap_or_else(|_| "default".to_owned()), password.to_string()),
+        [user, password] => (user.to_string(), password.to_string()),
+        _ => return Err(Value::err("syntax error")),
+    };
+    match manager.process_message(&line(&["NEW", &user, &password]), id).await {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Value::Error("WRONGPASS invalid username-password pair or user is disabled.".to_owned())),
+    }
+}
+
+async fn ttl(manager: &DataBaseManager, id: &str, k: &str) -> Result<i64, Value> {
+    match manager.process_message(&line(&["TTL", &key(k)]), id).await {
+        Ok(x) => Ok(x.as_i64().unwrap_or(-2)),
+        Err(e) => Err(reply_err(e)),
+    }
+}
+
+/// every key matching the glob pattern, read a page at a time
+async fn keys(manager: &DataBaseManager, id: &str, pattern: &str) -> Res<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut cursor = "0".to_owned();
+    let count = scan::MAX_COUNT.to_string();
+    loop {
+        let page = manager.process_message(&line(&["SCAN", &cursor, "MATCH", pattern, "COUNT", &count]), id).await?;
+        if let Some(x) = page["keys"].as_array() {
+            keys.extend(x.iter().filter_map(|x| x.as_str()).map(|x| x.to_owned()));
+        }
+        cursor = page["cursor"].as_str().unwrap_or("0").to_owned();
+        if cursor == "0" {
+            return Ok(keys);
+        }
+    }
+}
+
+/// redis keys are flat, so a key is always the top-level key even when it holds dots or slashes
+fn key(k: &str) -> String {
+    pointer::escape(k)
+}
+
+/// a database command as a json array, so keys and values never need quoting
+fn line(command: &[&str]) -> String {
+    json::to_json!(command).to_string()
+}
+
+fn reply_err(e: error::ServerError) -> Value {
+    match e {
+        error::ServerError::ACCESS_DENIED => Value::Error("NOAUTH Authentication required.".to_owned()),
+        e => Value::err(e.produce_error().trim_start_matches("Error: ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::manager;
+
+    /// runs a command written the way redis-cli takes it, without quoting
+    async fn send(manager: &DataBaseManager, connection: &mut Connection, command: &str) -> Value {
+        let args: Vec<String> = command.split(' ').map(|x| x.to_owned()).collect();
+        run(manager, connection, &args).await
+    }
+
+    /// the connection the test manager logged in as admin
+    fn admin() -> Connection {
+        Connection { id: "c".to_owned(), version: 2 }
+    }
+
+    fn ok() -> Value {
+        Value::Simple("OK".to_owned())
+    }
+
+    fn bulk(x: &str) -> Value {
+        Value::Bulk(x.to_owned())
+    }
+
+    #[tokio::test]
+    async fn set_replaces_and_rounds_expiry_up() {
+        let manager = manager().await;
+        let c = &mut admin();
+        assert_eq!(send(&manager, c, "SET k 1").await, ok());
+        assert_eq!(send(&manager, c, "SET k {\"a\":1}").await, ok());
+        assert_eq!(send(&manager, c, "GET k").await, bulk("{\"a\":1}"));
+        assert_eq!(send(&manager, c, "SET k text EX 10").await, ok());
+        assert_eq!(send(&manager, c, "GET k").await, bulk("text"));
+        assert_eq!(send(&manager, c, "TTL k").await, Value::Integer(10));
+        send(&manager, c, "SET k 1 px 1500").await;
+        assert_eq!(send(&manager, c, "TTL k").await, Value::Integer(2));
+        send(&manager, c, "SET k 1 PX 1").await;
+        assert_eq!(send(&manager, c, "TTL k").await, Value::Integer(1));
+        // a SET without a time to live takes the old one away
+        send(&manager, c, "SET k 2").await;
+        assert_eq!(send(&manager, c, "TTL k").await, Value::Integer(-1));
+        assert_eq!(send(&manager, c, "GET k").await, bulk("2"));
+
+        for command in ["SET k 1 EX 0", "SET k 1 EX x", "SET k 1 PX -1"] {
+            assert_eq!(send(&manager, c, command).await, Value::err("invalid expire time in 'set' command"));
+        }
+        assert_eq!(send(&manager, c, "SET k 1 NX").await, Value::err("syntax error"));
+        assert_eq!(send(&manager, c, "SET k 1 EX 1 PX 1").await, Value::err("syntax error"));
+        assert_eq!(send(&manager, c, "SET k").await, Value::err("wrong number of arguments for 'set' command"));
+        assert_eq!(send(&manager, c, "GET k").await, bulk("2"));
+    }
+
+    #[tokio::test]
+    async fn del_exists_and_keys() {
+        let manager = manager().await;
+        let c = &mut admin();
+        for k in ["a", "b", "c.d"] {
+            send(&manager, c, &format!("SET {} 1", k)).await;
+        }
+        assert_eq!(send(&manager, c, "DEL a missing b b").await, Value::Integer(2));
+        assert_eq!(send(&manager, c, "EXISTS a c.d c.d missing").await, Value::Integer(2));
+        assert_eq!(send(&manager, c, "EXPIRE c.d 100").await, Value::Integer(1));
+        assert_eq!(send(&manager, c, "EXPIRE missing 100").await, Value::Integer(0));
+        assert_eq!(send(&manager, c, "EXPIRE c.d x").await, Value::err("value is not an integer or out of range"));
+        assert_eq!(send(&manager, c, "TTL missing").await, Value::Integer(-2));
+        assert_eq!(send(&manager, c, "KEYS *").await, Value::Array(vec![bulk("c.d")]));
+
+        // more keys than one page of SCAN holds
+        for i in 0..scan::MAX_COUNT + 5 {
+            send(&manager, c, &format!("SET p{} 1", i)).await;
+        }
+        match send(&manager, c, "KEYS p*").await {
+            Value::Array(keys) => assert_eq!(keys.len(), scan::MAX_COUNT + 5),
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(send(&manager, c, "KEYS p1?").await, Value::Array((10..20).map(|i| bulk(&format!("p{}", i))).collect()));
+    }
+
+    #[tokio::test]
+    async fn keys_are_flat() {
+        let manager = manager().await;
+        let c = &mut admin();
+        send(&manager, c, "SET a/b~c 1").await;
+        send(&manager, c, "SET a 2").await;
+        send(&manager, c, "SET a.b 3").await;
+        assert_eq!(send(&manager, c, "GET a/b~c").await, bulk("1"));
+        assert_eq!(send(&manager, c, "GET a").await, bulk("2"));
+        assert_eq!(send(&manager, c, "GET a.b").await, bulk("3"));
+        assert_eq!(send(&manager, c, "GET a/b").await, Value::Null);
+        assert_eq!(manager.process_message("FND /a~1b~0c", "c").await.unwrap(), json::to_json!(1));
+        assert_eq!(send(&manager, c, "KEYS a*").await, Value::Array(vec![bulk("a"), bulk("a.b"), bulk("a/b~c")]));
+        assert_eq!(send(&manager, c, "DEL a/b~c").await, Value::Integer(1));
+        assert_eq!(send(&manager, c, "EXISTS a/b~c a").await, Value::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn noauth_and_wrongpass() {
+        let manager = manager().await;
+        manager.permissions.write().await.insert("super".to_owned(), json::to_json!({"ann": "pw"}));
+        let c = &mut Connection { id: "r".to_owned(), version: 2 };
+        let noauth = Value::Error("NOAUTH Authentication required.".to_owned());
+        let wrongpass = Value::Error("WRONGPASS invalid username-password pair or user is disabled.".to_owned());
+        assert_eq!(send(&manager, c, "GET k").await, noauth);
+        assert_eq!(send(&manager, c, "SET k 1").await, noauth);
+        assert_eq!(send(&manager, c, "PING").await, Value::Simple("PONG".to_owned()));
+        assert_eq!(send(&manager, c, "AUTH ann nope").await, wrongpass);
+        assert_eq!(send(&manager, c, "AUTH nobody pw").await, wrongpass);
+        assert_eq!(send(&manager, c, "HELLO 3 AUTH ann nope").await, wrongpass);
+        assert_eq!(c.version, 2);
+        assert_eq!(send(&manager, c, "GET k").await, noauth);
+
+        assert_eq!(send(&manager, c, "AUTH ann pw").await, ok());
+        assert_eq!(send(&manager, c, "GET k").await, Value::Null);
+        assert!(matches!(send(&manager, c, "HELLO 3 AUTH ann pw").await, Value::Map(_)));
+        assert_eq!(c.version, 3);
+    }
+}
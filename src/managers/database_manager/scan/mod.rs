@@ -0,0 +1,347 @@
+use std::ops::Bound;
+
+use super::super::error;
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// how many keys a page holds when no COUNT is given
+pub const DEFAULT_COUNT: usize = 10;
+/// the most keys a single page may hold
+pub const MAX_COUNT: usize = 1000;
+
+/// what a `SCAN <cursor> [PREFIX p] [MATCH pattern] [COUNT n] [VALUES]` asked for
+#[derive(Debug, Clone)]
+pub struct ScanArgs {
+    /// only keys after this one are returned, none on the first page
+    pub after: Option<String>,
+    pub prefix: String,
+    pub pattern: Option<String>,
+    pub count: usize,
+    pub values: bool,
+}
+
+impl ScanArgs {
+    pub fn new(cursor: &str, prefix: Option<&str>, pattern: Option<&str>, count: Option<usize>, values: bool) -> Res<ScanArgs> {
+        let count = match count {
+            Some(x) if x > 0 && x <= MAX_COUNT => x,
+            Some(_) => return Err(error::ServerError::INVALID_ARG),
+            None => DEFAULT_COUNT,
+        };
+        Ok(ScanArgs {
+            after: decode_cursor(cursor)?,
+            prefix: prefix.unwrap_or("").to_owned(),
+            pattern: pattern.map(|x| x.to_owned()),
+            count,
+            values,
+        })
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        key.starts_with(&self.prefix) && match self.pattern {
+            Some(ref pattern) => glob_match(pattern.as_bytes(), key.as_bytes()),
+            None => true,
+        }
+    }
+
+    /// where the next page starts, right after the cursor or at the prefix
+    pub fn start(&self) -> Bound<&str> {
+        match self.after {
+            Some(ref after) if after.as_str() >= self.prefix.as_str() => Bound::Excluded(after.as_str()),
+            _ => Bound::Included(self.prefix.as_str()),
+        }
+    }
+
+    /// takes the next page out of keys, which must be sorted and begin at `start`. returns the page and the cursor for the one after it
+    pub fn page<'a, I: Iterator<Item = &'a String>>(&self, keys: I) -> (Vec<&'a String>, String) {
+        let mut page = Vec::with_capacity(self.count);
+        let mut more = false;
+        for key in keys {
+            // keys sharing the prefix sit next to each other, the first one without it ends the scan
+            if !key.starts_with(&self.prefix) {
+                break;
+            }
+            if !self.matches(key) {
+                continue;
+            }
+            if page.len() == self.count {
+                more = true;
+                break;
+            }
+            page.push(key);
+        }
+        let cursor = match page.last() {
+            Some(last) if more => encode_cursor(last),
+            _ => "0".to_owned(),
+        };
+        (page, cursor)
+    }
+}
+
+/// what a `RANGE <start> <end> [LIMIT n] [REV] [KEYS]` asked for. both ends are inclusive,
+/// `-` and `+` leave the start and end open so `RANGE - + LIMIT 50 REV` returns the last 50 keys
+#[derive(Debug, Clone)]
+pub struct RangeArgs {
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub limit: Option<usize>,
+    pub reverse: bool,
+    pub keys_only: bool,
+}
+
+impl RangeArgs {
+    pub fn new(start: &str, end: &str, limit: Option<usize>, reverse: bool, keys_only: bool) -> Res<RangeArgs> {
+        let start = match start {
+            "-" => None,
+            x if !x.is_empty() => Some(x.to_owned()),
+            _ => return Err(error::ServerError::INVALID_ARG),
+        };
+        let end = match end {
+            "+" => None,
+            x if !x.is_empty() => Some(x.to_owned()),
+            _ => return Err(error::ServerError::INVALID_ARG),
+        };
+        Ok(RangeArgs { start, end, limit, reverse, keys_only })
+    }
+
+    pub fn bounds(&self) -> (Bound<&str>, Bound<&str>) {
+        let start = match self.start {
+            Some(ref x) => Bound::Included(x.as_str()),
+            None => Bound::Unbounded,
+        };
+        let end = match self.end {
+            Some(ref x) => Bound::Included(x.as_str()),
+            None => Bound::Unbounded,
+        };
+        (start, end)
+    }
+}
+
+/// cursors are the last key of the previous page in hex, so pages stay consistent while keys are
+/// added or removed and keys with spaces still fit in one argument. `0` starts and ends a scan
+fn encode_cursor(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Res<Option<String>> {
+    if cursor == "0" {
+        return Ok(None);
+    }
+    // from_str_radix would take a sign as well, so every character is checked first
+    if cursor.is_empty() || !cursor.len().is_multiple_of(2) || !cursor.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(error::ServerError::INVALID_ARG);
+    }
+    let mut bytes = Vec::with_capacity(cursor.len() / 2);
+    for i in (0..cursor.len()).step_by(2) {
+        match cursor.get(i..i + 2).map(|x| u8::from_str_radix(x, 16)) {
+            Some(Ok(b)) => bytes.push(b),
+            _ => return Err(error::ServerError::INVALID_ARG),
+        }
+    }
+    match String::from_utf8(bytes) {
+        Ok(x) => Ok(Some(x)),
+        Err(_) => Err(error::ServerError::INVALID_ARG),
+    }
+}
+
+/// redis style glob. `*` matches any run of characters, `?` a single one, `[abc]`/`[a-z]`/`[^a]`
+/// a set and `\` escapes the next character
+pub fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // where to resume when the last `*` has to swallow one more character
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p, k));
+                    p += 1;
+                    continue;
+                },
+                b'?' => {
+                    p += 1;
+                    k += 1;
+                    continue;
+                },
+                b'[' => {
+                    match match_set(&pattern[p..], key[k]) {
+                        Some((true, len)) => {
+                            p += len;
+                            k += 1;
+                            continue;
+                        },
+                        Some((false, _)) => (),
+                        // an unclosed `[` is matched literally
+                        None => {
+                            if key[k] == b'[' {
+                                p += 1;
+                                k += 1;
+                                continue;
+                            }
+                        },
+                    }
+                },
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == key[k] {
+                        p += 2;
+                        k += 1;
+                        continue;
+                    }
+                },
+                c => {
+                    if c == key[k] {
+                        p += 1;
+                        k += 1;
+                        continue;
+                    }
+                },
+            }
+        }
+        match star {
+            Some((sp, sk)) => {
+                star = Some((sp, sk + 1));
+                p = sp + 1;
+                k = sk + 1;
+            },
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// matches c against the set at the start of pattern. returns whether it matched and the length of the set
+fn match_set(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut lo = *pattern.get(i)?;
+        if lo == b']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+        if lo == b'\\' {
+            i += 1;
+            lo = *pattern.get(i)?;
+        }
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|x| *x != b']') {
+            let hi = pattern[i + 2];
+            if lo <= c && c <= hi {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if lo == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use super::*;
+
+    fn glob(pattern: &str, key: &str) -> bool {
+        glob_match(pattern.as_bytes(), key.as_bytes())
+    }
+
+    #[test]
+    fn stars_and_question_marks() {
+        assert!(glob("*", ""));
+        assert!(glob("user:*", "user:1"));
+        assert!(glob("*:*:x", "a:b:c:x"));
+        assert!(!glob("user:*", "users"));
+        assert!(glob("h?llo", "hello"));
+        assert!(!glob("h?llo", "hllo"));
+        assert!(glob("a**", "a"));
+    }
+
+    #[test]
+    fn sets() {
+        assert!(glob("k[a-c]", "kb"));
+        assert!(!glob("k[a-c]", "kd"));
+        assert!(glob("k[xyz]", "ky"));
+        assert!(glob("k[^x]", "ky"));
+        assert!(!glob("k[^x]", "kx"));
+        assert!(glob("k[]]", "k]"));
+        assert!(glob(r"k[\]]", "k]"));
+        assert!(glob("k[a-]", "k-"));
+        // an unterminated set is a literal `[`
+        assert!(glob("k[ab", "k[ab"));
+        assert!(!glob("k[ab", "ka"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(glob(r"a\*", "a*"));
+        assert!(!glob(r"a\*", "ab"));
+        assert!(glob(r"\?\[x]", "?[x]"));
+        assert!(!glob(r"\?", "a"));
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        for key in ["a", "with space", "é/~1", "0"] {
+            assert_eq!(decode_cursor(&encode_cursor(key)).unwrap(), Some(key.to_owned()));
+        }
+        assert_eq!(decode_cursor("0").unwrap(), None);
+        for cursor in ["", "abc", "zz", "+f", "-1", "ff", "éé"] {
+            assert!(decode_cursor(cursor).is_err(), "{}", cursor);
+        }
+    }
+
+    #[test]
+    fn count_bounds() {
+        assert_eq!(ScanArgs::new("0", None, None, None, false).unwrap().count, DEFAULT_COUNT);
+        assert_eq!(ScanArgs::new("0", None, None, Some(MAX_COUNT), false).unwrap().count, MAX_COUNT);
+        assert_eq!(ScanArgs::new("0", None, None, Some(0), false).unwrap_err().code(), "INVALID_ARG");
+        assert_eq!(ScanArgs::new("0", None, None, Some(MAX_COUNT + 1), false).unwrap_err().code(), "INVALID_ARG");
+        assert_eq!(ScanArgs::new("zz", None, None, None, false).unwrap_err().code(), "INVALID_ARG");
+    }
+
+    /// every key a scan returns, a page at a time, with keys deleted after the first page
+    fn scan(keys: &mut BTreeSet<String>, prefix: Option<&str>, pattern: Option<&str>, count: usize, deleted: &[&str]) -> Vec<Vec<String>> {
+        let mut pages = Vec::new();
+        let mut cursor = "0".to_owned();
+        loop {
+            let args = ScanArgs::new(&cursor, prefix, pattern, Some(count), false).unwrap();
+            let (page, next) = args.page(keys.range::<str, _>((args.start(), Bound::Unbounded)));
+            pages.push(page.into_iter().cloned().collect());
+            if pages.len() == 1 {
+                for key in deleted {
+                    keys.remove(*key);
+                }
+            }
+            if next == "0" {
+                return pages;
+            }
+            cursor = next;
+        }
+    }
+
+    #[test]
+    fn paging() {
+        let mut keys: BTreeSet<String> = ["a", "b", "c", "d", "e", "user:1", "user:2", "w"].iter().map(|x| x.to_string()).collect();
+        assert_eq!(scan(&mut keys.clone(), None, None, 3, &[]), vec![vec!["a", "b", "c"], vec!["d", "e", "user:1"], vec!["user:2", "w"]]);
+        assert_eq!(scan(&mut keys.clone(), Some("user:"), None, 1, &[]), vec![vec!["user:1"], vec!["user:2"]]);
+        assert_eq!(scan(&mut keys.clone(), None, Some("[b-d]"), 2, &[]), vec![vec!["b", "c"], vec!["d"]]);
+        // the key the cursor names is gone, the next page starts after it all the same
+        assert_eq!(scan(&mut keys, None, None, 2, &["b", "c"]), vec![vec!["a", "b"], vec!["d", "e"], vec!["user:1", "user:2"], vec!["w"]]);
+    }
+
+    #[test]
+    fn ranges() {
+        let args = RangeArgs::new("-", "+", Some(5), true, false).unwrap();
+        assert_eq!(args.bounds(), (Bound::Unbounded, Bound::Unbounded));
+        let args = RangeArgs::new("a", "c", None, false, true).unwrap();
+        assert_eq!(args.bounds(), (Bound::Included("a"), Bound::Included("c")));
+        assert_eq!(RangeArgs::new("", "c", None, false, false).unwrap_err().code(), "INVALID_ARG");
+        assert_eq!(RangeArgs::new("a", "", None, false, false).unwrap_err().code(), "INVALID_ARG");
+    }
+}
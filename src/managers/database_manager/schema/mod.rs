@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use regex::Regex;
+
+use super::super::{error, json};
+use super::pointer;
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// a json schema, the draft 2020-12 keywords `type`, `required`, `properties`, `enum`, `minimum`,
+/// `maximum`, `exclusiveMinimum`, `exclusiveMaximum`, `minLength`, `maxLength`, `minItems`,
+/// `maxItems`, `pattern` and `items`. other keywords are ignored
+#[derive(Debug)]
+pub struct Schema {
+    // the document it was parsed from, kept to save and show it
+    source: json::JSON,
+    types: Vec<String>,
+    required: Vec<String>,
+    properties: Vec<(String, Schema)>,
+    choices: Option<Vec<json::JSON>>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+    pattern: Option<Regex>,
+    items: Option<Box<Schema>>,
+}
+
+const TYPES: [&str; 7] = ["null", "boolean", "object", "array", "number", "string", "integer"];
+
+impl Schema {
+    pub fn parse(source: &json::JSON) -> Res<Schema> {
+        let keywords = match source.as_object() {
+            Some(x) => x,
+            None => return Err(error::ServerError::INVALID_ARG),
+        };
+        let mut schema = Schema {
+            source: source.clone(),
+            types: Vec::new(),
+            required: Vec::new(),
+            properties: Vec::new(),
+            choices: None,
+            minimum: None,
+            maximum: None,
+            exclusive_minimum: None,
+            exclusive_maximum: None,
+            min_length: None,
+            max_length: None,
+            min_items: None,
+            max_items: None,
+            pattern: None,
+            items: None,
+        };
+        for (k, v) in keywords {
+            match k.as_str() {
+                "type" => {
+                    let types = match v {
+                        json::JSON::String(_) => vec![v.clone()],
+                        json::JSON::Array(x) if !x.is_empty() => x.clone(),
+                        _ => return Err(error::ServerError::INVALID_ARG),
+                    };
+                    for t in types {
+                        match t.as_str() {
+                            Some(x) if TYPES.contains(&x) => schema.types.push(x.to_owned()),
+                            _ => return Err(error::ServerError::INVALID_ARG),
+                        }
+                    }
+                },
+                "required" => {
+                    match v.as_array() {
+                        Some(names) => {
+                            for name in names {
+                                match name.as_str() {
+                                    Some(x) => schema.required.push(x.to_owned()),
+                                    None => return Err(error::ServerError::INVALID_ARG),
+                                }
+                            }
+                        },
+                        None => return Err(error::ServerError::INVALID_ARG),
+                    }
+                },
+                "properties" => {
+                    match v.as_object() {
+                        Some(properties) => {
+                            for (name, property) in properties {
+                                schema.properties.push((name.clone(), Schema::parse(property)?));
+                            }
+                        },
+                        None => return Err(error::ServerError::INVALID_ARG),
+                    }
+                },
+                "enum" => {
+                    match v.as_array() {
+                        Some(x) => schema.choices = Some(x.clone()),
+                        None => return Err(error::ServerError::INVALID_ARG),
+                    }
+                },
+                "minimum" => schema.minimum = Some(number(v)?),
+                "maximum" => schema.maximum = Some(number(v)?),
+                "exclusiveMinimum" => schema.exclusive_minimum = Some(number(v)?),
+                "exclusiveMaximum" => schema.exclusive_maximum = Some(number(v)?),
+                "minLength" => schema.min_length = Some(count(v)?),
+                "maxLength" => schema.max_length = Some(count(v)?),
+                "minItems" => schema.min_items = Some(count(v)?),
+                "maxItems" => schema.max_items = Some(count(v)?),
+                "pattern" => {
+                    match v.as_str().map(Regex::new) {
+                        Some(Ok(x)) => schema.pattern = Some(x),
+                        _ => return Err(error::ServerError::INVALID_ARG),
+                    }
+                },
+                "items" => schema.items = Some(Box::new(Schema::parse(v)?)),
+                _ => (),
+            }
+        }
+        Ok(schema)
+    }
+
+    pub fn source(&self) -> &json::JSON {
+        &self.source
+    }
+
+    /// checks value against the schema. path is the json pointer of value and is reported with the
+    /// first keyword it breaks
+    pub fn validate(&self, value: &json::JSON, path: &str) -> Res<()> {
+        if !self.types.is_empty() && !self.types.iter().any(|t| is_type(value, t)) {
+            return violation(path, &format!("expected {}", self.types.join(" or ")));
+        }
+        if let Some(ref choices) = self.choices {
+            if !choices.contains(value) {
+                return violation(path, "not one of the enum values");
+            }
+        }
+        match value {
+            json::JSON::Number(x) => {
+                let x = x.as_f64().unwrap_or(0.0);
+                if self.minimum.is_some_and(|m| x < m) || self.exclusive_minimum.is_some_and(|m| x <= m) {
+                    return violation(path, "below the minimum");
+                }
+                if self.maximum.is_some_and(|m| x > m) || self.exclusive_maximum.is_some_and(|m| x >= m) {
+                    return violation(path, "above the maximum");
+                }
+            },
+            json::JSON::String(x) => {
+                let len = x.chars().count();
+                if self.min_length.is_some_and(|m| len < m) {
+                    return violation(path, "shorter than minLength");
+                }
+                if self.max_length.is_some_and(|m| len > m) {
+                    return violation(path, "longer than maxLength");
+                }
+                if let Some(ref re) = self.pattern {
+                    if !re.is_match(x) {
+                        return violation(path, "does not match the pattern");
+                    }
+                }
+            },
+            json::JSON::Array(x) => {
+                if self.min_items.is_some_and(|m| x.len() < m) {
+                    return violation(path, "fewer items than minItems");
+                }
+                if self.max_items.is_some_and(|m| x.len() > m) {
+                    return violation(path, "more items than maxItems");
+                }
+                if let Some(ref items) = self.items {
+                    for (i, item) in x.iter().enumerate() {
+                        items.validate(item, &format!("{}/{}", path, i))?;
+                    }
+                }
+            },
+            json::JSON::Object(x) => {
+                for name in self.required.iter() {
+                    if !x.contains_key(name) {
+                        return violation(&child(path, name), "required");
+                    }
+                }
+                for (name, property) in self.properties.iter() {
+                    if let Some(v) = x.get(name) {
+                        property.validate(v, &child(path, name))?;
+                    }
+                }
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// the schemas of a collection, one every document has to match and one per top-level key
+#[derive(Debug, Default)]
+pub struct Schemas {
+    all: Option<Schema>,
+    keys: HashMap<String, Schema>,
+}
+
+/// the name `SCHEMA` uses for the schema of the whole collection
+pub const ALL: &str = "*";
+
+impl Schemas {
+    pub fn is_empty(&self) -> bool {
+        self.all.is_none() && self.keys.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Schema> {
+        if key == ALL {
+            self.all.as_ref()
+        } else {
+            self.keys.get(key)
+        }
+    }
+
+    pub fn set(&mut self, key: &str, schema: Schema) {
+        if key == ALL {
+            self.all = Some(schema);
+        } else {
+            self.keys.insert(key.to_owned(), schema);
+        }
+    }
+
+    /// returns whether there was a schema to remove
+    pub fn remove(&mut self, key: &str) -> bool {
+        if key == ALL {
+            self.all.take().is_some()
+        } else {
+            self.keys.remove(key).is_some()
+        }
+    }
+
+    /// checks the document stored under top against the schema of the collection and its own
+    pub fn validate(&self, top: &str, doc: &json::JSON) -> Res<()> {
+        let path = pointer::escape(top);
+        if let Some(ref schema) = self.all {
+            schema.validate(doc, &path)?;
+        }
+        if let Some(schema) = self.keys.get(top) {
+            schema.validate(doc, &path)?;
+        }
+        Ok(())
+    }
+
+    /// every schema by the key it belongs to, `*` for the collection
+    pub fn to_json(&self) -> json::JSON {
+        let mut schemas = serde_json::Map::new();
+        if let Some(ref schema) = self.all {
+            schemas.insert(ALL.to_owned(), schema.source().clone());
+        }
+        for (k, schema) in self.keys.iter() {
+            schemas.insert(k.clone(), schema.source().clone());
+        }
+        json::JSON::Object(schemas)
+    }
+}
+
+fn is_type(value: &json::JSON, t: &str) -> bool {
+    match t {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        // 1.0 is an integer too
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|x| x.fract() == 0.0),
+        _ => false,
+    }
+}
+
+fn number(v: &json::JSON) -> Res<f64> {
+    match v.as_f64() {
+        Some(x) => Ok(x),
+        None => Err(error::ServerError::INVALID_ARG),
+    }
+}
+
+fn count(v: &json::JSON) -> Res<usize> {
+    match v.as_u64() {
+        Some(x) => Ok(x as usize),
+        None => Err(error::ServerError::INVALID_ARG),
+    }
+}
+
+fn child(path: &str, name: &str) -> String {
+    format!("{}{}", path, pointer::escape(name))
+}
+
+fn violation(path: &str, reason: &str) -> Res<()> {
+    Err(error::ServerError::SCHEMA_VIOLATION(format!("Error: Schema violation at {}: {}", path, reason)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema(source: json::JSON) -> Schema {
+        Schema::parse(&source).unwrap()
+    }
+
+    /// where and why value breaks the schema, none if it matches
+    fn broken(schema: &Schema, value: json::JSON) -> Option<String> {
+        match schema.validate(&value, "/k") {
+            Ok(()) => None,
+            Err(error::ServerError::SCHEMA_VIOLATION(x)) => Some(x),
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn invalid_schemas() {
+        for source in [
+            json::to_json!([]),
+            json::to_json!({"type": "float"}),
+            json::to_json!({"type": []}),
+            json::to_json!({"required": "a"}),
+            json::to_json!({"properties": {"a": 1}}),
+            json::to_json!({"minimum": "1"}),
+            json::to_json!({"minLength": -1}),
+            json::to_json!({"pattern": "("}),
+        ] {
+            assert_eq!(Schema::parse(&source).unwrap_err().code(), "INVALID_ARG");
+        }
+        assert!(Schema::parse(&json::to_json!({"unknown": 1})).is_ok());
+    }
+
+    #[test]
+    fn types_and_enums() {
+        let s = schema(json::to_json!({"type": ["integer", "null"]}));
+        assert_eq!(broken(&s, json::to_json!(1)), None);
+        assert_eq!(broken(&s, json::to_json!(1.0)), None);
+        assert_eq!(broken(&s, json::JSON::Null), None);
+        assert_eq!(broken(&s, json::to_json!(1.5)).unwrap(), "Error: Schema violation at /k: expected integer or null");
+        let s = schema(json::to_json!({"enum": ["a", 1]}));
+        assert_eq!(broken(&s, json::to_json!(1)), None);
+        assert!(broken(&s, json::to_json!("b")).is_some());
+    }
+
+    #[test]
+    fn bounds() {
+        let s = schema(json::to_json!({"minimum": 1, "exclusiveMaximum": 3}));
+        assert_eq!(broken(&s, json::to_json!(1)), None);
+        assert!(broken(&s, json::to_json!(0.5)).unwrap().ends_with("below the minimum"));
+        assert!(broken(&s, json::to_json!(3)).unwrap().ends_with("above the maximum"));
+        assert_eq!(broken(&s, json::to_json!("not a number")), None);
+
+        let s = schema(json::to_json!({"minLength": 2, "maxLength": 3, "pattern": "^a"}));
+        assert_eq!(broken(&s, json::to_json!("aé")), None);
+        assert!(broken(&s, json::to_json!("a")).unwrap().ends_with("shorter than minLength"));
+        assert!(broken(&s, json::to_json!("abcd")).unwrap().ends_with("longer than maxLength"));
+        assert!(broken(&s, json::to_json!("ba")).unwrap().ends_with("does not match the pattern"));
+    }
+
+    #[test]
+    fn nested_paths() {
+        let s = schema(json::to_json!({
+            "type": "object",
+            "required": ["a/b"],
+            "properties": {"list": {"maxItems": 2, "items": {"type": "string"}}},
+        }));
+        assert_eq!(broken(&s, json::to_json!({"a/b": 1, "list": ["x"]})), None);
+        assert_eq!(broken(&s, json::to_json!({})).unwrap(), "Error: Schema violation at /k/a~1b: required");
+        assert_eq!(broken(&s, json::to_json!({"a/b": 1, "list": ["x", 2]})).unwrap(), "Error: Schema violation at /k/list/1: expected string");
+        assert!(broken(&s, json::to_json!({"a/b": 1, "list": ["x", "y", "z"]})).unwrap().ends_with("more items than maxItems"));
+    }
+
+    #[test]
+    fn collection_and_key_schemas() {
+        let mut schemas = Schemas::default();
+        assert!(schemas.is_empty());
+        schemas.set(ALL, schema(json::to_json!({"type": "object"})));
+        schemas.set("n", schema(json::to_json!({"required": ["x"]})));
+        assert!(schemas.validate("m", &json::to_json!({})).is_ok());
+        assert!(schemas.validate("m", &json::to_json!(1)).is_err());
+        assert!(schemas.validate("n", &json::to_json!({})).is_err());
+        assert_eq!(schemas.to_json(), json::to_json!({"*": {"type": "object"}, "n": {"required": ["x"]}}));
+        assert!(schemas.remove("n"));
+        assert!(!schemas.remove("n"));
+        assert!(schemas.validate("n", &json::to_json!({})).is_ok());
+    }
+}
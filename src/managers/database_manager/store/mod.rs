@@ -0,0 +1,213 @@
+use std::{collections::{BTreeMap, HashMap}, ops::Bound};
+use serde::{Serialize, Serializer};
+
+use super::super::{error, json};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// which map the top-level keys of a collection live in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreKind {
+    /// fastest for single key lookups, sorted reads have to sort every key first
+    Hash,
+    /// keeps keys sorted so scans and ranges only touch the keys they return
+    Ordered,
+}
+
+impl StoreKind {
+    pub fn parse(kind: &str) -> Res<StoreKind> {
+        match kind.to_lowercase().as_str() {
+            "hash" => Ok(StoreKind::Hash),
+            "ordered" | "btree" => Ok(StoreKind::Ordered),
+            _ => Err(error::ServerError::INVALID_ARG),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StoreKind::Hash => "hash",
+            StoreKind::Ordered => "ordered",
+        }
+    }
+}
+
+/// the top-level keys of a collection
+#[derive(Debug)]
+pub enum Store {
+    Hash(HashMap<String,json::JSON>),
+    Ordered(BTreeMap<String,json::JSON>),
+}
+
+impl Store {
+    pub fn new(kind: StoreKind) -> Store {
+        match kind {
+            StoreKind::Hash => Store::Hash(HashMap::new()),
+            StoreKind::Ordered => Store::Ordered(BTreeMap::new()),
+        }
+    }
+
+    pub fn kind(&self) -> StoreKind {
+        match self {
+            Store::Hash(_) => StoreKind::Hash,
+            Store::Ordered(_) => StoreKind::Ordered,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&json::JSON> {
+        match self {
+            Store::Hash(x) => x.get(key),
+            Store::Ordered(x) => x.get(key),
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut json::JSON> {
+        match self {
+            Store::Hash(x) => x.get_mut(key),
+            Store::Ordered(x) => x.get_mut(key),
+        }
+    }
+
+    pub fn insert(&mut self, key: String, value: json::JSON) -> Option<json::JSON> {
+        match self {
+            Store::Hash(x) => x.insert(key, value),
+            Store::Ordered(x) => x.insert(key, value),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<json::JSON> {
+        match self {
+            Store::Hash(x) => x.remove(key),
+            Store::Ordered(x) => x.remove(key),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Store::Hash(x) => x.len(),
+            Store::Ordered(x) => x.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// every key and value in no particular order
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&String, &json::JSON)> + '_> {
+        match self {
+            Store::Hash(x) => Box::new(x.iter()),
+            Store::Ordered(x) => Box::new(x.iter()),
+        }
+    }
+
+    /// the keys and values between start and end in key order, or in reverse order if reverse is set
+    pub fn range(&self, start: Bound<&str>, end: Bound<&str>, reverse: bool) -> Box<dyn Iterator<Item = (&String, &json::JSON)> + '_> {
+        match self {
+            Store::Hash(x) => {
+                let mut entries: Vec<(&String, &json::JSON)> = x.iter().filter(|(k, _)| in_range(k, start, end)).collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                if reverse {
+                    entries.reverse();
+                }
+                Box::new(entries.into_iter())
+            },
+            Store::Ordered(x) => {
+                // BTreeMap::range panics on an empty or inverted range
+                if !valid_range(start, end) {
+                    return Box::new(std::iter::empty());
+                }
+                let range = x.range::<str, _>((start, end));
+                if reverse {
+                    Box::new(range.rev())
+                } else {
+                    Box::new(range)
+                }
+            },
+        }
+    }
+}
+
+impl Serialize for Store {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Store::Hash(x) => x.serialize(serializer),
+            Store::Ordered(x) => x.serialize(serializer),
+        }
+    }
+}
+
+fn in_range(key: &str, start: Bound<&str>, end: Bound<&str>) -> bool {
+    let after_start = match start {
+        Bound::Included(s) => key >= s,
+        Bound::Excluded(s) => key > s,
+        Bound::Unbounded => true,
+    };
+    let before_end = match end {
+        Bound::Included(e) => key <= e,
+        Bound::Excluded(e) => key < e,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+fn valid_range(start: Bound<&str>, end: Bound<&str>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s <= e,
+        (Bound::Included(s), Bound::Excluded(e)) | (Bound::Excluded(s), Bound::Included(e)) => s < e,
+        (Bound::Excluded(s), Bound::Excluded(e)) => s < e,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(kind: StoreKind) -> Store {
+        let mut store = Store::new(kind);
+        for (i, k) in ["b", "a", "d", "c", "e"].iter().enumerate() {
+            store.insert(k.to_string(), json::to_json!(i));
+        }
+        store
+    }
+
+    /// the keys both kinds of store return for the range, which have to be the same
+    fn keys(start: Bound<&str>, end: Bound<&str>, reverse: bool) -> Vec<String> {
+        let (hash, ordered) = (filled(StoreKind::Hash), filled(StoreKind::Ordered));
+        let hashed: Vec<String> = hash.range(start, end, reverse).map(|(k, _)| k.clone()).collect();
+        let sorted: Vec<String> = ordered.range(start, end, reverse).map(|(k, _)| k.clone()).collect();
+        assert_eq!(hashed, sorted);
+        sorted
+    }
+
+    #[test]
+    fn ranges_are_the_same_for_both_kinds() {
+        assert_eq!(keys(Bound::Unbounded, Bound::Unbounded, false), vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(keys(Bound::Included("b"), Bound::Included("d"), false), vec!["b", "c", "d"]);
+        assert_eq!(keys(Bound::Excluded("b"), Bound::Excluded("d"), false), vec!["c"]);
+        assert_eq!(keys(Bound::Included("bb"), Bound::Unbounded, true), vec!["e", "d", "c"]);
+        assert_eq!(keys(Bound::Unbounded, Bound::Excluded("c"), true), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn empty_and_inverted_ranges() {
+        assert_eq!(keys(Bound::Included("d"), Bound::Included("b"), false), Vec::<String>::new());
+        assert_eq!(keys(Bound::Excluded("c"), Bound::Excluded("c"), false), Vec::<String>::new());
+        assert_eq!(keys(Bound::Included("c"), Bound::Excluded("c"), true), Vec::<String>::new());
+        assert_eq!(keys(Bound::Included("c"), Bound::Included("c"), false), vec!["c"]);
+        assert!(!valid_range(Bound::Excluded("c"), Bound::Included("c")));
+        assert!(valid_range(Bound::Unbounded, Bound::Excluded("a")));
+    }
+
+    #[test]
+    fn kinds_and_sizes() {
+        assert_eq!(StoreKind::parse("BTree").unwrap(), StoreKind::Ordered);
+        assert_eq!(StoreKind::parse("hash").unwrap().name(), "hash");
+        assert_eq!(StoreKind::parse("list").unwrap_err().code(), "INVALID_ARG");
+        let mut store = Store::new(StoreKind::Ordered);
+        assert!(store.is_empty());
+        store.insert("a".to_owned(), json::JSON::Null);
+        assert_eq!((store.len(), store.is_empty(), store.kind()), (1, false, StoreKind::Ordered));
+        assert_eq!(serde_json::to_string(&filled(StoreKind::Ordered)).unwrap(), r#"{"a":1,"b":0,"c":3,"d":2,"e":4}"#);
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::{BTreeMap, HashMap};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::super::{error, json, framing};
+use super::pointer;
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// what a `WATCH` is interested in. `user` matches changes to the key `user` and to anything inside
+/// it, `user:*` matches every top-level key starting with `user:`
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Prefix(String),
+    Path(Vec<String>),
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Res<Pattern> {
+        if let Some(prefix) = pattern.strip_suffix('*') {
+            return Ok(Pattern::Prefix(prefix.to_owned()));
+        }
+        let path = path(pattern)?;
+        if path.is_empty() || path[0].is_empty() {
+            return Err(error::ServerError::INVALID_ARG);
+        }
+        Ok(Pattern::Path(path))
+    }
+
+    /// whether a change to key touches what the pattern watches, a change above the watched path
+    /// replaces it and one below it changes part of it
+    pub fn matches(&self, key: &str) -> bool {
+        let changed = match path(key) {
+            Ok(x) if !x.is_empty() => x,
+            _ => return false,
+        };
+        match self {
+            Pattern::Prefix(prefix) => changed[0].starts_with(prefix.as_str()),
+            Pattern::Path(watched) => watched.iter().zip(changed.iter()).all(|(a, b)| a == b),
+        }
+    }
+}
+
+/// the parts of a dotted key or json pointer
+fn path(key: &str) -> Res<Vec<String>> {
+    if key.starts_with('/') {
+        pointer::parse(key)
+    } else {
+        Ok(key.split('.').map(|x| x.to_owned()).collect())
+    }
+}
+
+#[derive(Debug)]
+struct Watch {
+    connection: String,
+    collection: String,
+    pattern: Pattern,
+}
+
+/// the connections changes can be pushed to and what each of them watches. events are sent as
+/// `!<watch id> <json>` frames so a client can tell them apart from replies
+#[derive(Debug, Default)]
+pub struct Watches {
+    next: u64,
+    subscribers: HashMap<String, UnboundedSender<String>>,
+    watches: BTreeMap<u64, Watch>,
+}
+
+impl Watches {
+    /// the channel events for the connection arrive on. it is dropped with the connection
+    pub fn subscribe(&mut self, connection: &str) -> UnboundedReceiver<String> {
+        self.prune();
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.insert(connection.to_owned(), tx);
+        rx
+    }
+
+    /// starts pushing changes matching pattern in the collection to the connection. returns the id
+    /// the events carry
+    pub fn add(&mut self, connection: &str, collection: &str, pattern: Pattern) -> Res<u64> {
+        if !self.subscribers.contains_key(connection) {
+            return Err(error::ServerError::CONNECTION);
+        }
+        self.next += 1;
+        self.watches.insert(self.next, Watch { connection: connection.to_owned(), collection: collection.to_owned(), pattern });
+        Ok(self.next)
+    }
+
+    /// stops a watch of the connection. returns whether there was one
+    pub fn remove(&mut self, connection: &str, id: u64) -> bool {
+        match self.watches.get(&id) {
+            Some(x) if x.connection == connection => self.watches.remove(&id).is_some(),
+            _ => false,
+        }
+    }
+
+    /// the watches of the connection by id
+    pub fn list(&self, connection: &str) -> json::JSON {
+        let mut watches = serde_json::Map::new();
+        for (id, watch) in self.watches.iter().filter(|(_, x)| x.connection == connection) {
+            let pattern = match watch.pattern {
+                Pattern::Prefix(ref x) => format!("{}*", x),
+                Pattern::Path(ref x) => x.join("."),
+            };
+            watches.insert(id.to_string(), json::to_json!({"collection": watch.collection, "pattern": pattern}));
+        }
+        json::JSON::Object(watches)
+    }
+
+    /// pushes a change made by command to key to every watch it matches. value is what key holds now
+    pub fn notify(&mut self, collection: &str, command: &str, key: &str, value: &json::JSON) {
+        let mut gone = false;
+        for (id, watch) in self.watches.iter() {
+            if watch.collection != collection || !watch.pattern.matches(key) {
+                continue;
+            }
+            let event = json::to_json!({"watch": id, "event": command, "collection": collection, "key": key, "value": value});
+            let sent = match self.subscribers.get(&watch.connection) {
+                Some(tx) => tx.send(framing::tag_event(&id.to_string(), &event.to_string())).is_ok(),
+                None => false,
+            };
+            gone = gone || !sent;
+        }
+        if gone {
+            self.prune();
+        }
+    }
+
+    /// forgets connections that closed and their watches
+    fn prune(&mut self) {
+        self.subscribers.retain(|_, tx| !tx.is_closed());
+        let subscribers = &self.subscribers;
+        self.watches.retain(|_, x| subscribers.contains_key(&x.connection));
+    }
+}
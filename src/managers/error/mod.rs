@@ -17,12 +17,19 @@ pub enum ServerError{
     FAILED_WRITE,
     INCOMPLETE_OPERATION,
     INCOMPATIBLE_DATA_TYPES,
-    ACCESS_DENIED
+    ACCESS_DENIED,
+    CONFLICT,
+    // carries which path broke the schema and how
+    SCHEMA_VIOLATION(String),
+    // carries where a command line could not be parsed and why
+    SYNTAX_ERROR(String),
+    // carries the commands that would have been understood
+    UNKNOWN_COMMAND(String)
 }
 
 impl fmt::Display for ServerError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "Error occured sorry! {}", self)// user facing
+        write!(fmt, "Error occured sorry! {}", self.produce_error())// user facing
     }
 }
 
@@ -35,6 +42,66 @@ impl fmt::Debug for ServerError {
 impl std::error::Error for ServerError {}
 
 impl ServerError {
+    /// stable name of the error that clients can match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::TEST => "TEST",
+            ServerError::NONE => "NONE",
+            ServerError::TAKEN => "TAKEN",
+            ServerError::MISSING_DATA => "MISSING_DATA",
+            ServerError::INVALID_JSON => "INVALID_JSON",
+            ServerError::INVALID_DATA => "INVALID_DATA",
+            ServerError::INVALID_ARG => "INVALID_ARG",
+            ServerError::INVALID_FILE => "INVALID_FILE",
+            ServerError::CONNECTION => "CONNECTION",
+            ServerError::NO_VALUE => "NO_VALUE",
+            ServerError::FAILED_READ => "FAILED_READ",
+            ServerError::FAILED_WRITE => "FAILED_WRITE",
+            ServerError::INCOMPLETE_OPERATION => "INCOMPLETE_OPERATION",
+            ServerError::INCOMPATIBLE_DATA_TYPES => "INCOMPATIBLE_DATA_TYPES",
+            ServerError::ACCESS_DENIED => "ACCESS_DENIED",
+            ServerError::CONFLICT => "CONFLICT",
+            ServerError::SCHEMA_VIOLATION(_) => "SCHEMA_VIOLATION",
+            ServerError::SYNTAX_ERROR(_) => "SYNTAX_ERROR",
+            ServerError::UNKNOWN_COMMAND(_) => "UNKNOWN_COMMAND",
+        }
+    }
+
+    /// turns a code sent by another manager back into the error. unknown codes become `NONE`
+    pub fn from_code(code: &str) -> ServerError {
+        match code {
+            "TEST" => ServerError::TEST,
+            "TAKEN" => ServerError::TAKEN,
+            "MISSING_DATA" => ServerError::MISSING_DATA,
+            "INVALID_JSON" => ServerError::INVALID_JSON,
+            "INVALID_DATA" => ServerError::INVALID_DATA,
+            "INVALID_ARG" => ServerError::INVALID_ARG,
+            "INVALID_FILE" => ServerError::INVALID_FILE,
+            "CONNECTION" => ServerError::CONNECTION,
+            "NO_VALUE" => ServerError::NO_VALUE,
+            "FAILED_READ" => ServerError::FAILED_READ,
+            "FAILED_WRITE" => ServerError::FAILED_WRITE,
+            "INCOMPLETE_OPERATION" => ServerError::INCOMPLETE_OPERATION,
+            "INCOMPATIBLE_DATA_TYPES" => ServerError::INCOMPATIBLE_DATA_TYPES,
+            "ACCESS_DENIED" => ServerError::ACCESS_DENIED,
+            "CONFLICT" => ServerError::CONFLICT,
+            "SCHEMA_VIOLATION" => ServerError::SCHEMA_VIOLATION("Error: Schema violation".to_owned()),
+            "SYNTAX_ERROR" => ServerError::SYNTAX_ERROR("Error: Syntax error".to_owned()),
+            "UNKNOWN_COMMAND" => ServerError::UNKNOWN_COMMAND("Error: Unknown command".to_owned()),
+            _ => ServerError::NONE,
+        }
+    }
+
+    /// puts back the message of an error that carries one, the others stay as they are
+    pub fn with_message(self, message: &str) -> ServerError {
+        match self {
+            ServerError::SCHEMA_VIOLATION(_) => ServerError::SCHEMA_VIOLATION(message.to_owned()),
+            ServerError::SYNTAX_ERROR(_) => ServerError::SYNTAX_ERROR(message.to_owned()),
+            ServerError::UNKNOWN_COMMAND(_) => ServerError::UNKNOWN_COMMAND(message.to_owned()),
+            x => x,
+        }
+    }
+
     pub fn produce_error(&self) -> &str {
         match self {
             ServerError::TEST => {
@@ -97,6 +164,18 @@ impl ServerError {
                 //println!("Error: Incompatible data types");
                 "Error: Access Denied"
             }
+            ServerError::CONFLICT => {
+                "Error: Key changed since the version given"
+            }
+            ServerError::SCHEMA_VIOLATION(message) => {
+                message
+            }
+            ServerError::SYNTAX_ERROR(message) => {
+                message
+            }
+            ServerError::UNKNOWN_COMMAND(message) => {
+                message
+            }
             
         }
     }
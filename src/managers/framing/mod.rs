@@ -0,0 +1,296 @@
+use std::io::Read;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::error;
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// largest message a peer is allowed to send in a single frame
+pub const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// how messages are delimited on a tcp connection between managers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// every message ends with a `\n`. this is the default when a peer does not negotiate
+    Line,
+    /// every message starts with its length as a 4 byte big endian integer
+    Length,
+}
+
+impl Framing {
+    /// the line a client sends first to ask the server for this framing
+    pub fn handshake(&self) -> &'static str {
+        match self {
+            Framing::Line => "FRAME LINE",
+            Framing::Length => "FRAME LEN",
+        }
+    }
+
+    fn from_handshake(line: &str) -> Option<Framing> {
+        match line {
+            "FRAME LINE" => Some(Framing::Line),
+            "FRAME LEN" => Some(Framing::Length),
+            _ => None,
+        }
+    }
+
+    /// wraps the message so the peer can find where it ends
+    pub fn encode(&self, message: &str) -> Vec<u8> {
+        match self {
+            Framing::Line => {
+                let mut bytes = Vec::with_capacity(message.len() + 1);
+                bytes.extend_from_slice(message.as_bytes());
+                bytes.push(b'\n');
+                bytes
+            },
+            Framing::Length => {
+                let mut bytes = Vec::with_capacity(message.len() + 4);
+                bytes.extend_from_slice(&(message.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(message.as_bytes());
+                bytes
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// the peer asked to switch to this framing, the server answers with `OK` in the new framing
+    Handshake(Framing),
+    Message(String),
+}
+
+/// buffers bytes read from a connection and splits them into frames
+#[derive(Debug)]
+pub struct Decoder {
+    framing: Option<Framing>,
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    /// decoder for the accepting side. the framing is picked by the first line the peer sends
+    pub fn server() -> Decoder {
+        Decoder { framing: None, buf: Vec::new() }
+    }
+
+    /// decoder for a connection whose framing is already agreed on
+    pub fn new(framing: Framing) -> Decoder {
+        Decoder { framing: Some(framing), buf: Vec::new() }
+    }
+
+    /// framing replies should be written with
+    pub fn framing(&self) -> Framing {
+        self.framing.unwrap_or(Framing::Line)
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// takes the next complete frame out of the buffer if there is one
+    pub fn next_frame(&mut self) -> Res<Option<Frame>> {
+        match self.framing {
+            None => {
+                match self.next_line()? {
+                    Some(line) => {
+                        // whatever the first line is the framing is settled after it
+                        match Framing::from_handshake(&line) {
+                            Some(framing) => {
+                                self.framing = Some(framing);
+                                Ok(Some(Frame::Handshake(framing)))
+                            },
+                            None => {
+                                self.framing = Some(Framing::Line);
+                                Ok(Some(Frame::Message(line)))
+                            },
+                        }
+                    },
+                    None => Ok(None),
+                }
+            },
+            Some(Framing::Line) => Ok(self.next_line()?.map(Frame::Message)),
+            Some(Framing::Length) => {
+                if self.buf.len() < 4 {
+                    return Ok(None);
+                }
+                let len = u32::from_be_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]]) as usize;
+                if len > MAX_FRAME {
+                    return Err(error::ServerError::INVALID_DATA);
+                }
+                if self.buf.len() < len + 4 {
+                    return Ok(None);
+                }
+                let bytes: Vec<u8> = self.buf.drain(..len + 4).skip(4).collect();
+                match String::from_utf8(bytes) {
+                    Ok(message) => Ok(Some(Frame::Message(message))),
+                    Err(_) => Err(error::ServerError::INVALID_DATA),
+                }
+            },
+        }
+    }
+
+    fn next_line(&mut self) -> Res<Option<String>> {
+        match self.buf.iter().position(|b| *b == b'\n') {
+            Some(end) => {
+                let mut bytes: Vec<u8> = self.buf.drain(..=end).collect();
+                bytes.pop();
+                if bytes.last() == Some(&b'\r') {
+                    bytes.pop();
+                }
+                match String::from_utf8(bytes) {
+                    Ok(line) => Ok(Some(line)),
+                    Err(_) => Err(error::ServerError::INVALID_DATA),
+                }
+            },
+            None => {
+                if self.buf.len() > MAX_FRAME {
+                    Err(error::ServerError::INVALID_DATA)
+                } else {
+                    Ok(None)
+                }
+            },
+        }
+    }
+}
+
+/// reads from the socket until a whole frame is buffered. returns none when the peer closed the connection
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, decoder: &mut Decoder) -> Res<Option<Frame>> {
+    let mut buf = [0; 4096];
+    loop {
+        if let Some(frame) = decoder.next_frame()? {
+            return Ok(Some(frame));
+        }
+        match reader.read(&mut buf).await {
+            Ok(0) => return Ok(None),
+            Ok(n) => decoder.extend(&buf[..n]),
+            Err(_) => return Err(error::ServerError::FAILED_READ),
+        }
+    }
+}
+
+/// blocking version of `read_frame` for std streams
+pub fn read_frame_blocking<R: Read>(reader: &mut R, decoder: &mut Decoder) -> Res<Option<Frame>> {
+    let mut buf = [0; 4096];
+    loop {
+        if let Some(frame) = decoder.next_frame()? {
+            return Ok(Some(frame));
+        }
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(n) => decoder.extend(&buf[..n]),
+            Err(_) => return Err(error::ServerError::FAILED_READ),
+        }
+    }
+}
+
+/// prefixes a message with the id of the request it belongs to, `#<id> <message>`
+pub fn tag(id: &str, message: &str) -> String {
+    format!("#{} {}", id, message)
+}
+
+/// marks a message the server pushed without being asked, `!<watch id> <message>`
+pub fn tag_event(id: &str, message: &str) -> String {
+    format!("!{} {}", id, message)
+}
+
+/// splits the watch id off a pushed message, none if it is not one
+pub fn untag_event(message: &str) -> Option<(&str, &str)> {
+    message.strip_prefix('!').and_then(|rest| rest.split_once(' '))
+}
+
+/// splits the request id off a message if it carries one
+pub fn untag(message: &str) -> (Option<&str>, &str) {
+    match message.strip_prefix('#') {
+        Some(rest) => {
+            match rest.split_once(' ') {
+                Some((id, message)) => (Some(id), message),
+                None => (Some(rest), ""),
+            }
+        },
+        None => (None, message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(decoder: &mut Decoder) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn server_defaults_to_lines() {
+        let mut decoder = Decoder::server();
+        decoder.extend(b"FND a\r\nFND b\n");
+        assert_eq!(frames(&mut decoder), vec![Frame::Message("FND a".to_owned()), Frame::Message("FND b".to_owned())]);
+        assert_eq!(decoder.framing(), Framing::Line);
+    }
+
+    #[test]
+    fn handshake_switches_framing() {
+        let mut decoder = Decoder::server();
+        decoder.extend(b"FRAME LEN\n");
+        decoder.extend(&Framing::Length.encode("a\nb"));
+        assert_eq!(frames(&mut decoder), vec![Frame::Handshake(Framing::Length), Frame::Message("a\nb".to_owned())]);
+        assert_eq!(decoder.framing(), Framing::Length);
+    }
+
+    #[test]
+    fn handshake_only_counts_first() {
+        let mut decoder = Decoder::server();
+        decoder.extend(b"hello\nFRAME LEN\n");
+        assert_eq!(frames(&mut decoder), vec![Frame::Message("hello".to_owned()), Frame::Message("FRAME LEN".to_owned())]);
+    }
+
+    #[test]
+    fn partial_frames_wait_for_the_rest() {
+        let encoded = Framing::Length.encode("hello");
+        let mut decoder = Decoder::new(Framing::Length);
+        decoder.extend(&encoded[..2]);
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        decoder.extend(&encoded[2..6]);
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        decoder.extend(&encoded[6..]);
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::Message("hello".to_owned())));
+
+        let mut decoder = Decoder::new(Framing::Line);
+        decoder.extend(b"hel");
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        decoder.extend(b"lo\n");
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::Message("hello".to_owned())));
+    }
+
+    #[test]
+    fn oversized_and_invalid_frames_fail() {
+        let mut decoder = Decoder::new(Framing::Length);
+        decoder.extend(&((MAX_FRAME + 1) as u32).to_be_bytes());
+        assert_eq!(decoder.next_frame().unwrap_err().code(), "INVALID_DATA");
+
+        let mut decoder = Decoder::new(Framing::Line);
+        decoder.extend(&[0xff, 0xfe, b'\n']);
+        assert_eq!(decoder.next_frame().unwrap_err().code(), "INVALID_DATA");
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(untag(&tag("7", "FND a b")), (Some("7"), "FND a b"));
+        assert_eq!(untag("#7"), (Some("7"), ""));
+        assert_eq!(untag("FND a"), (None, "FND a"));
+        assert_eq!(untag_event(&tag_event("3", "{}")), Some(("3", "{}")));
+        assert_eq!(untag_event("#3 {}"), None);
+    }
+
+    #[tokio::test]
+    async fn reads_from_a_stream() {
+        let mut bytes: &[u8] = b"FRAME LEN\n\x00\x00\x00\x02hi";
+        let mut decoder = Decoder::server();
+        assert_eq!(read_frame(&mut bytes, &mut decoder).await.unwrap(), Some(Frame::Handshake(Framing::Length)));
+        assert_eq!(read_frame(&mut bytes, &mut decoder).await.unwrap(), Some(Frame::Message("hi".to_owned())));
+        assert_eq!(read_frame(&mut bytes, &mut decoder).await.unwrap(), None);
+    }
+}
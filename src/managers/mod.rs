@@ -3,4 +3,9 @@ pub mod session_manager;
 pub mod cache_manager;
 pub mod database_manager;
 pub mod json;
-pub mod error;
\ No newline at end of file
+pub mod error;
+pub mod framing;
+pub mod response;
+pub mod command;
+pub mod resp;
+pub mod client;
@@ -0,0 +1,250 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::{error, framing};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// the most arguments a single command may have
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// a reply in the redis serialization protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<Value>),
+    /// sent as a map to RESP3 clients and as a flat array of keys and values to RESP2 ones
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    /// `-ERR <message>`
+    pub fn err(message: &str) -> Value {
+        Value::Error(format!("ERR {}", message))
+    }
+
+    /// writes the value for a client speaking RESP version 2 or 3
+    pub fn encode(&self, version: u8, out: &mut Vec<u8>) {
+        match self {
+            Value::Simple(x) => out.extend_from_slice(format!("+{}\r\n", x.replace(['\r', '\n'], " ")).as_bytes()),
+            Value::Error(x) => out.extend_from_slice(format!("-{}\r\n", x.replace(['\r', '\n'], " ")).as_bytes()),
+            Value::Integer(x) => out.extend_from_slice(format!(":{}\r\n", x).as_bytes()),
+            Value::Bulk(x) => {
+                out.extend_from_slice(format!("${}\r\n", x.len()).as_bytes());
+                out.extend_from_slice(x.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            },
+            Value::Null => {
+                if version >= 3 {
+                    out.extend_from_slice(b"_\r\n");
+                } else {
+                    out.extend_from_slice(b"$-1\r\n");
+                }
+            },
+            Value::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(version, out);
+                }
+            },
+            Value::Map(pairs) => {
+                if version >= 3 {
+                    out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                } else {
+                    out.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                }
+                for (k, v) in pairs {
+                    k.encode(version, out);
+                    v.encode(version, out);
+                }
+            },
+        }
+    }
+}
+
+/// buffers bytes read from a connection and splits them into commands. clients send an array of bulk
+/// strings, `*2\r\n$3\r\nGET\r\n$1\r\na\r\n`, and a line of words is taken as an inline command
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// takes the next complete command out of the buffer if there is one. an empty inline line is an
+    /// empty command
+    pub fn next_command(&mut self) -> Res<Option<Vec<String>>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        if self.buf[0] != b'*' {
+            return match self.line(0)? {
+                Some((line, end)) => {
+                    let command = line.split_whitespace().map(|x| x.to_owned()).collect();
+                    self.buf.drain(..end);
+                    Ok(Some(command))
+                },
+                None => Ok(None),
+            };
+        }
+        let (count, mut at) = match self.line(1)? {
+            Some((x, end)) => (number(&x)?, end),
+            None => return Ok(None),
+        };
+        if count > MAX_ARGS {
+            return Err(error::ServerError::INVALID_DATA);
+        }
+        let mut command = Vec::with_capacity(count);
+        for _ in 0..count {
+            if at >= self.buf.len() {
+                return Ok(None);
+            }
+            if self.buf[at] != b'$' {
+                return Err(error::ServerError::INVALID_DATA);
+            }
+            let (len, start) = match self.line(at + 1)? {
+                Some((x, end)) => (number(&x)?, end),
+                None => return Ok(None),
+            };
+            if len > framing::MAX_FRAME {
+                return Err(error::ServerError::INVALID_DATA);
+            }
+            if self.buf.len() < start + len + 2 {
+                return Ok(None);
+            }
+            if &self.buf[start + len..start + len + 2] != b"\r\n" {
+                return Err(error::ServerError::INVALID_DATA);
+            }
+            match String::from_utf8(self.buf[start..start + len].to_vec()) {
+                Ok(x) => command.push(x),
+                Err(_) => return Err(error::ServerError::INVALID_DATA),
+            }
+            at = start + len + 2;
+        }
+        self.buf.drain(..at);
+        Ok(Some(command))
+    }
+
+    /// the line starting at from without its `\r\n`, and where the next one starts
+    fn line(&self, from: usize) -> Res<Option<(String, usize)>> {
+        match self.buf[from..].iter().position(|b| *b == b'\n') {
+            Some(i) => {
+                let end = from + i;
+                let line = if end > from && self.buf[end - 1] == b'\r' { &self.buf[from..end - 1] } else { &self.buf[from..end] };
+                match String::from_utf8(line.to_vec()) {
+                    Ok(x) => Ok(Some((x, end + 1))),
+                    Err(_) => Err(error::ServerError::INVALID_DATA),
+                }
+            },
+            None => {
+                if self.buf.len() > framing::MAX_FRAME {
+                    Err(error::ServerError::INVALID_DATA)
+                } else {
+                    Ok(None)
+                }
+            },
+        }
+    }
+}
+
+fn number(x: &str) -> Res<usize> {
+    match x.parse::<usize>() {
+        Ok(x) => Ok(x),
+        Err(_) => Err(error::ServerError::INVALID_DATA),
+    }
+}
+
+/// reads from the socket until a whole command is buffered. returns none when the peer closed the connection
+pub async fn read_command<R: AsyncRead + Unpin>(reader: &mut R, decoder: &mut Decoder) -> Res<Option<Vec<String>>> {
+    let mut buf = [0; 4096];
+    loop {
+        if let Some(command) = decoder.next_command()? {
+            return Ok(Some(command));
+        }
+        match reader.read(&mut buf).await {
+            Ok(0) => return Ok(None),
+            Ok(n) => decoder.extend(&buf[..n]),
+            Err(_) => return Err(error::ServerError::FAILED_READ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(value: Value, version: u8) -> String {
+        let mut out = Vec::new();
+        value.encode(version, &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    fn words(x: &[&str]) -> Option<Vec<String>> {
+        Some(x.iter().map(|x| x.to_string()).collect())
+    }
+
+    #[test]
+    fn encodes_replies() {
+        assert_eq!(encoded(Value::Simple("OK".to_owned()), 2), "+OK\r\n");
+        assert_eq!(encoded(Value::err("bad\r\nthing"), 2), "-ERR bad  thing\r\n");
+        assert_eq!(encoded(Value::Integer(-3), 2), ":-3\r\n");
+        assert_eq!(encoded(Value::Bulk("é\r\n".to_owned()), 2), "$4\r\né\r\n\r\n");
+        assert_eq!(encoded(Value::Null, 2), "$-1\r\n");
+        assert_eq!(encoded(Value::Null, 3), "_\r\n");
+        assert_eq!(encoded(Value::Array(vec![Value::Integer(1), Value::Null]), 2), "*2\r\n:1\r\n$-1\r\n");
+        let map = Value::Map(vec![(Value::Bulk("a".to_owned()), Value::Integer(1))]);
+        assert_eq!(encoded(map.clone(), 2), "*2\r\n$1\r\na\r\n:1\r\n");
+        assert_eq!(encoded(map, 3), "%1\r\n$1\r\na\r\n:1\r\n");
+    }
+
+    #[test]
+    fn decodes_commands_a_byte_at_a_time() {
+        let bytes = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n";
+        let mut decoder = Decoder::default();
+        for (i, b) in bytes.iter().enumerate() {
+            assert_eq!(decoder.next_command().unwrap(), None, "complete after {} bytes", i);
+            decoder.extend(&[*b]);
+        }
+        assert_eq!(decoder.next_command().unwrap(), words(&["SET", "k", "a\r\nb"]));
+        assert_eq!(decoder.next_command().unwrap(), None);
+    }
+
+    #[test]
+    fn pipelined_and_inline_commands() {
+        let mut decoder = Decoder::default();
+        decoder.extend(b"*1\r\n$4\r\nPING\r\nGET  k\r\n\r\n*0\r\nEXISTS a b\n");
+        assert_eq!(decoder.next_command().unwrap(), words(&["PING"]));
+        assert_eq!(decoder.next_command().unwrap(), words(&["GET", "k"]));
+        assert_eq!(decoder.next_command().unwrap(), words(&[]));
+        assert_eq!(decoder.next_command().unwrap(), words(&[]));
+        assert_eq!(decoder.next_command().unwrap(), words(&["EXISTS", "a", "b"]));
+        assert_eq!(decoder.next_command().unwrap(), None);
+    }
+
+    #[test]
+    fn malformed_commands() {
+        for bytes in [&b"*x\r\n"[..], b"*1\r\n:1\r\n", b"*1\r\n$2\r\nabc\r\n", b"*1\r\n$-1\r\n", b"*1\r\n$1\r\n\xff\r\n"] {
+            let mut decoder = Decoder::default();
+            decoder.extend(bytes);
+            assert_eq!(decoder.next_command().unwrap_err().code(), "INVALID_DATA");
+        }
+        let mut decoder = Decoder::default();
+        decoder.extend(format!("*{}\r\n", MAX_ARGS + 1).as_bytes());
+        assert_eq!(decoder.next_command().unwrap_err().code(), "INVALID_DATA");
+    }
+
+    #[tokio::test]
+    async fn reads_from_a_stream() {
+        let mut bytes: &[u8] = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\nPING\r\n";
+        let mut decoder = Decoder::default();
+        assert_eq!(read_command(&mut bytes, &mut decoder).await.unwrap(), words(&["GET", "k"]));
+        assert_eq!(read_command(&mut bytes, &mut decoder).await.unwrap(), words(&["PING"]));
+        assert_eq!(read_command(&mut bytes, &mut decoder).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,76 @@
+use super::{error, json};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// `{"ok":true,"result":...}`
+pub fn ok(result: json::JSON) -> json::JSON {
+    json::to_json!({"ok": true, "result": result})
+}
+
+/// `{"ok":false,"error":{"code":...,"message":...}}`
+pub fn err(e: &error::ServerError) -> json::JSON {
+    json::to_json!({"ok": false, "error": {"code": e.code(), "message": e.produce_error()}})
+}
+
+/// wraps the outcome of a command in the envelope every reply is sent in
+pub fn envelope(result: Res<json::JSON>) -> json::JSON {
+    match result {
+        Ok(x) => ok(x),
+        Err(e) => err(&e),
+    }
+}
+
+/// reads an envelope sent back by another manager
+pub fn parse(reply: &str) -> Res<json::JSON> {
+    match json::to_value_from_str::<json::JSON>(reply) {
+        Ok(json::JSON::Object(mut x)) => {
+            match x.get("ok") {
+                Some(json::JSON::Bool(true)) => Ok(x.remove("result").unwrap_or(json::JSON::Null)),
+                Some(json::JSON::Bool(false)) => {
+                    let code = x.get("error").and_then(|e| e.get("code")).and_then(|c| c.as_str()).unwrap_or("");
+                    let message = x.get("error").and_then(|e| e.get("message")).and_then(|m| m.as_str());
+                    match message {
+                        Some(message) => Err(error::ServerError::from_code(code).with_message(message)),
+                        None => Err(error::ServerError::from_code(code)),
+                    }
+                },
+                _ => Err(error::ServerError::INVALID_DATA),
+            }
+        },
+        _ => Err(error::ServerError::INVALID_DATA),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelopes_round_trip() {
+        let reply = envelope(Ok(json::to_json!({"a": [1, 2]}))).to_string();
+        assert_eq!(parse(&reply).unwrap(), json::to_json!({"a": [1, 2]}));
+
+        let reply = envelope(Err(error::ServerError::MISSING_DATA)).to_string();
+        assert_eq!(parse(&reply).unwrap_err().code(), "MISSING_DATA");
+    }
+
+    #[test]
+    fn errors_keep_their_message() {
+        let reply = err(&error::ServerError::SYNTAX_ERROR("Error: Syntax error: at 3".to_owned())).to_string();
+        let e = parse(&reply).unwrap_err();
+        assert_eq!(e.code(), "SYNTAX_ERROR");
+        assert_eq!(e.produce_error(), "Error: Syntax error: at 3");
+    }
+
+    #[test]
+    fn ok_without_result_is_null() {
+        assert_eq!(parse(r#"{"ok":true}"#).unwrap(), json::JSON::Null);
+    }
+
+    #[test]
+    fn unknown_codes_and_bad_envelopes() {
+        assert_eq!(parse(r#"{"ok":false,"error":{"code":"NOPE"}}"#).unwrap_err().code(), "NONE");
+        assert_eq!(parse(r#"{"result":1}"#).unwrap_err().code(), "INVALID_DATA");
+        assert_eq!(parse("not json").unwrap_err().code(), "INVALID_DATA");
+    }
+}
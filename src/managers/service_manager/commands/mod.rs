@@ -0,0 +1,69 @@
+use super::super::{error, command::{self, Arg, Spec}};
+
+/// a command in the `command` field of a websocket message, its arguments already checked. the
+/// `message` field is read by the handler
+#[derive(Debug, Clone)]
+pub enum Command {
+    Help(String),
+    Add { kind: String, address: String },
+    Msg(String),
+    Watch(String),
+    Unwatch(String),
+    Del(String),
+    Test,
+}
+
+/// the kinds of server ADD connects to
+const KINDS: [&str; 2] = ["TCP", "DB"];
+
+/// every command the service manager understands, `HELP` lists them
+pub static COMMANDS: command::Registry<Command> = command::Registry(&[
+    Spec {
+        name: "HELP",
+        args: &[Arg::Optional("command")],
+        help: "how the commands are written, all of them or the ones with this name",
+        parse: |p| Ok(Command::Help(p.string("command"))),
+    },
+    Spec {
+        name: "ADD",
+        args: &[Arg::Required("kind"), Arg::Required("port")],
+        help: "connects to a TCP or DB server on a local port or at host:port and logs in",
+        parse: |p| {
+            let kind = p.string("kind");
+            if !KINDS.contains(&kind.as_str()) {
+                return Err(error::ServerError::INVALID_ARG);
+            }
+            Ok(Command::Add { kind, address: p.string("port") })
+        },
+    },
+    Spec {
+        name: "MSG",
+        args: &[Arg::Required("server")],
+        help: "sends the message field to a server and returns its reply",
+        parse: |p| Ok(Command::Msg(p.string("server"))),
+    },
+    Spec {
+        name: "WATCH",
+        args: &[Arg::Required("server")],
+        help: "watches the key or prefix* in the message field, its changes are pushed to this websocket",
+        parse: |p| Ok(Command::Watch(p.string("server"))),
+    },
+    Spec {
+        name: "UNWATCH",
+        args: &[Arg::Required("server")],
+        help: "stops the watch whose id is in the message field",
+        parse: |p| Ok(Command::Unwatch(p.string("server"))),
+    },
+    Spec {
+        name: "DEL",
+        args: &[Arg::Required("server")],
+        help: "disconnects from a server",
+        parse: |p| Ok(Command::Del(p.string("server"))),
+    },
+    Spec {
+        name: "TEST",
+        args: &[],
+        help: "the state of the service manager",
+        parse: |_| Ok(Command::Test),
+    },
+]);
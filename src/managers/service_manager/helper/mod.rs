@@ -1,44 +1,307 @@
 use std::sync::Arc;
 use std::{collections::HashMap, time::Duration};
 use std::fmt;
+use async_trait::async_trait;
 use futures::executor::block_on;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
-use tokio::sync::RwLock;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
 use warp::body::bytes;
 use warp::reply::Json;
-use std::net::TcpStream as tcp;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use warp::ws::Message;
-use super::{json, error, super::session_manager};
-use std::io::{ prelude::*};
+use uuid::Uuid;
+use super::{json, error, response, super::session_manager, super::framing};
 
 
 type Res<T> = Result<T, error::ServerError>;
+
+/// how long a backend gets to answer a request
+const REPLY_TIMEOUT: Duration = Duration::from_secs(7);
+
+#[async_trait]
 pub trait Manager: Sync + Send{
     
-    /// function processes the messasge and returns data 
-    fn process_message<'a>(&self, message: &str, id: &str) -> Res<String>;
+    /// function processes the messasge and returns data. the transport wraps it with `response::envelope`
+    async fn process_message(&self, message: &str, id: &str) -> Res<json::JSON>;
 
     /// sends data back from requested user
-    fn send(&self, message: &str, sender: Sender) -> Res<()>;
+    async fn send(&self, message: &str, sender: Sender<'_>) -> Res<()>;
+
+    /// messages to push to the connection `id` without it asking, none if the manager never pushes
+    async fn subscribe(&self, _id: &str) -> Option<UnboundedReceiver<String>> {
+        None
+    }
+
+    /// forgets whatever the manager kept for the connection `id` once it closed
+    async fn disconnect(&self, _id: &str) {}
 }
 
 pub enum Sender<'a> {
-    TCP(&'a mut TcpStream),
+    TCP(&'a mut TcpStream, framing::Framing),
     WS(&'a UnboundedSender<Result<Message, warp::Error>>),
 }
 
+/// requests waiting on a reply, `None` once the connection is gone
+type Pending = Arc<std::sync::Mutex<Option<HashMap<String, oneshot::Sender<String>>>>>;
+
+/// the channel `connect` writes to a websocket client through
+pub type Client = UnboundedSender<Result<Message, warp::Error>>;
+
+/// the websocket client each watch on a server pushes its events to, by watch id. while a WATCH
+/// waits on its reply, events of watches nobody took yet are kept since they may be for it
+#[derive(Debug, Default)]
+struct Watches {
+    clients: HashMap<String, Client>,
+    early: HashMap<String, Vec<String>>,
+    starting: usize,
+}
+
+type Events = Arc<std::sync::Mutex<Watches>>;
+
+/// a connection to another manager. every request is tagged with an id and a reader task
+/// hands each reply to whoever is waiting on that id, so many requests can be in flight at once
+#[derive(Debug)]
+pub struct Backend {
+    writer: Mutex<OwnedWriteHalf>,
+    framing: framing::Framing,
+    pending: Pending,
+    events: Events,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl Backend {
+    /// asks the server for length prefixed frames so replies of any size come back intact
+    pub async fn negotiate(mut stream: TcpStream) -> Res<Backend> {
+        let framing = framing::Framing::Length;
+        let handshake = framing::Framing::Line.encode(framing.handshake());
+        if stream.write_all(&handshake).await.is_err() {
+            return Err(error::ServerError::CONNECTION);
+        }
+        let mut decoder = framing::Decoder::new(framing);
+        match timeout(REPLY_TIMEOUT, framing::read_frame(&mut stream, &mut decoder)).await {
+            Ok(Ok(Some(framing::Frame::Message(x)))) if x == "OK" => {
+                let (read, write) = stream.into_split();
+                let pending: Pending = Arc::new(std::sync::Mutex::new(Some(HashMap::new())));
+                let events: Events = Arc::new(std::sync::Mutex::new(Watches::default()));
+                let reader = tokio::spawn(Backend::demultiplex(read, decoder, pending.clone(), events.clone()));
+                Ok(Backend { writer: Mutex::new(write), framing, pending, events, reader })
+            },
+            _ => Err(error::ServerError::CONNECTION),
+        }
+    }
+
+    /// reads replies for as long as the connection is up and routes them by request id, and events
+    /// by watch id
+    async fn demultiplex(mut read: OwnedReadHalf, mut decoder: framing::Decoder, pending: Pending, events: Events) {
+        while let Ok(Some(frame)) = framing::read_frame(&mut read, &mut decoder).await {
+            if let framing::Frame::Message(message) = frame {
+                if let Some((watch, event)) = framing::untag_event(&message) {
+                    let mut events = events.lock().unwrap();
+                    let gone = match events.clients.get(watch) {
+                        Some(client) => client.send(Ok(Message::text(event))).is_err(),
+                        None => {
+                            // the watch a WATCH started before its reply was read, or one the client
+                            // stopped while this was on its way
+                            if events.starting > 0 {
+                                events.early.entry(watch.to_owned()).or_default().push(event.to_owned());
+                            }
+                            false
+                        },
+                    };
+                    if gone {
+                        events.clients.remove(watch);
+                    }
+                    continue;
+                }
+                match framing::untag(&message) {
+                    (Some(id), reply) => {
+                        let waiting = pending.lock().unwrap().as_mut().and_then(|p| p.remove(id));
+                        match waiting {
+                            Some(tx) => {
+                                let _ = tx.send(reply.to_owned());
+                            },
+                            // the request timed out, nobody wants this anymore
+                            None => (),
+                        }
+                    },
+                    // every request is tagged, so nobody is waiting on a reply without an id
+                    (None, _) => (),
+                }
+            }
+        }
+        // dropping the senders wakes up every request still waiting with an error
+        *pending.lock().unwrap() = None;
+    }
+
+    /// whether the server hung up, every request on the connection fails from then on
+    pub fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().is_none()
+    }
+
+    fn forget(&self, id: &str) {
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+            pending.remove(id);
+        }
+    }
+
+    /// writes one message tagged with `id` and waits for the reply carrying the same id
+    pub async fn request(&self, id: &str, message: &str) -> Res<String> {
+        let reply = self.send(id, message).await?;
+        self.reply(id, reply).await
+    }
+
+    /// writes one message tagged with `id`, its reply arrives on the receiver. when this fails with
+    /// CONNECTION the server did not get the message
+    pub async fn send(&self, id: &str, message: &str) -> Res<oneshot::Receiver<String>> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let pending = match pending.as_mut() {
+                Some(x) => x,
+                None => return Err(error::ServerError::CONNECTION),
+            };
+            if pending.contains_key(id) {
+                return Err(error::ServerError::TAKEN);
+            }
+            pending.insert(id.to_owned(), tx);
+        }
+        let frame = self.framing.encode(&framing::tag(id, message));
+        let written = self.writer.lock().await.write_all(&frame).await;
+        if written.is_err() {
+            self.forget(id);
+            return Err(error::ServerError::CONNECTION);
+        }
+        Ok(rx)
+    }
+
+    /// starts a watch on the server with WATCH and pushes its events to client, the ones that came
+    /// before the reply was read too. returns the reply
+    pub async fn watch(&self, id: &str, pattern: &str, client: Client) -> Res<json::JSON> {
+        self.events.lock().unwrap().starting += 1;
+        let reply = self.request(id, &format!("WATCH {}", pattern)).await.and_then(|x| response::parse(&x));
+        let mut events = self.events.lock().unwrap();
+        events.starting -= 1;
+        let started = match &reply {
+            Ok(x) => x.get("watch").map(|x| x.to_string()),
+            Err(_) => None,
+        };
+        if let Some(watch) = &started {
+            for event in events.early.remove(watch).unwrap_or_default() {
+                let _ = client.send(Ok(Message::text(event)));
+            }
+            events.clients.insert(watch.clone(), client);
+        }
+        if events.starting == 0 {
+            events.early.clear();
+        }
+        match started {
+            Some(_) => reply,
+            None => reply.and(Err(error::ServerError::INVALID_DATA)),
+        }
+    }
+
+    /// waits for the reply to the message `send` wrote with `id`
+    pub async fn reply(&self, id: &str, rx: oneshot::Receiver<String>) -> Res<String> {
+        match timeout(REPLY_TIMEOUT, rx).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(error::ServerError::CONNECTION),
+            Err(_) => {
+                self.forget(id);
+                Err(error::ServerError::CONNECTION)
+            },
+        }
+    }
+}
+
+/// the connections one websocket client has to the servers. a server keeps who logged in, the
+/// collection picked with USE and an open MULTI per connection, so every client gets its own. they
+/// are opened the first time the client talks to a server and close when the client goes away
+#[derive(Debug)]
+pub struct Session {
+    client: Client,
+    // by server, with the address each was opened to
+    backends: Mutex<HashMap<String, (String, Arc<Backend>)>>,
+}
+
+impl Session {
+    pub fn new(client: Client) -> Session {
+        Session { client, backends: Mutex::new(HashMap::new()) }
+    }
+
+    /// the websocket events for this client are pushed to
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// the client's connection to the server k. it is opened and logged in the way `ADD` logs in the
+    /// first time, and again if it went away or the server was added again somewhere else
+    async fn backend(&self, servers: &TCPServers<'_>, k: &str) -> Res<Arc<Backend>> {
+        let addr = match servers.port(k).await {
+            Some(x) => x,
+            None => return Err(error::ServerError::CONNECTION),
+        };
+        let mut backends = self.backends.lock().await;
+        if let Some((opened, backend)) = backends.get(k) {
+            if *opened == addr && !backend.is_closed() {
+                return Ok(backend.clone());
+            }
+        }
+        backends.remove(k);
+        let stream = match TcpStream::connect(&addr).await {
+            Ok(x) => x,
+            Err(_) => return Err(error::ServerError::CONNECTION),
+        };
+        let backend = Arc::new(Backend::negotiate(stream).await?);
+        let user = dotenv::var("USER").unwrap_or_else(|_|"false".to_string());
+        let password = dotenv::var("PASSWORD").unwrap_or_else(|_|"false".to_string());
+        let id = Uuid::to_string(&Uuid::new_v4());
+        let reply = backend.request(&id, &format!("NEW {} {}", user, password)).await?;
+        if response::parse(&reply).is_err() {
+            return Err(error::ServerError::INCOMPLETE_OPERATION);
+        }
+        backends.insert(k.to_owned(), (addr, backend.clone()));
+        Ok(backend)
+    }
+
+    /// sends a message tagged with the request id over the client's connection and waits for its reply
+    pub async fn send_to_server(&self, servers: &TCPServers<'_>, k: &str, id: &str, message: &str) -> Res<String> {
+        self.backend(servers, k).await?.request(id, message).await
+    }
+
+    /// watches the key or prefix* on the server k over the client's connection and pushes its events
+    /// to the client. returns the server's reply
+    pub async fn watch(&self, servers: &TCPServers<'_>, k: &str, id: &str, pattern: &str) -> Res<json::JSON> {
+        self.backend(servers, k).await?.watch(id, pattern, self.client.clone()).await
+    }
+
+    pub async fn unwatch(&self, k: &str, watch: &str) {
+        if let Some((_, backend)) = self.backends.lock().await.get(k) {
+            backend.events.lock().unwrap().clients.remove(watch);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TCPServers<'a> {
     ports: RwLock<HashMap<&'a str, String>>,
-    store: RwLock<HashMap<&'a str, tcp>>
+    store: RwLock<HashMap<&'a str, Arc<Backend>>>
 }
 
 impl fmt::Display for TCPServers<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let store = block_on(self.store.read());
         let ports = block_on(self.ports.read());
-        write!(f,"store: ({:?}), ports: ({:?})",store,ports)
+        write!(f,"store: ({:?}), ports: ({:?})",store.keys(),ports)
     }
 }
 
@@ -51,10 +314,12 @@ impl<'a> TCPServers<'a> {
     }
 
     /// inserts value into store
-    pub async fn insert (&self, k:&'a str, v: tcp) -> Res<()> {
-        match self.store.write().await.insert(k,v) {
-            Some(mut x) => {
-                match x.write_all(b"NEW") {
+    pub async fn insert (&self, k:&'a str, v: Backend) -> Res<()> {
+        let replaced = self.store.write().await.insert(k,Arc::new(v));
+        match replaced {
+            Some(x) => {
+                let frame = x.framing.encode("NEW");
+                match x.writer.lock().await.write_all(&frame).await {
                     Ok(_)=>{
                         println!("sent");
                         Ok(())
@@ -97,6 +362,11 @@ impl<'a> TCPServers<'a> {
         }else { Ok(()) }
     }
 
+    /// the address the server k was added with
+    pub async fn port(&self, k:&str) -> Option<String> {
+        self.ports.read().await.get(k).cloned()
+    }
+
     pub async fn insert_port(&self, k:&'a str,v:String) -> Result<(),()> {
         let mut ports = self.ports.write().await;
         match ports.insert(k, v){
@@ -121,37 +391,12 @@ impl<'a> TCPServers<'a> {
         Ok(())
     }
 
-    pub async fn send_to_server(&self, k:&str, message: &str) -> Res<String> {
-        let server = self.store.read().await;
-        let server = server.get(k);
+    /// sends a message tagged with the request id to the server and waits for its reply
+    pub async fn send_to_server(&self, k:&str, id:&str, message: &str) -> Res<String> {
+        // only hold the map lock long enough to find the backend
+        let server = self.store.read().await.get(k).cloned();
         match server {
-            Some(mut s) => {
-                let _ = s.set_read_timeout(Some(Duration::new(7,50)));
-                match s.write_all(str::as_bytes(message)) {
-                    Ok(_) => {
-                        let mut buffer = [0; 1024];
-                        let n = match s.read(&mut buffer[..]){
-                            Ok(x) => {
-                                x
-                            },
-                            Err(_) => {
-                                0
-                            },
-                        };
-                        if n == 0 {
-                            return Err(error::ServerError::INVALID_DATA)
-                        }
-                        match std::str::from_utf8(&buffer[..n]) {
-                            Ok(v) => {
-                                Ok(v.to_owned())
-                            },
-                            Err(_) => Err(error::ServerError::INVALID_DATA),
-                        }
-                        
-                    },
-                    Err(_) => Err(error::ServerError::CONNECTION),
-                }
-            },
+            Some(s) => s.request(id, message).await,
             None => Err(error::ServerError::CONNECTION)
         }
     }
@@ -229,3 +474,62 @@ pub async fn login_func(data: json::JSON, _manager: Arc<super::ServiceManager<'_
    // Err(warp::reject::reject())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// a database that starts watch 7 on WATCH and pushes one of its events before the reply and
+    /// one right after it. anything else gets an event of watch 8 nobody asked for, then an echo
+    async fn server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut decoder = framing::Decoder::server();
+            while let Ok(Some(frame)) = framing::read_frame(&mut socket, &mut decoder).await {
+                let framing = decoder.framing();
+                let replies = match frame {
+                    framing::Frame::Handshake(_) => vec!["OK".to_owned()],
+                    framing::Frame::Message(x) => {
+                        let (id, command) = framing::untag(&x);
+                        let id = id.unwrap_or("");
+                        if command.starts_with("WATCH") {
+                            vec![
+                                framing::tag_event("7", "first"),
+                                framing::tag(id, &response::ok(json::to_json!({"watch": 7})).to_string()),
+                                framing::tag_event("7", "second"),
+                            ]
+                        } else {
+                            vec![framing::tag_event("8", "stray"), framing::tag(id, &response::ok(json::to_json!(command)).to_string())]
+                        }
+                    },
+                };
+                let bytes: Vec<u8> = replies.iter().flat_map(|x| framing.encode(x)).collect();
+                if socket.write_all(&bytes).await.is_err() {
+                    return;
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn events_right_after_watch_are_pushed() {
+        let backend = Backend::negotiate(TcpStream::connect(server().await).await.unwrap()).await.unwrap();
+        let (client, mut events) = tokio::sync::mpsc::unbounded_channel();
+        assert_eq!(backend.watch("1", "k*", client).await.unwrap(), json::to_json!({"watch": 7}));
+        for expected in ["first", "second"] {
+            let event = timeout(REPLY_TIMEOUT, events.recv()).await.unwrap().unwrap().unwrap();
+            assert_eq!(event.to_str().unwrap(), expected);
+        }
+
+        // with no WATCH waiting, events of unknown watches are dropped rather than kept
+        assert_eq!(backend.request("2", "FND k").await.unwrap(), response::ok(json::to_json!("FND k")).to_string());
+        assert!(events.try_recv().is_err());
+        let watches = backend.events.lock().unwrap();
+        assert!(watches.early.is_empty());
+        assert_eq!(watches.clients.len(), 1);
+    }
+}
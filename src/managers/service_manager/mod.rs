@@ -1,14 +1,13 @@
 use std::fmt;
-use std::str::Split;
 use std::sync::Arc;
-use super::{json, error, database_manager::DataBaseManager};
-use tokio::net::{TcpListener};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use super::{json, error, framing, response, database_manager::DataBaseManager};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::AsyncWriteExt;
 use tokio::sync::{mpsc};
 use tokio::task::JoinHandle;
 use std::io::{self, BufRead, prelude::*};
+use async_trait::async_trait;
 use futures::executor::block_on;
-use std::net::TcpStream;
 use futures::StreamExt;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use warp::ws::{WebSocket, Message};
@@ -17,7 +16,10 @@ use std::net::SocketAddr;
 use uuid::Uuid;
 
 pub mod helper;
-use helper::{Store, EventQueue, Manager, Sender, TCPServers, login_func};
+pub mod commands;
+pub mod rest;
+use commands::Command;
+use helper::{Store, EventQueue, Manager, Sender, TCPServers, Backend, Session, login_func};
 
 pub use helper as other;
 
@@ -36,9 +38,9 @@ pub struct ServiceManager<'a> {
 
 impl<'a> ServiceManager<'a> {
     // ---------------------------------------------------------------- self
-    async fn send_to_server(&self, server_key:&str, message:&str) -> Res<String> {
+    async fn send_to_server(&self, server_key:&str, id:&str, message:&str) -> Res<String> {
         
-        match self.servers.send_to_server(server_key,message).await {
+        match self.servers.send_to_server(server_key,id,message).await {
             Ok(x)=> {
                 Ok(x)
             },
@@ -46,7 +48,7 @@ impl<'a> ServiceManager<'a> {
         }
     }
 
-    async fn new_server(&self, k:&'a str, v: TcpStream) -> Res<()> {
+    async fn new_server(&self, k:&'a str, v: Backend) -> Res<()> {
         let servers = &self.servers; 
         if !k.is_empty() {
             if !servers.contains_key(k).await {
@@ -128,8 +130,11 @@ impl<'a> ServiceManager<'a> {
         
         // .map(|| "from login");
     
+        // the database over http, /db/{key...} and /db/_query
+        let rest = rest::routes(manager.clone());
+
         // add all routes together. can modularize even further
-        let routes = ws.or(login).or(res_404).with(cors);
+        let routes = ws.or(login).or(rest).or(res_404).with(cors);
         
         
         let test_env = dotenv::var("TEST").unwrap_or_else(|_|"false".to_string());
@@ -184,44 +189,58 @@ impl<'a> ServiceManager<'a> {
                         // moved to the new task and processed there.
                         tokio::spawn(async move {
                             let manager = manager;
-                            let mut buf = [0; 1024];
+                            let mut decoder = framing::Decoder::server();
+                            let mut events = manager.subscribe(&id).await;
                             
-                            // In a loop, read data from the socket and write the data back.
+                            // In a loop, read whole frames from the socket and write the reply back.
+                            // anything the manager pushes in between is written as soon as it comes
                             loop {
-                                let n = match socket.read(&mut buf).await {
+                                let read = tokio::select! {
+                                    read = framing::read_frame(&mut socket, &mut decoder) => read,
+                                    Some(event) = next_event(&mut events) => {
+                                        let _ = manager.send(&event, Sender::TCP(&mut socket, decoder.framing())).await;
+                                        continue
+                                    },
+                                };
+                                let frame = match read {
                                     // socket closed
-                                    Ok(n) if n == 0 => return,
-                                    Ok(n) => n,
-                                    Err(_) => {
+                                    Ok(None) => break,
+                                    Ok(Some(frame)) => frame,
+                                    Err(e) => {
                                         println!("failed to read from connection or remote host disconnected");
-                                        return
+                                        let reply = response::err(&e).to_string();
+                                        let _ = manager.send(&reply,Sender::TCP(&mut socket, decoder.framing())).await;
+                                        break
                                     }
                                 };
+                                let framing = decoder.framing();
 
-                                
-                                match std::str::from_utf8(&buf[..n]) {
-                                    Ok(x) => {
+                                match frame {
+                                    framing::Frame::Handshake(_) => {
+                                        let _ = manager.send("OK",Sender::TCP(&mut socket, framing)).await;
+                                    },
+                                    framing::Frame::Message(x) => {
+                                        // replies carry the request id back so the client can pipeline requests
+                                        let (request_id, x) = framing::untag(&x);
+                                        let reply = |x:&str| {
+                                            match request_id {
+                                                Some(request_id) => framing::tag(request_id, x),
+                                                None => x.to_owned(),
+                                            }
+                                        };
                                         // process and get data
-                                        match manager.process_message(x, &id) {
-                                            Ok(x) => {
-                                                match manager.send(&x, Sender::TCP(&mut socket)) {
-                                                    Ok(_) => (),
-                                                    Err(e) => {
-                                                        let _ = manager.send(e.produce_error(),Sender::TCP(&mut socket));
-                                                    }
-                                                }
-                                            },
+                                        let result = response::envelope(manager.process_message(x, &id).await);
+                                        match manager.send(&reply(&result.to_string()), Sender::TCP(&mut socket, framing)).await {
+                                            Ok(_) => (),
                                             Err(e) => {
-                                                let _ = manager.send(e.produce_error(),Sender::TCP(&mut socket));
-                                            },
+                                                let _ = manager.send(&reply(&response::err(&e).to_string()),Sender::TCP(&mut socket, framing)).await;
+                                            }
                                         }
                                     },
-                                    Err(_) => {
-                                        let _ = manager.send("server error",Sender::TCP(&mut socket));
-                                    },
                                 }
 
                             }
+                            manager.disconnect(&id).await;
                     });
                         },
                         Err(_) => println!("could not make connection"),
@@ -234,11 +253,10 @@ impl<'a> ServiceManager<'a> {
         
     }
 
-    fn add_cmd(&self,cmd:&str, mut result:Split<&str>) -> Res<String> {
+    async fn add_cmd(&self,cmd:&str, result_3:&str) -> Res<json::JSON> {
         match cmd {
             x => {
-                     let result_3 = result.next().unwrap_or_else(|| "");
-                     match block_on(self.contains_s_p(x, result_3)) {
+                     match self.contains_s_p(x, result_3).await {
                          Ok(_) => {
                              let addr;
                              let result_3 = result_3.to_owned();
@@ -247,8 +265,12 @@ impl<'a> ServiceManager<'a> {
                              }else {
                                  addr = format!("127.0.0.1:{}",result_3);
                              }
-                             match TcpStream::connect(&addr){
-                                 Ok(stream) => {
+                             let backend = match TcpStream::connect(&addr).await {
+                                 Ok(stream) => Backend::negotiate(stream).await,
+                                 Err(_) => Err(error::ServerError::CONNECTION),
+                             };
+                             match backend {
+                                 Ok(backend) => {
                                      let static_name = |x:&str| {
                                          match x {
                                              "TCP" => "TCP",
@@ -257,23 +279,25 @@ impl<'a> ServiceManager<'a> {
                                          }
                                      };
                                      let name = static_name(x);
-                                     let _ = block_on(self.servers.insert_port(name, addr));
-                                     match block_on(self.new_server(name, stream)) {
+                                     let _ = self.servers.insert_port(name, addr).await;
+                                     match self.new_server(name, backend).await {
                                          Ok(_) => {
                                              let user = dotenv::var("USER").unwrap_or_else(|_|"false".to_string());
                                              let password= dotenv::var("PASSWORD").unwrap_or_else(|_|"false".to_string());
                                              let cmd = format!("NEW {} {}",user, password);
-                                             match block_on(self.send_to_server(name, &cmd)){
+                                             let id = Uuid::to_string(&Uuid::new_v4());
+                                             match self.send_to_server(name, &id, &cmd).await {
                                                  Ok(x) => {
-                                                     if x.contains("Error") || x.contains("error") {
-                                                         let _ = block_on(self.servers.remove_server(name));
-                                                         Err(error::ServerError::INCOMPLETE_OPERATION)
-                                                     }else { 
-                                                         Ok(x)
+                                                     match response::parse(&x) {
+                                                         Ok(x) => Ok(x),
+                                                         Err(_) => {
+                                                             let _ = self.servers.remove_server(name).await;
+                                                             Err(error::ServerError::INCOMPLETE_OPERATION)
+                                                         }
                                                      }
                                                  },
                                                  Err(_) => {
-                                                     let _ = block_on(self.servers.remove_server(name));
+                                                     let _ = self.servers.remove_server(name).await;
                                                      Err(error::ServerError::CONNECTION)
                                                  }
                                              }
@@ -283,8 +307,8 @@ impl<'a> ServiceManager<'a> {
                                          }
                                      }
                                  },
-                                 Err(_) => {
-                                     Err(error::ServerError::CONNECTION)
+                                 Err(e) => {
+                                     Err(e)
                                  },
                              }
                          },
@@ -297,6 +321,89 @@ impl<'a> ServiceManager<'a> {
          }
     }
 
+    /// sends a message over the session's own connection to the server, or the one `ADD` opened when
+    /// there is no session
+    async fn forward(&self, session: Option<&Session>, server_key:&str, id:&str, message:&str) -> Res<String> {
+        match session {
+            Some(session) => session.send_to_server(&self.servers, server_key, id, message).await,
+            None => self.send_to_server(server_key, id, message).await,
+        }
+    }
+
+    /// runs the command in a websocket message. `id` tags anything forwarded to a server and `session`
+    /// belongs to the websocket it came from, if any
+    async fn run_command(&self, x_command: &serde_json::Map<String, json::JSON>, id: &str, session: Option<&Session>) -> Res<json::JSON> {
+        // ! use command format
+        let command = x_command.get("command");
+        match command {
+            Some(x) => {
+                let x = x.as_str();
+                match x {
+                    Some(x) => {
+                        match commands::COMMANDS.parse(x)? {
+                            Command::Help(name) => commands::COMMANDS.help(&name),
+                            Command::Add { kind, address } => {
+                                self.add_cmd(&kind, &address).await
+                            },
+                            Command::Msg(server) => {
+                                let message = x_command.get("message");
+                                match message {
+                                    Some(msg) => {
+                                        let msg = msg.as_str().unwrap_or_else(|| "Error");
+                                        // the server answers with its own envelope, unwrap it so it isn't sent twice
+                                        match self.forward(session, &server, id, msg).await {
+                                            Ok(x) => response::parse(&x),
+                                            Err(e) => Err(e),
+                                        }
+                                    },
+                                    None => {
+                                        Err(error::ServerError::INVALID_JSON)
+                                    }
+                                }
+                            },
+                            Command::Watch(server) => {
+                                // the key or prefix is the message, the server's events for it are
+                                // pushed to this websocket from then on
+                                let session = match session {
+                                    Some(x) => x,
+                                    None => return Err(error::ServerError::INVALID_ARG),
+                                };
+                                let pattern = match x_command.get("message").and_then(|x| x.as_str()) {
+                                    Some(x) => x,
+                                    None => return Err(error::ServerError::INVALID_JSON),
+                                };
+                                session.watch(&self.servers, &server, id, pattern).await
+                            },
+                            Command::Unwatch(server) => {
+                                // the watch id is the message
+                                let watch = match x_command.get("message") {
+                                    Some(json::JSON::String(x)) => x.clone(),
+                                    Some(json::JSON::Number(x)) => x.to_string(),
+                                    _ => return Err(error::ServerError::INVALID_JSON),
+                                };
+                                if let Some(session) = session {
+                                    session.unwatch(&server, &watch).await;
+                                }
+                                match self.forward(session, &server, id, &format!("UNWATCH {}", watch)).await {
+                                    Ok(x) => response::parse(&x),
+                                    Err(e) => Err(e),
+                                }
+                            },
+                            Command::Del(server) => {
+                                let _ = self.servers.remove_server(&server).await;
+                                Ok(json::JSON::Null)
+                            },
+                            Command::Test => {
+                                Ok(json::JSON::String(format!("{:?}",self)))
+                            },
+                        }
+                    },
+                    None => Err(error::ServerError::INVALID_JSON),
+                }
+            },
+            None => Err(error::ServerError::INVALID_JSON),
+        }
+    }
 }
 
 impl fmt::Display for ServiceManager<'_> {
@@ -305,9 +412,11 @@ impl fmt::Display for ServiceManager<'_> {
     }
 }
 
+#[async_trait]
 impl Manager for ServiceManager<'_> {
     // this needs to send message to tcp servers via commands and then return the data
-    fn process_message(&self, message: &str, _id: &str) -> Res<String> {
+    // `id` is the request id made by `connect` for this message
+    async fn process_message(&self, message: &str, id: &str) -> Res<json::JSON> {
 
         let processed_message = json::to_value_from_str(message);
         let processed_message = match processed_message {
@@ -317,76 +426,18 @@ impl Manager for ServiceManager<'_> {
 
         
         match processed_message {
-            serde_json::Value::Null => Err(error::ServerError::INVALID_JSON),
-            serde_json::Value::Bool(_) => Err(error::ServerError::INVALID_JSON),
-            serde_json::Value::Number(_) => Err(error::ServerError::INVALID_JSON),
-            serde_json::Value::String(_) => Err(error::ServerError::INVALID_JSON),
-            serde_json::Value::Array(_) => Err(error::ServerError::INVALID_JSON),
-            serde_json::Value::Object(x_command) => {
-                // ! use command format
-                let command = x_command.get("command");
-                match command {
-                    Some(x) => {
-                        let x = x.as_str();
-                        match x {
-                            Some(x) => {
-                                // ! maybe something faser than a vec?
-                                let mut result = x.split(" ");
-                                let result_1 = result.next().unwrap();
-                                let result_2 = result.next().unwrap_or_else(|| "");
-                                match result_1 {
-                                    "ADD" => {
-                                        self.add_cmd(result_2, result)
-                                    },
-                                    "MSG" => {
-                                        let message = x_command.get("message");
-                                        match message {
-                                            Some(msg) => {
-                                                let msg = msg.as_str().unwrap_or_else(|| "Error");
-                                                match block_on(self.send_to_server(result_2, msg)) {
-                                                    Ok(x) => Ok(x),
-                                                    Err(e) => Err(e),
-                                                } 
-                                            },
-                                            None => {
-                                                Err(error::ServerError::INVALID_JSON)
-                                            }
-                                        }
-                                    },
-                                    "DEL" => {
-                                        match result_2 {
-                                            x => {
-                                                let _ = block_on(self.servers.remove_server(x));
-                                                Ok(String::from("Deleted server"))
-                                            }
-                                        }
-                                    },
-                                    "TEST" => {
-                                        Ok(format!("{:?}",self))
-                                    },
-                                    _ => {
-                                        let example = json::to_json!({"command":"command_for_service_manager server_name optional_argument","message":"server_command server_argument optional_argument"});
-                                        let example = example.to_string();
-                                        Ok(String::from("Use this format for message\n") + &example)
-                                    }
-                                }
-                            },
-                            None => Err(error::ServerError::INVALID_JSON),
-                        }
-                    },
-                    None => Err(error::ServerError::INVALID_JSON),
-                }
-            },
+            serde_json::Value::Object(x_command) => self.run_command(&x_command, id, None).await,
+            _ => Err(error::ServerError::INVALID_JSON),
         }
     }
 
     // either sends data to user or tcp servers
-    fn send(&self, message: &str, sender: Sender) -> Res<()> {
+    async fn send(&self, message: &str, sender: Sender<'_>) -> Res<()> {
         
         match sender {
             // this writes back data from tcp servers
-            Sender::TCP(x) => {
-                match block_on(x.write_all(message.as_bytes())) {
+            Sender::TCP(x, framing) => {
+                match x.write_all(&framing.encode(message)).await {
                     Ok(_) => Ok(()),
                     Err(_) => Err(error::ServerError::NO_VALUE),
                 }
@@ -410,33 +461,37 @@ async fn connect(ws: WebSocket, server: Arc<ServiceManager<'_>>) {
     // Establishing a connection
     let (user_tx, mut user_rx) = ws.split();
     let (tx, rx) = mpsc::unbounded_channel();
-
-    let id = Uuid::new_v4();
-    let id = Uuid::to_string(&id);
     
     let rx = UnboundedReceiverStream::new(rx);
     
     tokio::spawn(rx.forward(user_tx));
+
+    // this client's own connections to the servers
+    let session = Session::new(tx.clone());
     
     while let Some(result) = user_rx.next().await {
         match result {
             Ok(x) => {
                 match x.to_str() {
                     Ok(result) => {
-                        // processing message and return data
-                        match server.process_message(result, &id) {
-                            Ok(x) => {
-                                // service manager sends to websocekt user
-                                match server.send(&x, Sender::WS(&tx)) {
-                                    Ok(_) => (),
-                                    Err(err) => println!("error sending message: {}", err),
+                        // every message gets its own request id so replies from servers can't get mixed up
+                        let id = Uuid::to_string(&Uuid::new_v4());
+                        let reply = match json::to_value_from_str(result) {
+                            Ok(serde_json::Value::Object(x_command)) => {
+                                let mut reply = response::envelope(server.run_command(&x_command, &id, Some(&session)).await);
+                                // an id supplied by the client is echoed back so it can match up replies.
+                                // it is never sent on to the servers since two clients could pick the same one
+                                if let Some(client_id) = x_command.get("id") {
+                                    reply["id"] = client_id.clone();
                                 }
+                                reply
                             },
-                            Err(e) => {
-                                let e = e.produce_error();
-                                let error = format!("error processing your message: {}",e);
-                                let _ = server.send(&error, Sender::WS(&tx));
-                            }
+                            _ => response::err(&error::ServerError::INVALID_JSON),
+                        };
+                        // service manager sends to websocekt user
+                        match server.send(&reply.to_string(), Sender::WS(&tx)).await {
+                            Ok(_) => (),
+                            Err(err) => println!("error sending message: {}", err),
                         }
                     },
                     Err(_) => break,
@@ -450,27 +505,33 @@ async fn connect(ws: WebSocket, server: Arc<ServiceManager<'_>>) {
         // }
         // broadcast_msg(result.expect("Failed to fetch message")).await;
     }
-    // disconnected    
+    // disconnected, dropping the session closes its connections and the servers forget its state and watches
+    drop(session);
 }
 
 
+/// waits for the next pushed message, forever if the connection has no subscription
+async fn next_event(events: &mut Option<mpsc::UnboundedReceiver<String>>) -> Option<String> {
+    match events {
+        Some(x) => x.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
 /// function that takes the port number as a string and connects to that tcp server for testing a single message
 fn test_server(port: &str) -> Res<()> {
     if port.chars().all(char::is_numeric){
-        match TcpStream::connect(format!("127.0.0.1:{}",port)){
+        match std::net::TcpStream::connect(format!("127.0.0.1:{}",port)){
             Ok(mut stream) => {
 
-                let mut buffer = [0; 1024];
+                let mut decoder = framing::Decoder::new(framing::Framing::Line);
                 
-                let _ = stream.write_all(b"hello");
-
-                let n = stream.read(&mut buffer[..]).unwrap();
-
-                let buffer = &buffer[..n];
+                let _ = stream.write_all(&framing::Framing::Line.encode("hello"));
 
-                match String::from_utf8(buffer.into()) {
-                    Ok(v) => println!("{}",v),
-                    Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
+                match framing::read_frame_blocking(&mut stream, &mut decoder) {
+                    Ok(Some(framing::Frame::Message(v))) => println!("{}",v),
+                    Ok(_) => println!("Server closed the connection"),
+                    Err(e) => println!("{}",e.produce_error()),
                 };
                 
             },
@@ -544,8 +605,8 @@ pub fn start() {
             "db" => {
                 if !online_db {
                     let manager = DataBaseManager::new();
-                    let y = tokio::task::spawn_blocking(move || {
-                        let _ = block_on(ServiceManager::start_tcp_server(manager,"8000"));
+                    let y = tokio::spawn(async move {
+                        ServiceManager::start_tcp_server(manager,"8000").await;
                     });
                     processes.push(y);
                     online_db = true;
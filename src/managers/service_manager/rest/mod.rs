@@ -0,0 +1,422 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use blake2::{Blake2b512, Digest};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use warp::{Filter, Reply, http::StatusCode, hyper::body::Bytes, path::Tail, reply::Response};
+
+use super::ServiceManager;
+use super::super::{error, json, response, client::DbClient, database_manager::pointer};
+
+type Res<T> = Result<T, error::ServerError>;
+
+/// the server REST requests go to, added with `ADD DB <port>`
+const BACKEND: &str = "DB";
+
+/// `?collection=<name>` picks the collection like `-c` does, `?ttl=<seconds>` makes a PUT expire
+#[derive(Debug, Default, Deserialize)]
+pub struct Params {
+    collection: Option<String>,
+    ttl: Option<u64>,
+}
+
+/// connections to the database logged in as the users requests came from, by the database's address,
+/// the user and a hash of the password, with when each was last used. a request only logs in when
+/// nobody with its credentials did before
+#[derive(Debug)]
+struct Clients {
+    // hashed in with every password, so the keys tell nothing about them
+    secret: [u8; 32],
+    clients: Mutex<HashMap<Login, (DbClient, Instant)>>,
+}
+
+/// the database's address, the user and the hash of the password
+type Login = (String, String, Vec<u8>);
+
+/// connections each user keeps to the database
+const POOL_SIZE: usize = 2;
+
+/// users whose connections are kept at once, the one unused the longest goes first
+const MAX_CLIENTS: usize = 64;
+
+/// how long the connections of a user nobody logged in as are kept
+const IDLE: Duration = Duration::from_secs(600);
+
+/// a user and password sent with `Authorization: Basic`
+type Credentials = (String, String);
+
+/// `GET`, `PUT` and `DELETE /db/{key...}` read, write and delete a key, nested segments reach into it
+/// like a dotted key. `POST /db/_query` runs a QUERY. replies are the same envelopes the websocket sends.
+/// every request logs in to the database with the user and password of its `Authorization: Basic` header
+pub fn routes(manager: Arc<ServiceManager<'static>>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let clients = Arc::new(Clients::new());
+    let manager = warp::any()
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |authorization: Option<String>| (manager.clone(), clients.clone(), credentials(authorization)))
+        .untuple_one();
+
+    let query = warp::path!("db" / "_query")
+        .and(warp::post())
+        .and(warp::query::<Params>())
+        .and(warp::body::bytes())
+        .and(manager.clone())
+        .and_then(query);
+
+    let get = warp::path("db")
+        .and(warp::path::tail())
+        .and(warp::get())
+        .and(warp::query::<Params>())
+        .and(manager.clone())
+        .and_then(get);
+
+    let put = warp::path("db")
+        .and(warp::path::tail())
+        .and(warp::put())
+        .and(warp::query::<Params>())
+        .and(warp::body::bytes())
+        .and(manager.clone())
+        .and_then(put);
+
+    let delete = warp::path("db")
+        .and(warp::path::tail())
+        .and(warp::delete())
+        .and(warp::query::<Params>())
+        .and(manager)
+        .and_then(delete);
+
+    query.or(get).or(put).or(delete)
+}
+
+async fn get(tail: Tail, params: Params, manager: Arc<ServiceManager<'static>>, clients: Arc<Clients>, credentials: Option<Credentials>) -> Result<Response, warp::Rejection> {
+    let client = match clients.login(&manager, credentials).await {
+        Ok(x) => x,
+        Err(e) => return Ok(unauthorized(e)),
+    };
+    let result = match key(&tail) {
+        // a missing top-level key reads as null
+        Ok(key) => {
+            match run(&client, "FND", &params, vec![key]).await {
+                Ok(json::JSON::Null) => Err(error::ServerError::MISSING_DATA),
+                x => x,
+            }
+        },
+        Err(e) => Err(e),
+    };
+    Ok(reply(result))
+}
+
+async fn put(tail: Tail, params: Params, body: Bytes, manager: Arc<ServiceManager<'static>>, clients: Arc<Clients>, credentials: Option<Credentials>) -> Result<Response, warp::Rejection> {
+    let client = match clients.login(&manager, credentials).await {
+        Ok(x) => x,
+        Err(e) => return Ok(unauthorized(e)),
+    };
+    let result = match (key(&tail), document(&body)) {
+        (Ok(key), Ok(value)) => {
+            let mut args = vec![key, value.to_string()];
+            if let Some(ttl) = params.ttl {
+                args.push("EX".to_owned());
+                args.push(ttl.to_string());
+            }
+            run(&client, "INS", &params, args).await
+        },
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    };
+    Ok(reply(result))
+}
+
+async fn delete(tail: Tail, params: Params, manager: Arc<ServiceManager<'static>>, clients: Arc<Clients>, credentials: Option<Credentials>) -> Result<Response, warp::Rejection> {
+    let client = match clients.login(&manager, credentials).await {
+        Ok(x) => x,
+        Err(e) => return Ok(unauthorized(e)),
+    };
+    let result = match key(&tail) {
+        Ok(key) => run(&client, "DEL", &params, vec![key]).await,
+        Err(e) => Err(e),
+    };
+    Ok(reply(result))
+}
+
+async fn query(params: Params, body: Bytes, manager: Arc<ServiceManager<'static>>, clients: Arc<Clients>, credentials: Option<Credentials>) -> Result<Response, warp::Rejection> {
+    let client = match clients.login(&manager, credentials).await {
+        Ok(x) => x,
+        Err(e) => return Ok(unauthorized(e)),
+    };
+    let result = match document(&body) {
+        Ok(query) => run(&client, "QUERY", &params, vec![query.to_string()]).await,
+        Err(e) => Err(e),
+    };
+    Ok(reply(result))
+}
+
+impl Clients {
+    fn new() -> Clients {
+        let mut secret = [0; 32];
+        OsRng.fill_bytes(&mut secret);
+        Clients { secret, clients: Mutex::new(HashMap::new()) }
+    }
+
+    fn hash(&self, password: &str) -> Vec<u8> {
+        let mut hasher = Blake2b512::new();
+        hasher.update(self.secret);
+        hasher.update(password.as_bytes());
+        hasher.finalize().to_vec()
+    }
+
+    /// the client logged in with the credentials to the database added with `ADD DB`, logging in
+    /// first if there is none yet
+    async fn login(&self, manager: &ServiceManager<'_>, credentials: Option<Credentials>) -> Res<DbClient> {
+        let credentials = match credentials {
+            Some(x) => x,
+            None => return Err(error::ServerError::ACCESS_DENIED),
+        };
+        match manager.servers.port(BACKEND).await {
+            Some(addr) => self.client(addr, credentials).await,
+            None => Err(error::ServerError::CONNECTION),
+        }
+    }
+
+    /// the client logged in to addr with the credentials. credentials the database turns down are
+    /// never kept, and nobody waits on someone else logging in
+    async fn client(&self, addr: String, (user, password): Credentials) -> Res<DbClient> {
+        let k = (addr, user, self.hash(&password));
+        {
+            let mut clients = self.clients.lock().await;
+            // clients of a database that was replaced by another one, or of users gone quiet, are no
+            // use anymore
+            clients.retain(|(x, _, _), (_, used)| *x == k.0 && used.elapsed() < IDLE);
+            if let Some((client, used)) = clients.get_mut(&k) {
+                *used = Instant::now();
+                return Ok(client.clone());
+            }
+        }
+        let client = match DbClient::connect_pooled(&k.0, &k.1, &password, POOL_SIZE).await {
+            Ok(x) => x,
+            Err(error::ServerError::CONNECTION) => return Err(error::ServerError::CONNECTION),
+            Err(_) => return Err(error::ServerError::ACCESS_DENIED),
+        };
+        let mut clients = self.clients.lock().await;
+        if !clients.contains_key(&k) && clients.len() >= MAX_CLIENTS {
+            let oldest = clients.iter().min_by_key(|(_, (_, used))| *used).map(|(x, _)| x.clone());
+            if let Some(oldest) = oldest {
+                clients.remove(&oldest);
+            }
+        }
+        // whoever logged in with the same credentials meanwhile got there first
+        let (client, _) = clients.entry(k).or_insert((client, Instant::now()));
+        Ok(client.clone())
+    }
+}
+
+/// the user and password of an `Authorization: Basic <base64 of user:password>` header
+fn credentials(authorization: Option<String>) -> Option<Credentials> {
+    let authorization = authorization?;
+    let (scheme, encoded) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_owned(), password.to_owned()))
+}
+
+/// sends the command to the database as a json array, so keys and values never need quoting
+async fn run(client: &DbClient, command: &str, params: &Params, args: Vec<String>) -> Res<json::JSON> {
+    let mut line = vec![command];
+    if let Some(ref collection) = params.collection {
+        line.push("-c");
+        line.push(collection);
+    }
+    line.extend(args.iter().map(|x| x.as_str()));
+    client.command(&line).await
+}
+
+/// the key the path names. `/db/user/address/city` is `user.address.city`, a segment with a `.` or an
+/// escaped `/` in it makes it a json pointer so the segment stays one key
+fn key(tail: &Tail) -> Res<String> {
+    let mut segments = Vec::new();
+    for segment in tail.as_str().split('/').filter(|x| !x.is_empty()) {
+        match percent_decode_str(segment).decode_utf8() {
+            Ok(x) => segments.push(x.into_owned()),
+            Err(_) => return Err(error::ServerError::INVALID_ARG),
+        }
+    }
+    if segments.is_empty() {
+        return Err(error::ServerError::INVALID_ARG);
+    }
+    if segments.iter().any(|x| x.contains('.') || x.contains('/')) {
+        Ok(segments.iter().map(|x| pointer::escape(x)).collect())
+    } else {
+        Ok(segments.join("."))
+    }
+}
+
+fn document(body: &Bytes) -> Res<json::JSON> {
+    match serde_json::from_slice::<json::JSON>(body) {
+        Ok(x) => Ok(x),
+        Err(_) => Err(error::ServerError::INVALID_JSON),
+    }
+}
+
+fn reply(result: Res<json::JSON>) -> Response {
+    let status = match result {
+        Ok(_) => StatusCode::OK,
+        Err(ref e) => status(e),
+    };
+    warp::reply::with_status(warp::reply::json(&response::envelope(result)), status).into_response()
+}
+
+/// the reply to a request that could not log in, asking for credentials unless the database is away
+fn unauthorized(e: error::ServerError) -> Response {
+    match e {
+        error::ServerError::ACCESS_DENIED => {
+            let reply = warp::reply::with_status(warp::reply::json(&response::envelope(Err(e))), StatusCode::UNAUTHORIZED);
+            warp::reply::with_header(reply, "WWW-Authenticate", "Basic realm=\"db\"").into_response()
+        },
+        e => reply(Err(e)),
+    }
+}
+
+fn status(e: &error::ServerError) -> StatusCode {
+    match e {
+        error::ServerError::MISSING_DATA => StatusCode::NOT_FOUND,
+        error::ServerError::ACCESS_DENIED => StatusCode::FORBIDDEN,
+        error::ServerError::CONFLICT => StatusCode::CONFLICT,
+        // no database was added to the service manager or it went away
+        error::ServerError::CONNECTION => StatusCode::SERVICE_UNAVAILABLE,
+        error::ServerError::INVALID_JSON | error::ServerError::INVALID_DATA | error::ServerError::INVALID_ARG |
+        error::ServerError::INCOMPATIBLE_DATA_TYPES | error::ServerError::SCHEMA_VIOLATION(_) |
+        error::ServerError::SYNTAX_ERROR(_) | error::ServerError::UNKNOWN_COMMAND(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+    use super::super::super::framing;
+
+    /// a database that lets in anyone with the password pw, counting the logins
+    async fn server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let logins = Arc::new(AtomicUsize::new(0));
+        let count = logins.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let count = count.clone();
+                tokio::spawn(async move {
+                    let mut decoder = framing::Decoder::server();
+                    while let Ok(Some(frame)) = framing::read_frame(&mut socket, &mut decoder).await {
+                        let reply = match frame {
+                            framing::Frame::Handshake(_) => "OK".to_owned(),
+                            framing::Frame::Message(x) => {
+                                let (id, command) = framing::untag(&x);
+                                let reply = if command.ends_with(r#","pw"]"#) {
+                                    count.fetch_add(1, Ordering::SeqCst);
+                                    response::ok(json::JSON::Null)
+                                } else {
+                                    response::err(&error::ServerError::ACCESS_DENIED)
+                                };
+                                framing::tag(id.unwrap_or(""), &reply.to_string())
+                            },
+                        };
+                        if socket.write_all(&decoder.framing().encode(&reply)).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (addr, logins)
+    }
+
+    fn login(user: &str, password: &str) -> Credentials {
+        (user.to_owned(), password.to_owned())
+    }
+
+    async fn key_of(path: &str) -> Res<String> {
+        let tail = warp::test::request().path(path).filter(&warp::path("db").and(warp::path::tail())).await.unwrap();
+        key(&tail)
+    }
+
+    #[tokio::test]
+    async fn keys_from_paths() {
+        assert_eq!(key_of("/db/user").await.unwrap(), "user");
+        assert_eq!(key_of("/db/user/address/city").await.unwrap(), "user.address.city");
+        assert_eq!(key_of("/db//user//address/").await.unwrap(), "user.address");
+        // a segment a dotted key would split up makes the key a pointer
+        assert_eq!(key_of("/db/a.b/c").await.unwrap(), "/a.b/c");
+        assert_eq!(key_of("/db/a%2Fb/c").await.unwrap(), "/a~1b/c");
+        assert_eq!(key_of("/db/x~y/a.b").await.unwrap(), "/x~0y/a.b");
+        assert_eq!(key_of("/db/caf%C3%A9/a%20b").await.unwrap(), "café.a b");
+        for path in ["/db", "/db/", "/db//", "/db/%FF"] {
+            assert_eq!(key_of(path).await.unwrap_err().code(), "INVALID_ARG");
+        }
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(status(&error::ServerError::MISSING_DATA), StatusCode::NOT_FOUND);
+        assert_eq!(status(&error::ServerError::ACCESS_DENIED), StatusCode::FORBIDDEN);
+        assert_eq!(status(&error::ServerError::CONFLICT), StatusCode::CONFLICT);
+        assert_eq!(status(&error::ServerError::CONNECTION), StatusCode::SERVICE_UNAVAILABLE);
+        for code in ["INVALID_JSON", "INVALID_DATA", "INVALID_ARG", "INCOMPATIBLE_DATA_TYPES", "SCHEMA_VIOLATION", "SYNTAX_ERROR", "UNKNOWN_COMMAND"] {
+            assert_eq!(status(&error::ServerError::from_code(code)), StatusCode::BAD_REQUEST, "{}", code);
+        }
+        assert_eq!(status(&error::ServerError::FAILED_WRITE), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(unauthorized(error::ServerError::ACCESS_DENIED).status(), StatusCode::UNAUTHORIZED);
+        assert!(unauthorized(error::ServerError::ACCESS_DENIED).headers().contains_key("WWW-Authenticate"));
+        assert_eq!(unauthorized(error::ServerError::CONNECTION).status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn logins_are_kept_by_user_and_password() {
+        let (addr, logins) = server().await;
+        let clients = Clients::new();
+        clients.client(addr.clone(), login("ann", "pw")).await.unwrap();
+        clients.client(addr.clone(), login("ann", "pw")).await.unwrap();
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+        assert_eq!(clients.client(addr.clone(), login("ann", "nope")).await.unwrap_err().code(), "ACCESS_DENIED");
+        clients.client(addr.clone(), login("bob", "pw")).await.unwrap();
+        assert_eq!(logins.load(Ordering::SeqCst), 2);
+
+        let kept = clients.clients.lock().await;
+        assert_eq!(kept.len(), 2);
+        assert!(kept.keys().all(|(_, _, hash)| *hash == clients.hash("pw") && hash.as_slice() != b"pw"));
+    }
+
+    #[tokio::test]
+    async fn the_user_unused_the_longest_goes_first() {
+        let (addr, logins) = server().await;
+        let clients = Clients::new();
+        for i in 0..MAX_CLIENTS {
+            clients.client(addr.clone(), login(&i.to_string(), "pw")).await.unwrap();
+        }
+        clients.client(addr.clone(), login("0", "pw")).await.unwrap();
+        clients.client(addr.clone(), login("new", "pw")).await.unwrap();
+        assert_eq!(logins.load(Ordering::SeqCst), MAX_CLIENTS + 1);
+
+        let kept = clients.clients.lock().await;
+        assert_eq!(kept.len(), MAX_CLIENTS);
+        assert!(kept.keys().any(|(_, user, _)| user == "0"));
+        assert!(!kept.keys().any(|(_, user, _)| user == "1"));
+    }
+
+    fn basic(x: &str) -> Option<String> {
+        Some(format!("Basic {}", base64::encode(x)))
+    }
+
+    #[test]
+    fn basic_credentials() {
+        assert_eq!(credentials(basic("ann:p:w")), Some(("ann".to_owned(), "p:w".to_owned())));
+        assert_eq!(credentials(basic("ann:")), Some(("ann".to_owned(), "".to_owned())));
+        assert_eq!(credentials(Some(format!("basic  {} ", base64::encode("a:b")))), Some(("a".to_owned(), "b".to_owned())));
+        assert_eq!(credentials(basic("ann")), None);
+        assert_eq!(credentials(Some("Basic !!".to_owned())), None);
+        assert_eq!(credentials(Some(format!("Bearer {}", base64::encode("a:b")))), None);
+        assert_eq!(credentials(None), None);
+    }
+}